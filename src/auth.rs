@@ -0,0 +1,678 @@
+//! Authentication module
+//!
+//! Everything to do with the JWTs handed out by `/api/login`. Tokens get minted with
+//! [`issue_token`] and checked with [`verify_token`]. You shouldn't really need to call
+//! [`verify_token`] yourself, just stick `with_auth(config, conn)` (in `main.rs`) on the route
+//! and the handler gets an [`AuthUser`]:
+//!
+//! ```rust
+//! let me_routes = warp::path!("api" / "me")
+//!     .and(warp::get())
+//!     .and(with_auth(config.clone(), conn.clone()))
+//!     .and(with_db(conn.clone()))
+//!     .then(handler::me_handler);
+//! ```
+//!
+//! If the token is missing or dodgy the filter rejects with an [`AuthError`], which
+//! `error::handle_rejection` turns into a 401.
+//!
+//! Login:
+//! [`login`] checks an email and password. An unknown email and a wrong password get the same
+//! [`LoginError::InvalidCredentials`], and an unknown email is still checked against a dummy
+//! hash so it takes about as long. Otherwise you could find out who has an account. Too many
+//! failures and the email or IP gets locked out for a while, see `lockout.rs`.
+//!
+//! The signing secret, issuer and token lifetime all come from [`AuthConfig`].
+//!
+//! Refresh tokens:
+//! Access tokens are short lived. Login also hands out an opaque refresh token which can be
+//! swapped (once!) for a new access/refresh pair with [`rotate_refresh_token`]. Every token that
+//! came from the same login is one "family". If a token that was already swapped shows up again,
+//! someone has a copy they shouldn't, so the whole family gets revoked and both parties have to
+//! log in again.
+//!
+//! Revocation:
+//! Every access token has a `jti` and the `sid` (family) of the login it came from. Logging out
+//! revokes the family, and any access token with that `sid` is rejected from then on, not just
+//! the one it was done with (so is every one from a family burnt for reuse). Logging out
+//! everywhere bumps the user's `token_generation`, and any token carrying an older `gen` is
+//! rejected.
+
+use std::{net::IpAddr, sync::{Arc, LazyLock}, time::{SystemTime, UNIX_EPOCH}};
+
+use argon2::{password_hash::rand_core::{OsRng, RngCore}, Argon2, PasswordHash, PasswordVerifier};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use libsql::Connection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use warp::Rejection;
+
+use crate::{
+    config::{AuthConfig, Config},
+    database::{Database, Db, RefreshTokens, Register, Revocations},
+    lockout,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,   // Subject (user ID)
+    pub iss: String,   // Issuer
+    pub exp: usize,    // Expiration time
+    pub iat: usize,    // Issued at
+    pub jti: String,   // Token ID, used for revocation
+    pub sid: String,   // Session (refresh token family) the token belongs to
+    pub r#gen: i64,    // The user's token generation when this was issued
+}
+
+/// The caller of a route guarded by `with_auth()`
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub uuid: Uuid,
+    pub session_id: String,
+    /// When the token they used expires (unix seconds)
+    pub expires_at: usize,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// No `Authorization` header at all
+    MissingToken,
+    /// There is a header, but it isn't `Bearer <token>`
+    MalformedHeader,
+    /// Bad signature, wrong issuer, garbage subject, etc.
+    InvalidToken,
+    ExpiredToken,
+    /// Logged out, or logged out everywhere since it was issued
+    RevokedToken,
+    /// Couldn't check the revocation list, we fail closed
+    Unavailable,
+}
+
+impl AuthError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            AuthError::MissingToken => "Authorization token is missing",
+            AuthError::MalformedHeader => "Authorization header must be in the form 'Bearer <token>'",
+            AuthError::InvalidToken => "Authorization token is invalid",
+            AuthError::ExpiredToken => "Authorization token has expired",
+            AuthError::RevokedToken => "Authorization token has been revoked",
+            AuthError::Unavailable => "Unable to verify authorization token",
+        }
+    }
+}
+
+impl warp::reject::Reject for AuthError {}
+
+/// What login and refresh hand back to the client
+pub struct IssuedTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Seconds until `access_token` expires
+    pub expires_in: u64,
+}
+
+#[derive(Debug)]
+pub enum RefreshError {
+    /// Never heard of it, or it's been revoked
+    Invalid,
+    Expired,
+    /// Already swapped once, the whole family has now been revoked
+    Reused,
+    Internal(anyhow::Error),
+}
+
+impl RefreshError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            RefreshError::Invalid => "Refresh token is invalid",
+            RefreshError::Expired => "Refresh token has expired",
+            RefreshError::Reused => "Refresh token has already been used, please log in again",
+            RefreshError::Internal(_) => "Internal error",
+        }
+    }
+}
+
+impl From<anyhow::Error> for RefreshError {
+    fn from(e: anyhow::Error) -> Self {
+        RefreshError::Internal(e)
+    }
+}
+
+/// Signs a new access token for the given user and session
+pub fn issue_token(config: &AuthConfig, user_id: &str, session_id: &str, generation: i64) -> anyhow::Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let claims = Claims {
+        sub: user_id.to_string(),
+        iss: config.issuer.clone(),
+        exp: (now + config.token_lifetime_secs) as usize,
+        iat: now as usize,
+        jti: Uuid::new_v4().to_string(),
+        sid: session_id.to_string(),
+        r#gen: generation,
+    };
+
+    let token = encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+    )?;
+
+    Ok(token)
+}
+
+/// Checks the signature, expiry and issuer of a token and pulls the claims out of it.
+/// This does NOT look at the revocation list, [`authorize`] does that.
+pub fn verify_token(config: &AuthConfig, token: &str) -> Result<Claims, AuthError> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_required_spec_claims(&["exp", "iss", "sub"]);
+
+    let data = decode::<Claims>(token, &DecodingKey::from_secret(config.jwt_secret.as_bytes()), &validation)
+        .map_err(|e| match e.kind() {
+            ErrorKind::ExpiredSignature => AuthError::ExpiredToken,
+            _ => AuthError::InvalidToken,
+        })?;
+
+    Ok(data.claims)
+}
+
+/// Used by the `with_auth(config, conn)` filter, takes the raw `Authorization` header
+pub async fn authorize(header: Option<String>, config: Arc<Config>, conn: Arc<Db>) -> Result<AuthUser, Rejection> {
+    let header = header.ok_or_else(|| warp::reject::custom(AuthError::MissingToken))?;
+
+    let token = header
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| warp::reject::custom(AuthError::MalformedHeader))?;
+
+    let claims = verify_token(&config.auth, token).map_err(warp::reject::custom)?;
+    let uuid = Uuid::parse_str(&claims.sub).map_err(|_| warp::reject::custom(AuthError::InvalidToken))?;
+
+    check_not_revoked(&conn, &claims).await.map_err(warp::reject::custom)?;
+
+    Ok(AuthUser {
+        uuid,
+        session_id: claims.sid,
+        expires_at: claims.exp,
+    })
+}
+
+/// Same as [`authorize`], but no `Authorization` header at all is fine and gives `None`.
+/// A header that's there but bad still gets rejected, better than silently treating the
+/// caller as anonymous.
+pub async fn authorize_optional(header: Option<String>, config: Arc<Config>, conn: Arc<Db>) -> Result<Option<AuthUser>, Rejection> {
+    match header {
+        None => Ok(None),
+        Some(header) => authorize(Some(header), config, conn).await.map(Some),
+    }
+}
+
+async fn check_not_revoked(conn: &Connection, claims: &Claims) -> Result<(), AuthError> {
+    let generation = Revocations::token_generation(conn, &claims.sub).await.map_err(|e| {
+        log::error!("Failed to look up token generation: {}", e);
+        AuthError::Unavailable
+    })?;
+
+    match generation {
+        // The account is gone, so is the token
+        None => return Err(AuthError::InvalidToken),
+        Some(generation) if claims.r#gen < generation => return Err(AuthError::RevokedToken),
+        Some(_) => {}
+    }
+
+    // Every token from a logged out session, not just the one it was done with
+    let revoked = RefreshTokens::family_revoked(conn, &claims.sid).await.map_err(|e| {
+        log::error!("Failed to check whether session {} is revoked: {}", claims.sid, e);
+        AuthError::Unavailable
+    })?;
+    if revoked {
+        return Err(AuthError::RevokedToken);
+    }
+
+    Ok(())
+}
+
+/// Logs out the session the caller's token belongs to, every access token it has handed out
+/// included
+pub async fn logout(conn: &Connection, user: &AuthUser) -> anyhow::Result<()> {
+    RefreshTokens::revoke_family(conn, &user.session_id).await?;
+    Ok(())
+}
+
+/// Logs out every session the user has, on every device
+pub async fn logout_everywhere(conn: &Db, user: &AuthUser) -> anyhow::Result<()> {
+    let user_id = user.uuid.to_string();
+
+    let tx = Database::start_transaction(conn).await?;
+    let result = async {
+        Revocations::bump_token_generation(&tx, &user_id).await?;
+        RefreshTokens::revoke_all_for_user(&tx, &user_id).await?;
+        anyhow::Ok(())
+    }.await;
+
+    if let Err(e) = result {
+        let _ = tx.rollback().await;
+        return Err(e);
+    }
+    Database::commit_transaction(tx).await
+}
+
+/// Makes a random opaque refresh token, 256 bits of hex
+pub fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// What actually goes in the database, so a leaked DB doesn't leak working tokens
+pub fn hash_refresh_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Debug)]
+pub enum LoginError {
+    /// Unknown email or wrong password, on purpose you can't tell which
+    InvalidCredentials,
+    /// Too many failures for the email or from the IP, see `lockout.rs`
+    Locked { retry_after_secs: u64 },
+    Internal(anyhow::Error),
+}
+
+impl LoginError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            LoginError::InvalidCredentials => "Invalid email or password",
+            LoginError::Locked { .. } => "Too many failed logins, try again later",
+            LoginError::Internal(_) => "Internal error",
+        }
+    }
+}
+
+impl From<anyhow::Error> for LoginError {
+    fn from(e: anyhow::Error) -> Self {
+        LoginError::Internal(e)
+    }
+}
+
+/// Who just logged in
+pub struct LoggedIn {
+    pub user_id: String,
+    pub username: String,
+    pub tokens: IssuedTokens,
+}
+
+/// What unknown emails get checked against, hashed the same way as real passwords so it takes
+/// as long
+static DUMMY_PASSWORD_HASH: LazyLock<String> = LazyLock::new(|| {
+    Register::hash_password("not anybody's password").expect("Failed to hash the dummy password")
+});
+
+/// Hashes the dummy password now, otherwise the first unknown email takes longer than the rest
+pub fn prepare_login() {
+    LazyLock::force(&DUMMY_PASSWORD_HASH);
+}
+
+/// Checks an email and password and starts a session. `ip` is who's asking, for the lockout.
+pub async fn login(conn: &Db, config: &Config, email: &str, password: &str, ip: IpAddr) -> Result<LoggedIn, LoginError> {
+    let account = Register::find_login(conn, email).await?;
+    let account_uuid = account.as_ref().map(|a| a.uuid.clone());
+
+    // Locked out means no guess gets checked, right or wrong. Otherwise this guess is counted
+    // before it's checked, so parallel ones can't all sneak in under the limit.
+    let Some(reservation) = lockout::reserve(conn, &config.login, email, ip).await? else {
+        // No lock yet means the guess that used up the last try is still being checked
+        let retry_after_secs = lockout::locked_for(conn, email, ip).await?.unwrap_or(config.login.lockout_secs);
+        lockout::record_locked(conn, email, account_uuid.as_deref(), ip).await?;
+        return Err(LoginError::Locked { retry_after_secs });
+    };
+
+    let stored_hash = account.as_ref().map_or(DUMMY_PASSWORD_HASH.as_str(), |a| a.password_hash.as_str());
+    let parsed_hash = PasswordHash::new(stored_hash)
+        .map_err(|e| anyhow::anyhow!("Stored password hash for {} is invalid: {}", email, e))?;
+    let verified = Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok();
+
+    let Some(account) = account.filter(|_| verified) else {
+        lockout::record_failure(conn, &config.login, reservation, account_uuid.as_deref()).await?;
+        return Err(LoginError::InvalidCredentials);
+    };
+
+    lockout::record_success(conn, reservation).await?;
+    let tokens = start_session(conn, &config.auth, &account.uuid).await?;
+    Ok(LoggedIn {
+        user_id: account.uuid,
+        username: account.username,
+        tokens,
+    })
+}
+
+/// Starts a new token family for a user, this is what login calls
+pub async fn start_session(conn: &Connection, config: &AuthConfig, user_id: &str) -> anyhow::Result<IssuedTokens> {
+    let family_id = Uuid::new_v4().to_string();
+    issue_token_pair(conn, config, user_id, &family_id).await
+}
+
+/// Swaps a refresh token for a new access/refresh pair, returns the user it belongs to
+pub async fn rotate_refresh_token(
+    conn: &Db,
+    config: &AuthConfig,
+    refresh_token: &str,
+) -> Result<(String, IssuedTokens), RefreshError> {
+    let tx = Database::start_transaction(conn).await?;
+
+    let stored = match RefreshTokens::find(&tx, &hash_refresh_token(refresh_token)).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => {
+            let _ = tx.rollback().await;
+            return Err(RefreshError::Invalid);
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            return Err(e.into());
+        }
+    };
+
+    if stored.revoked_at.is_some() {
+        let _ = tx.rollback().await;
+        return Err(RefreshError::Invalid);
+    }
+
+    if stored.used_at.is_some() {
+        // Somebody is replaying an old token, burn the whole family
+        let revoked = RefreshTokens::revoke_family(&tx, &stored.family_id).await;
+        Database::commit_transaction(tx).await?;
+        let revoked = revoked?;
+        log::warn!("Refresh token reuse detected for user {}, revoked {} token(s) in family {}",
+            stored.user_uuid, revoked, stored.family_id);
+        return Err(RefreshError::Reused);
+    }
+
+    if stored.expires_at <= Utc::now() {
+        let _ = tx.rollback().await;
+        return Err(RefreshError::Expired);
+    }
+
+    let result = async {
+        RefreshTokens::mark_used(&tx, stored.id).await?;
+        issue_token_pair(&tx, config, &stored.user_uuid, &stored.family_id).await
+    }.await;
+
+    match result {
+        Ok(tokens) => {
+            Database::commit_transaction(tx).await?;
+            Ok((stored.user_uuid, tokens))
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e.into())
+        }
+    }
+}
+
+async fn issue_token_pair(conn: &Connection, config: &AuthConfig, user_id: &str, family_id: &str) -> anyhow::Result<IssuedTokens> {
+    let generation = Revocations::token_generation(conn, user_id).await?
+        .ok_or_else(|| anyhow::anyhow!("User {} does not exist", user_id))?;
+    let access_token = issue_token(config, user_id, family_id, generation)?;
+
+    let refresh_token = generate_refresh_token();
+    let expires_at = Utc::now() + Duration::seconds(config.refresh_token_lifetime_secs as i64);
+    RefreshTokens::insert(conn, user_id, family_id, &hash_refresh_token(&refresh_token), expires_at).await?;
+
+    Ok(IssuedTokens {
+        access_token,
+        refresh_token,
+        expires_in: config.token_lifetime_secs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{account, test_db};
+
+    fn auth_config() -> AuthConfig {
+        AuthConfig { jwt_secret: "a test secret that's long enough to sign with".to_string(), ..AuthConfig::default() }
+    }
+
+    /// Signs whatever claims it's given, for tokens `issue_token` wouldn't make
+    fn sign(config: &AuthConfig, claims: &Claims) -> String {
+        encode(&Header::new(Algorithm::HS256), claims, &EncodingKey::from_secret(config.jwt_secret.as_bytes())).unwrap()
+    }
+
+    fn claims(config: &AuthConfig, exp: u64) -> Claims {
+        Claims {
+            sub: Uuid::new_v4().to_string(),
+            iss: config.issuer.clone(),
+            exp: exp as usize,
+            iat: exp as usize - 60,
+            jti: Uuid::new_v4().to_string(),
+            sid: Uuid::new_v4().to_string(),
+            r#gen: 0,
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn issued_tokens_verify() {
+        let config = auth_config();
+        let token = issue_token(&config, "user", "session", 3).unwrap();
+
+        let claims = verify_token(&config, &token).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.sid.as_str(), claims.r#gen), ("user", "session", 3));
+        assert!(claims.exp as u64 > now() && claims.exp as u64 <= now() + config.token_lifetime_secs);
+    }
+
+    #[test]
+    fn expired_tokens_dont() {
+        let config = auth_config();
+        // Past jsonwebtoken's default minute of leeway
+        let token = sign(&config, &claims(&config, now() - 120));
+
+        assert!(matches!(verify_token(&config, &token), Err(AuthError::ExpiredToken)));
+    }
+
+    #[test]
+    fn forged_tokens_dont() {
+        let config = auth_config();
+        let token = issue_token(&config, "user", "session", 0).unwrap();
+
+        let other_secret = AuthConfig { jwt_secret: "somebody else's secret, also long enough".to_string(), ..auth_config() };
+        assert!(matches!(verify_token(&other_secret, &token), Err(AuthError::InvalidToken)));
+
+        let other_issuer = AuthConfig { issuer: "someone-else".to_string(), ..auth_config() };
+        assert!(matches!(verify_token(&config, &sign(&other_issuer, &claims(&other_issuer, now() + 60))), Err(AuthError::InvalidToken)));
+
+        // Change the first character of the signature
+        let (unsigned, signature) = token.rsplit_once('.').unwrap();
+        let swapped = if signature.starts_with('A') { 'B' } else { 'A' };
+        let tampered = format!("{}.{}{}", unsigned, swapped, &signature[1..]);
+        assert!(matches!(verify_token(&config, &tampered), Err(AuthError::InvalidToken)));
+        assert!(matches!(verify_token(&config, "not.a.token"), Err(AuthError::InvalidToken)));
+    }
+
+    #[tokio::test]
+    async fn authorize_wants_a_bearer_token() {
+        let db = test_db().await;
+        let config = Arc::new(Config { auth: auth_config(), ..Config::default() });
+        let alice = account(&db, "alice").await;
+        let token = issue_token(&config.auth, &alice, "session", 0).unwrap();
+
+        let missing = authorize(None, config.clone(), db.clone()).await;
+        assert_eq!(auth_error(missing), Some(AuthError::MissingToken.message()));
+        let basic = authorize(Some(format!("Basic {}", token)), config.clone(), db.clone()).await;
+        assert_eq!(auth_error(basic), Some(AuthError::MalformedHeader.message()));
+        let empty = authorize(Some("Bearer  ".to_string()), config.clone(), db.clone()).await;
+        assert_eq!(auth_error(empty), Some(AuthError::MalformedHeader.message()));
+
+        // Signed fine, but the subject isn't a user id
+        let not_a_uuid = sign(&config.auth, &Claims { sub: "alice".to_string(), ..claims(&config.auth, now() + 60) });
+        assert_eq!(auth_error(authorize_token(&db, &config, &not_a_uuid).await), Some(AuthError::InvalidToken.message()));
+
+        let user = authorize_token(&db, &config, &token).await.unwrap();
+        assert_eq!(user.uuid.to_string(), alice);
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_rotate_once() {
+        let db = test_db().await;
+        let config = auth_config();
+        let alice = account(&db, "alice").await;
+        let first = start_session(&db, &config, &alice).await.unwrap();
+
+        let (user_id, second) = rotate_refresh_token(&db, &config, &first.refresh_token).await.unwrap();
+        assert_eq!(user_id, alice);
+        assert_ne!(second.refresh_token, first.refresh_token);
+        assert_eq!(verify_token(&config, &second.access_token).unwrap().sid, verify_token(&config, &first.access_token).unwrap().sid);
+
+        assert!(matches!(rotate_refresh_token(&db, &config, "never issued").await, Err(RefreshError::Invalid)));
+    }
+
+    /// Swapping a token twice means someone has a copy, the whole family goes including what
+    /// the first swap handed out. Other logins are left alone.
+    #[tokio::test]
+    async fn reusing_a_refresh_token_burns_the_family() {
+        let db = test_db().await;
+        let config = auth_config();
+        let alice = account(&db, "alice").await;
+        let first = start_session(&db, &config, &alice).await.unwrap();
+        let elsewhere = start_session(&db, &config, &alice).await.unwrap();
+
+        let (_, second) = rotate_refresh_token(&db, &config, &first.refresh_token).await.unwrap();
+        assert!(matches!(rotate_refresh_token(&db, &config, &first.refresh_token).await, Err(RefreshError::Reused)));
+        assert!(matches!(rotate_refresh_token(&db, &config, &second.refresh_token).await, Err(RefreshError::Invalid)));
+
+        rotate_refresh_token(&db, &config, &elsewhere.refresh_token).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_refresh_tokens_dont_rotate() {
+        let db = test_db().await;
+        let config = AuthConfig { refresh_token_lifetime_secs: 0, ..auth_config() };
+        let alice = account(&db, "alice").await;
+        let session = start_session(&db, &config, &alice).await.unwrap();
+
+        assert!(matches!(rotate_refresh_token(&db, &config, &session.refresh_token).await, Err(RefreshError::Expired)));
+    }
+
+    #[tokio::test]
+    async fn only_the_hash_is_stored() {
+        let db = test_db().await;
+        let alice = account(&db, "alice").await;
+        let session = start_session(&db, &auth_config(), &alice).await.unwrap();
+
+        let mut rows = db.query("SELECT token_hash FROM refresh_tokens", ()).await.unwrap();
+        let stored: String = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(stored, hash_refresh_token(&session.refresh_token));
+        assert_ne!(stored, session.refresh_token);
+    }
+
+    /// Bumping the generation kills every access and refresh token the user has, tokens issued
+    /// afterwards are fine
+    #[tokio::test]
+    async fn logout_everywhere_bumps_the_generation() {
+        let db = test_db().await;
+        let config = Arc::new(Config { auth: auth_config(), ..Config::default() });
+        let alice = account(&db, "alice").await;
+        let bob = account(&db, "bob").await;
+
+        let here = start_session(&db, &config.auth, &alice).await.unwrap();
+        let there = start_session(&db, &config.auth, &alice).await.unwrap();
+        let bobs = start_session(&db, &config.auth, &bob).await.unwrap();
+
+        let user = authorize_token(&db, &config, &here.access_token).await.unwrap();
+        logout_everywhere(&db, &user).await.unwrap();
+
+        for session in [&here, &there] {
+            assert_eq!(auth_error(authorize_token(&db, &config, &session.access_token).await), Some(AuthError::RevokedToken.message()));
+            assert!(matches!(rotate_refresh_token(&db, &config.auth, &session.refresh_token).await, Err(RefreshError::Invalid)));
+        }
+        assert!(authorize_token(&db, &config, &bobs.access_token).await.is_ok());
+
+        let again = start_session(&db, &config.auth, &alice).await.unwrap();
+        assert_eq!(verify_token(&config.auth, &again.access_token).unwrap().r#gen, 1);
+        assert!(authorize_token(&db, &config, &again.access_token).await.is_ok());
+    }
+
+    /// A token for an account that's gone is no good, even if nothing revoked it
+    #[tokio::test]
+    async fn tokens_for_missing_accounts_are_invalid() {
+        let db = test_db().await;
+        let config = Arc::new(Config { auth: auth_config(), ..Config::default() });
+        let token = issue_token(&config.auth, &Uuid::new_v4().to_string(), "session", 0).unwrap();
+
+        assert_eq!(auth_error(authorize_token(&db, &config, &token).await), Some(AuthError::InvalidToken.message()));
+    }
+
+    async fn authorize_token(db: &Arc<Db>, config: &Arc<Config>, token: &str) -> Result<AuthUser, Rejection> {
+        authorize(Some(format!("Bearer {}", token)), config.clone(), db.clone()).await
+    }
+
+    fn auth_error(result: Result<AuthUser, Rejection>) -> Option<&'static str> {
+        result.err().and_then(|rejection| rejection.find::<AuthError>().map(AuthError::message))
+    }
+
+    /// Logging out with the latest access token also kills the ones earlier refreshes handed
+    /// out, but not other sessions
+    #[tokio::test]
+    async fn logout_revokes_the_whole_session() {
+        let db = test_db().await;
+        let config = Arc::new(Config::default());
+        let alice = account(&db, "alice").await;
+
+        let first = start_session(&db, &config.auth, &alice).await.unwrap();
+        let (_, second) = rotate_refresh_token(&db, &config.auth, &first.refresh_token).await.unwrap();
+        let elsewhere = start_session(&db, &config.auth, &alice).await.unwrap();
+
+        let user = authorize_token(&db, &config, &second.access_token).await.unwrap();
+        logout(&db, &user).await.unwrap();
+
+        for token in [&first.access_token, &second.access_token] {
+            assert_eq!(auth_error(authorize_token(&db, &config, token).await), Some(AuthError::RevokedToken.message()));
+        }
+        assert!(authorize_token(&db, &config, &elsewhere.access_token).await.is_ok());
+    }
+
+    fn localhost() -> IpAddr {
+        "127.0.0.1".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn login_checks_the_password() {
+        let db = test_db().await;
+        let config = Config { auth: auth_config(), ..Config::default() };
+        let alice = account(&db, "alice").await;
+
+        let logged_in = login(&db, &config, "alice@example.com", "correct horse 1", localhost()).await.unwrap();
+        assert_eq!((logged_in.user_id.as_str(), logged_in.username.as_str()), (alice.as_str(), "alice"));
+        assert!(matches!(login(&db, &config, "alice@example.com", "correct horse 2", localhost()).await, Err(LoginError::InvalidCredentials)));
+    }
+
+    /// An unknown email gets the same answer as a wrong password, and counts towards the lockout
+    /// the same way
+    #[tokio::test]
+    async fn unknown_emails_look_like_wrong_passwords() {
+        let db = test_db().await;
+        let mut config = Config { auth: auth_config(), ..Config::default() };
+        config.login.max_failures = 2;
+
+        for _ in 0..2 {
+            assert!(matches!(login(&db, &config, "nobody@example.com", "correct horse 1", localhost()).await, Err(LoginError::InvalidCredentials)));
+        }
+        assert!(matches!(login(&db, &config, "nobody@example.com", "correct horse 1", localhost()).await, Err(LoginError::Locked { .. })));
+    }
+
+    #[test]
+    fn dummy_hash_is_a_real_hash() {
+        let parsed_hash = PasswordHash::new(&DUMMY_PASSWORD_HASH).unwrap();
+
+        assert!(Argon2::default().verify_password(b"", &parsed_hash).is_err());
+        // Same cost as the real thing, or the timing gives unknown emails away
+        let real_hash = Register::hash_password("correct horse 1").unwrap();
+        assert_eq!(parsed_hash.params, PasswordHash::new(&real_hash).unwrap().params);
+    }
+}
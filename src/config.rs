@@ -0,0 +1,535 @@
+//! Configuration module
+//!
+//! Settings are read from a TOML file (`winklink.toml` in the working directory, or whatever
+//! `WINKLINK_CONFIG` points at) and then any `WINKLINK_*` environment variables are layered on
+//! top. The file is optional, the environment is enough on its own. See
+//! `winklink.example.toml` for every key.
+//!
+//! To load, use ```Config::load()?```. It validates everything before handing it back, so if
+//! this returns `Ok` you can trust what's inside.
+//!
+//! | Environment variable                   | Config key                                 |
+//! |----------------------------------------|--------------------------------------------|
+//! | `WINKLINK_HOST`                        | `server.host`                              |
+//! | `WINKLINK_PORT`                        | `server.port`                              |
+//! | `WINKLINK_STATIC_DIR`                  | `server.static_dir`                        |
+//! | `WINKLINK_TRUST_FORWARDED_FOR`         | `server.trust_forwarded_for`               |
+//! | `WINKLINK_DATABASE_PATH`               | `database.path`                            |
+//! | `WINKLINK_JWT_SECRET`                  | `auth.jwt_secret`                          |
+//! | `WINKLINK_JWT_ISSUER`                  | `auth.issuer`                              |
+//! | `WINKLINK_TOKEN_LIFETIME`              | `auth.token_lifetime_secs`                 |
+//! | `WINKLINK_REFRESH_TOKEN_LIFETIME`      | `auth.refresh_token_lifetime_secs`         |
+//! | `WINKLINK_LOGIN_MAX_FAILURES`          | `login.max_failures`                       |
+//! | `WINKLINK_LOGIN_MAX_FAILURES_PER_IP`   | `login.max_failures_per_ip`                |
+//! | `WINKLINK_LOGIN_LOCKOUT`               | `login.lockout_secs`                       |
+//! | `WINKLINK_LOGIN_MAX_LOCKOUT`           | `login.max_lockout_secs`                   |
+//! | `WINKLINK_LOGIN_FAILURE_WINDOW`        | `login.failure_window_secs`                |
+//! | `WINKLINK_LOGIN_AUDIT_RETENTION`       | `login.audit_retention_secs`               |
+//! | `WINKLINK_TRANSFER_OFFER_LIFETIME`     | `devices.transfer_offer_lifetime_secs`     |
+//! | `WINKLINK_CHALLENGE_LIFETIME`          | `devices.challenge_lifetime_secs`          |
+//! | `WINKLINK_LOOKUP_RATE_LIMIT`           | `devices.lookup_rate_limit`                |
+//! | `WINKLINK_LOOKUP_RATE_LIMIT_WINDOW`    | `devices.lookup_rate_limit_window_secs`    |
+//! | `WINKLINK_CHALLENGE_RATE_LIMIT`        | `devices.challenge_rate_limit`             |
+//! | `WINKLINK_CHALLENGE_RATE_LIMIT_WINDOW` | `devices.challenge_rate_limit_window_secs` |
+//! | `WINKLINK_BATCH_LOOKUP_MAX`            | `devices.batch_lookup_max`                 |
+//! | `WINKLINK_DEVICE_ONLINE_TIMEOUT`       | `devices.online_timeout_secs`              |
+//! | `WINKLINK_WINK_RATE_LIMIT`             | `winks.rate_limit`                         |
+//! | `WINKLINK_WINK_RATE_LIMIT_WINDOW`      | `winks.rate_limit_window_secs`             |
+//! | `WINKLINK_WINK_TTL`                    | `winks.ttl_secs`                           |
+//! | `WINKLINK_WINK_MAX_TTL`                | `winks.max_ttl_secs`                       |
+//! | `WINKLINK_WINK_DEAD_LETTER_RETENTION`  | `winks.dead_letter_retention_secs`         |
+//! | `WINKLINK_REALTIME_QUEUE_SIZE`         | `realtime.queue_size`                      |
+//! | `WINKLINK_REALTIME_MAX_CONNECTIONS`    | `realtime.max_connections_per_client`      |
+//! | `WINKLINK_HEARTBEAT_INTERVAL`          | `realtime.heartbeat_interval_secs`         |
+//! | `WINKLINK_HEARTBEAT_TIMEOUT`           | `realtime.heartbeat_timeout_secs`          |
+//! | `WINKLINK_EVENT_RETENTION`             | `realtime.event_retention_secs`            |
+//! | `WINKLINK_EVENT_REPLAY_LIMIT`          | `realtime.replay_limit`                    |
+
+use std::{env, fmt, fs, net::{IpAddr, Ipv4Addr, SocketAddr}, path::PathBuf, str::FromStr};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+const DEFAULT_CONFIG_PATH: &str = "winklink.toml";
+const MIN_SECRET_LENGTH: usize = 32;
+
+/// Secrets people copy out of tutorials, we refuse to start with any of these
+const KNOWN_WEAK_SECRETS: &[&str] = &["your_secret_key", "secret", "changeme", "change_me", "jwt_secret"];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub auth: AuthConfig,
+    pub login: LoginConfig,
+    pub devices: DevicesConfig,
+    pub winks: WinksConfig,
+    pub realtime: RealtimeConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: IpAddr,
+    pub port: u16,
+    pub static_dir: PathBuf,
+    /// Behind a reverse proxy, take the client's IP from `X-Forwarded-For` instead of the socket
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 3030,
+            static_dir: PathBuf::from("./src/static"),
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: PathBuf,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("winklink.db"),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// HS256 signing key, no default on purpose
+    pub jwt_secret: String,
+    pub issuer: String,
+    /// How long access tokens last, keep this short
+    pub token_lifetime_secs: u64,
+    /// How long a refresh token can sit unused before it stops working
+    pub refresh_token_lifetime_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            jwt_secret: String::new(),
+            issuer: "winklink-web-api".to_string(),
+            token_lifetime_secs: 15 * 60,
+            refresh_token_lifetime_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginConfig {
+    /// Failed logins for one email before it's locked out
+    pub max_failures: u32,
+    /// Failed logins from one IP, across any emails, before it's locked out
+    pub max_failures_per_ip: u32,
+    /// How long the first lockout lasts, each failure after that doubles it
+    pub lockout_secs: u64,
+    /// The doubling stops here
+    pub max_lockout_secs: u64,
+    /// Failures are forgotten after this long without another one
+    pub failure_window_secs: u64,
+    /// How long failed logins are kept in `login_failures`
+    pub audit_retention_secs: u64,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            max_failures_per_ip: 20,
+            lockout_secs: 60,
+            max_lockout_secs: 60 * 60,
+            failure_window_secs: 24 * 60 * 60,
+            audit_retention_secs: 90 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DevicesConfig {
+    /// How long the recipient has to accept a transfer before the code stops working
+    pub transfer_offer_lifetime_secs: u64,
+    /// How long a device has to sign a nonce from `GET /api/devices/{serial}/challenge`
+    pub challenge_lifetime_secs: u64,
+    /// A device that hasn't been heard from in this long is offline
+    pub online_timeout_secs: u64,
+    /// How many anonymous lookups one IP can make per `lookup_rate_limit_window_secs`
+    pub lookup_rate_limit: u32,
+    pub lookup_rate_limit_window_secs: u64,
+    /// How many challenges one IP can ask for, across all serial numbers, per
+    /// `challenge_rate_limit_window_secs`
+    pub challenge_rate_limit: u32,
+    pub challenge_rate_limit_window_secs: u64,
+    /// Most serial numbers in one `POST /api/devices:batchLookup`
+    pub batch_lookup_max: usize,
+}
+
+impl Default for DevicesConfig {
+    fn default() -> Self {
+        Self {
+            transfer_offer_lifetime_secs: 48 * 60 * 60,
+            challenge_lifetime_secs: 5 * 60,
+            online_timeout_secs: 2 * 60,
+            lookup_rate_limit: 20,
+            lookup_rate_limit_window_secs: 60,
+            challenge_rate_limit: 10,
+            challenge_rate_limit_window_secs: 60,
+            batch_lookup_max: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WinksConfig {
+    /// How many winks one device can send per `rate_limit_window_secs`
+    pub rate_limit: u32,
+    pub rate_limit_window_secs: u64,
+    /// How long a wink waits to be delivered before it's a dead letter, unless the sender asks
+    /// for something else
+    pub ttl_secs: u64,
+    /// The most a sender can ask for
+    pub max_ttl_secs: u64,
+    /// How long dead letters stick around for senders to look at
+    pub dead_letter_retention_secs: u64,
+}
+
+impl Default for WinksConfig {
+    fn default() -> Self {
+        Self {
+            rate_limit: 30,
+            rate_limit_window_secs: 60,
+            ttl_secs: 24 * 60 * 60,
+            max_ttl_secs: 7 * 24 * 60 * 60,
+            dead_letter_retention_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RealtimeConfig {
+    /// How many events can wait for one connection before it's closed for being too slow
+    pub queue_size: usize,
+    /// Per device or account, opening another closes the oldest
+    pub max_connections_per_client: usize,
+    pub heartbeat_interval_secs: u64,
+    /// Connections we haven't heard from in this long are dropped
+    pub heartbeat_timeout_secs: u64,
+    /// How long account events are kept for SSE clients to catch up on
+    pub event_retention_secs: u64,
+    /// Most events replayed on one SSE reconnect, the client reconnects again for the rest
+    pub replay_limit: u32,
+}
+
+impl Default for RealtimeConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 64,
+            max_connections_per_client: 5,
+            heartbeat_interval_secs: 30,
+            heartbeat_timeout_secs: 75,
+            event_retention_secs: 7 * 24 * 60 * 60,
+            replay_limit: 500,
+        }
+    }
+}
+
+// Hand written so the secret never ends up in a log line
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("jwt_secret", &"<redacted>")
+            .field("issuer", &self.issuer)
+            .field("token_lifetime_secs", &self.token_lifetime_secs)
+            .field("refresh_token_lifetime_secs", &self.refresh_token_lifetime_secs)
+            .finish()
+    }
+}
+
+impl Config {
+    /// Reads the config file (if any), applies environment overrides and validates the result
+    pub fn load() -> anyhow::Result<Self> {
+        let (path, required) = match env::var("WINKLINK_CONFIG") {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let mut config = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read config file {}", path.display()))?;
+            let config: Config = toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+            log::debug!("Loaded config from {}", path.display());
+            config
+        } else if required {
+            // If someone pointed us at a file, not finding it is an error rather than a shrug
+            anyhow::bail!("Config file {} does not exist", path.display());
+        } else {
+            Config::default()
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        if let Some(host) = env_parse("WINKLINK_HOST")? {
+            self.server.host = host;
+        }
+        if let Some(port) = env_parse("WINKLINK_PORT")? {
+            self.server.port = port;
+        }
+        if let Ok(static_dir) = env::var("WINKLINK_STATIC_DIR") {
+            self.server.static_dir = PathBuf::from(static_dir);
+        }
+        if let Some(trust) = env_parse("WINKLINK_TRUST_FORWARDED_FOR")? {
+            self.server.trust_forwarded_for = trust;
+        }
+        if let Ok(path) = env::var("WINKLINK_DATABASE_PATH") {
+            self.database.path = PathBuf::from(path);
+        }
+        if let Ok(secret) = env::var("WINKLINK_JWT_SECRET") {
+            self.auth.jwt_secret = secret;
+        }
+        if let Ok(issuer) = env::var("WINKLINK_JWT_ISSUER") {
+            self.auth.issuer = issuer;
+        }
+        if let Some(lifetime) = env_parse("WINKLINK_TOKEN_LIFETIME")? {
+            self.auth.token_lifetime_secs = lifetime;
+        }
+        if let Some(lifetime) = env_parse("WINKLINK_REFRESH_TOKEN_LIFETIME")? {
+            self.auth.refresh_token_lifetime_secs = lifetime;
+        }
+        if let Some(max) = env_parse("WINKLINK_LOGIN_MAX_FAILURES")? {
+            self.login.max_failures = max;
+        }
+        if let Some(max) = env_parse("WINKLINK_LOGIN_MAX_FAILURES_PER_IP")? {
+            self.login.max_failures_per_ip = max;
+        }
+        if let Some(lockout) = env_parse("WINKLINK_LOGIN_LOCKOUT")? {
+            self.login.lockout_secs = lockout;
+        }
+        if let Some(lockout) = env_parse("WINKLINK_LOGIN_MAX_LOCKOUT")? {
+            self.login.max_lockout_secs = lockout;
+        }
+        if let Some(window) = env_parse("WINKLINK_LOGIN_FAILURE_WINDOW")? {
+            self.login.failure_window_secs = window;
+        }
+        if let Some(retention) = env_parse("WINKLINK_LOGIN_AUDIT_RETENTION")? {
+            self.login.audit_retention_secs = retention;
+        }
+        if let Some(lifetime) = env_parse("WINKLINK_TRANSFER_OFFER_LIFETIME")? {
+            self.devices.transfer_offer_lifetime_secs = lifetime;
+        }
+        if let Some(lifetime) = env_parse("WINKLINK_CHALLENGE_LIFETIME")? {
+            self.devices.challenge_lifetime_secs = lifetime;
+        }
+        if let Some(timeout) = env_parse("WINKLINK_DEVICE_ONLINE_TIMEOUT")? {
+            self.devices.online_timeout_secs = timeout;
+        }
+        if let Some(limit) = env_parse("WINKLINK_LOOKUP_RATE_LIMIT")? {
+            self.devices.lookup_rate_limit = limit;
+        }
+        if let Some(window) = env_parse("WINKLINK_LOOKUP_RATE_LIMIT_WINDOW")? {
+            self.devices.lookup_rate_limit_window_secs = window;
+        }
+        if let Some(limit) = env_parse("WINKLINK_CHALLENGE_RATE_LIMIT")? {
+            self.devices.challenge_rate_limit = limit;
+        }
+        if let Some(window) = env_parse("WINKLINK_CHALLENGE_RATE_LIMIT_WINDOW")? {
+            self.devices.challenge_rate_limit_window_secs = window;
+        }
+        if let Some(max) = env_parse("WINKLINK_BATCH_LOOKUP_MAX")? {
+            self.devices.batch_lookup_max = max;
+        }
+        if let Some(limit) = env_parse("WINKLINK_WINK_RATE_LIMIT")? {
+            self.winks.rate_limit = limit;
+        }
+        if let Some(window) = env_parse("WINKLINK_WINK_RATE_LIMIT_WINDOW")? {
+            self.winks.rate_limit_window_secs = window;
+        }
+        if let Some(ttl) = env_parse("WINKLINK_WINK_TTL")? {
+            self.winks.ttl_secs = ttl;
+        }
+        if let Some(ttl) = env_parse("WINKLINK_WINK_MAX_TTL")? {
+            self.winks.max_ttl_secs = ttl;
+        }
+        if let Some(retention) = env_parse("WINKLINK_WINK_DEAD_LETTER_RETENTION")? {
+            self.winks.dead_letter_retention_secs = retention;
+        }
+        if let Some(size) = env_parse("WINKLINK_REALTIME_QUEUE_SIZE")? {
+            self.realtime.queue_size = size;
+        }
+        if let Some(max) = env_parse("WINKLINK_REALTIME_MAX_CONNECTIONS")? {
+            self.realtime.max_connections_per_client = max;
+        }
+        if let Some(interval) = env_parse("WINKLINK_HEARTBEAT_INTERVAL")? {
+            self.realtime.heartbeat_interval_secs = interval;
+        }
+        if let Some(timeout) = env_parse("WINKLINK_HEARTBEAT_TIMEOUT")? {
+            self.realtime.heartbeat_timeout_secs = timeout;
+        }
+        if let Some(retention) = env_parse("WINKLINK_EVENT_RETENTION")? {
+            self.realtime.event_retention_secs = retention;
+        }
+        if let Some(limit) = env_parse("WINKLINK_EVENT_REPLAY_LIMIT")? {
+            self.realtime.replay_limit = limit;
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let secret = self.auth.jwt_secret.trim();
+        if secret.is_empty() {
+            anyhow::bail!("auth.jwt_secret is not set (use the config file or WINKLINK_JWT_SECRET)");
+        }
+        if secret.len() < MIN_SECRET_LENGTH {
+            anyhow::bail!("auth.jwt_secret must be at least {} characters long", MIN_SECRET_LENGTH);
+        }
+        if KNOWN_WEAK_SECRETS.iter().any(|weak| secret.eq_ignore_ascii_case(weak)) {
+            anyhow::bail!("auth.jwt_secret is a well known placeholder, pick a random one");
+        }
+        // Something like "aaaaaaaa..." is long enough but still useless
+        let mut distinct: Vec<char> = secret.chars().collect();
+        distinct.sort_unstable();
+        distinct.dedup();
+        if distinct.len() < 8 {
+            anyhow::bail!("auth.jwt_secret is too repetitive, pick a random one");
+        }
+
+        if self.auth.issuer.trim().is_empty() {
+            anyhow::bail!("auth.issuer must not be empty");
+        }
+        if self.auth.token_lifetime_secs == 0 {
+            anyhow::bail!("auth.token_lifetime_secs must be greater than zero");
+        }
+        if self.auth.refresh_token_lifetime_secs <= self.auth.token_lifetime_secs {
+            anyhow::bail!("auth.refresh_token_lifetime_secs must be longer than auth.token_lifetime_secs");
+        }
+
+        if self.login.max_failures == 0 {
+            anyhow::bail!("login.max_failures must be greater than zero");
+        }
+        if self.login.max_failures_per_ip == 0 {
+            anyhow::bail!("login.max_failures_per_ip must be greater than zero");
+        }
+        if self.login.lockout_secs == 0 {
+            anyhow::bail!("login.lockout_secs must be greater than zero");
+        }
+        if self.login.max_lockout_secs < self.login.lockout_secs {
+            anyhow::bail!("login.max_lockout_secs can't be shorter than login.lockout_secs");
+        }
+        if self.login.failure_window_secs == 0 {
+            anyhow::bail!("login.failure_window_secs must be greater than zero");
+        }
+        if self.login.audit_retention_secs == 0 {
+            anyhow::bail!("login.audit_retention_secs must be greater than zero");
+        }
+
+        if self.devices.transfer_offer_lifetime_secs == 0 {
+            anyhow::bail!("devices.transfer_offer_lifetime_secs must be greater than zero");
+        }
+        if self.devices.challenge_lifetime_secs == 0 {
+            anyhow::bail!("devices.challenge_lifetime_secs must be greater than zero");
+        }
+        if self.devices.online_timeout_secs == 0 {
+            anyhow::bail!("devices.online_timeout_secs must be greater than zero");
+        }
+        if self.devices.lookup_rate_limit == 0 {
+            anyhow::bail!("devices.lookup_rate_limit must be greater than zero");
+        }
+        if self.devices.lookup_rate_limit_window_secs == 0 {
+            anyhow::bail!("devices.lookup_rate_limit_window_secs must be greater than zero");
+        }
+        if self.devices.challenge_rate_limit == 0 {
+            anyhow::bail!("devices.challenge_rate_limit must be greater than zero");
+        }
+        if self.devices.challenge_rate_limit_window_secs == 0 {
+            anyhow::bail!("devices.challenge_rate_limit_window_secs must be greater than zero");
+        }
+        if self.devices.batch_lookup_max == 0 {
+            anyhow::bail!("devices.batch_lookup_max must be greater than zero");
+        }
+
+        if self.winks.rate_limit == 0 {
+            anyhow::bail!("winks.rate_limit must be greater than zero");
+        }
+        if self.winks.rate_limit_window_secs == 0 {
+            anyhow::bail!("winks.rate_limit_window_secs must be greater than zero");
+        }
+        if self.winks.ttl_secs == 0 {
+            anyhow::bail!("winks.ttl_secs must be greater than zero");
+        }
+        if self.winks.max_ttl_secs < self.winks.ttl_secs {
+            anyhow::bail!("winks.max_ttl_secs can't be shorter than winks.ttl_secs");
+        }
+
+        if self.realtime.queue_size == 0 {
+            anyhow::bail!("realtime.queue_size must be greater than zero");
+        }
+        if self.realtime.max_connections_per_client == 0 {
+            anyhow::bail!("realtime.max_connections_per_client must be greater than zero");
+        }
+        if self.realtime.heartbeat_interval_secs == 0 {
+            anyhow::bail!("realtime.heartbeat_interval_secs must be greater than zero");
+        }
+        // Has to give the client at least one ping to answer
+        if self.realtime.heartbeat_timeout_secs <= self.realtime.heartbeat_interval_secs {
+            anyhow::bail!("realtime.heartbeat_timeout_secs must be longer than realtime.heartbeat_interval_secs");
+        }
+        if self.realtime.event_retention_secs == 0 {
+            anyhow::bail!("realtime.event_retention_secs must be greater than zero");
+        }
+        if self.realtime.replay_limit == 0 {
+            anyhow::bail!("realtime.replay_limit must be greater than zero");
+        }
+
+        if self.server.port == 0 {
+            anyhow::bail!("server.port must not be 0");
+        }
+        if !self.server.static_dir.is_dir() {
+            anyhow::bail!("server.static_dir {} is not a directory", self.server.static_dir.display());
+        }
+        if self.database.path.as_os_str().is_empty() {
+            anyhow::bail!("database.path must not be empty");
+        }
+
+        Ok(())
+    }
+}
+
+fn env_parse<T>(key: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("Invalid value for {}: {}", key, e)),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,245 @@
+//! Contacts module
+//!
+//! Who is allowed to wink whom. Contacts are between devices, not accounts: my WinkLink and
+//! yours. The owner manages them on the device's behalf:
+//!
+//! 1. A sends a request to B's serial number ([`ContactAction::Request`])
+//! 2. B accepts or declines it. If B had also sent A a request the two cancel out and they're
+//!    contacts straight away.
+//! 3. Either side can remove the other later, or block them outright
+//!
+//! Only contacts can wink at each other (see `wink.rs`). A block also hides the blocker from
+//! the blocked owner's device lookups and keeps anything the blocked device already sent out
+//! of the inbox. The other side is never told about a block or a decline, their requests just
+//! go nowhere.
+//!
+//! Contacts belong to the device, so they're all dropped when it's released or transferred.
+
+use libsql::Connection;
+
+use crate::database::{ContactRecord, DeviceLinks, Devices};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactAction {
+    Request,
+    Accept,
+    Decline,
+    /// Drop a contact, or take back a request that hasn't been answered
+    Remove,
+    Block,
+    Unblock,
+}
+
+impl ContactAction {
+    /// From the last bit of `/api/contacts/<action>`
+    pub fn from_path(action: &str) -> Option<Self> {
+        match action {
+            "request" => Some(ContactAction::Request),
+            "accept" => Some(ContactAction::Accept),
+            "decline" => Some(ContactAction::Decline),
+            "remove" => Some(ContactAction::Remove),
+            "block" => Some(ContactAction::Block),
+            "unblock" => Some(ContactAction::Unblock),
+            _ => None,
+        }
+    }
+}
+
+/// Where two devices stand after an action
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Requested,
+    /// Looks exactly like `Requested` to the sender, but they're blocked so nothing happened
+    Ignored,
+    Connected,
+    Declined,
+    Removed,
+    Blocked,
+    Unblocked,
+}
+
+impl Outcome {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Outcome::Requested | Outcome::Ignored => "Contact request sent",
+            Outcome::Connected => "You are now contacts",
+            Outcome::Declined => "Contact request declined",
+            Outcome::Removed => "Contact removed",
+            Outcome::Blocked => "Device blocked",
+            Outcome::Unblocked => "Device unblocked",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ContactError {
+    NoSuchDevice,
+    NotOwner,
+    /// The device on the other end isn't claimed by anyone
+    ContactNotFound,
+    CannotContactSelf,
+    AlreadyRequested,
+    AlreadyContacts,
+    NoPendingRequest,
+    NotContacts,
+    /// You can't send a request to a device you've blocked
+    BlockedByYou,
+    NotBlocked,
+    Internal(anyhow::Error),
+}
+
+impl ContactError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ContactError::NoSuchDevice => "Device with this serial number not found",
+            ContactError::NotOwner => "You do not own this device",
+            ContactError::ContactNotFound => "No device with that serial number",
+            ContactError::CannotContactSelf => "A device can't be its own contact",
+            ContactError::AlreadyRequested => "A contact request is already pending",
+            ContactError::AlreadyContacts => "These devices are already contacts",
+            ContactError::NoPendingRequest => "There is no pending request from that device",
+            ContactError::NotContacts => "These devices are not contacts",
+            ContactError::BlockedByYou => "You have blocked that device, unblock it first",
+            ContactError::NotBlocked => "That device is not blocked",
+            ContactError::Internal(_) => "Internal error",
+        }
+    }
+}
+
+impl From<anyhow::Error> for ContactError {
+    fn from(e: anyhow::Error) -> Self {
+        ContactError::Internal(e)
+    }
+}
+
+/// Applies `action` from `serial_number`'s side, which `account_uuid` has to own
+pub async fn apply(
+    conn: &Connection,
+    account_uuid: &str,
+    serial_number: &str,
+    contact_serial_number: &str,
+    action: ContactAction,
+) -> Result<Outcome, ContactError> {
+    check_owner(conn, account_uuid, serial_number).await?;
+    if serial_number == contact_serial_number {
+        return Err(ContactError::CannotContactSelf);
+    }
+
+    let me = serial_number;
+    let them = contact_serial_number;
+    let link = DeviceLinks::find(conn, me, them).await?;
+    let status = link.as_ref().map(|l| l.status.as_str());
+    let requested_by_them = link.as_ref().is_some_and(|l| l.requested_by == them);
+    let blocked_by_me = link.as_ref().is_some_and(|l| l.blocked_by.as_deref() == Some(me));
+
+    let outcome = match action {
+        ContactAction::Request => {
+            if Devices::owner_of(conn, them).await?.is_none() {
+                return Err(ContactError::ContactNotFound);
+            }
+            match status {
+                None | Some("declined") => {
+                    DeviceLinks::set(conn, me, them, "pending", me, None).await?;
+                    Outcome::Requested
+                }
+                Some("pending") if requested_by_them => {
+                    DeviceLinks::set(conn, me, them, "accepted", them, None).await?;
+                    Outcome::Connected
+                }
+                Some("pending") => return Err(ContactError::AlreadyRequested),
+                Some("accepted") => return Err(ContactError::AlreadyContacts),
+                Some(_) if blocked_by_me => return Err(ContactError::BlockedByYou),
+                // Blocked by them, look like it worked and change nothing
+                Some(_) => Outcome::Ignored,
+            }
+        }
+        ContactAction::Accept | ContactAction::Decline => {
+            if status != Some("pending") || !requested_by_them {
+                return Err(ContactError::NoPendingRequest);
+            }
+            if action == ContactAction::Accept {
+                DeviceLinks::set(conn, me, them, "accepted", them, None).await?;
+                Outcome::Connected
+            } else {
+                DeviceLinks::set(conn, me, them, "declined", them, None).await?;
+                Outcome::Declined
+            }
+        }
+        ContactAction::Remove => {
+            // Contacts, or a request I sent that hasn't been answered yet
+            let outgoing = status == Some("pending") && !requested_by_them;
+            if status != Some("accepted") && !outgoing {
+                return Err(ContactError::NotContacts);
+            }
+            DeviceLinks::delete(conn, me, them).await?;
+            Outcome::Removed
+        }
+        ContactAction::Block => {
+            if Devices::owner_of(conn, them).await?.is_none() {
+                return Err(ContactError::ContactNotFound);
+            }
+            // If they've already blocked me the pair is blocked either way, leave their block be
+            if status != Some("blocked") {
+                DeviceLinks::set(conn, me, them, "blocked", me, Some(me)).await?;
+            }
+            Outcome::Blocked
+        }
+        ContactAction::Unblock => {
+            if !blocked_by_me {
+                return Err(ContactError::NotBlocked);
+            }
+            DeviceLinks::delete(conn, me, them).await?;
+            Outcome::Unblocked
+        }
+    };
+
+    log::debug!("Contact {:?} from {} to {}: {:?}", action, me, them, outcome);
+    Ok(outcome)
+}
+
+pub async fn check_owner(conn: &Connection, account_uuid: &str, serial_number: &str) -> Result<(), ContactError> {
+    match Devices::owner_of(conn, serial_number).await? {
+        None => Err(ContactError::NoSuchDevice),
+        Some(owner) if owner != account_uuid => Err(ContactError::NotOwner),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Are these two devices contacts?
+pub async fn are_contacts(conn: &Connection, serial_number: &str, other_serial_number: &str) -> anyhow::Result<bool> {
+    let link = DeviceLinks::find(conn, serial_number, other_serial_number).await?;
+
+    Ok(link.is_some_and(|l| l.status == "accepted"))
+}
+
+/// Everything a device is linked to. Blocks and declines the other side made are left out, and
+/// so are names for anyone who hasn't accepted or asked.
+pub async fn list(conn: &Connection, serial_number: &str) -> anyhow::Result<Vec<ContactRecord>> {
+    let mut contacts = DeviceLinks::list_for_device(conn, serial_number).await?;
+
+    contacts.retain(|c| match c.status.as_str() {
+        "blocked" => c.blocked_by.as_deref() == Some(serial_number),
+        "declined" => c.requested_by != serial_number,
+        _ => true,
+    });
+    for contact in &mut contacts {
+        let visible = contact.status == "accepted" || (contact.status == "pending" && contact.requested_by != serial_number);
+        if !visible {
+            contact.device_name = None;
+            contact.owner_username = None;
+        }
+    }
+
+    Ok(contacts)
+}
+
+/// How `contact` relates to `serial_number`: contact, incoming, outgoing, declined or blocked
+pub fn state_of(serial_number: &str, contact: &ContactRecord) -> &'static str {
+    match contact.status.as_str() {
+        "accepted" => "contact",
+        "pending" if contact.requested_by == serial_number => "outgoing",
+        "pending" => "incoming",
+        "blocked" => "blocked",
+        _ => "declined",
+    }
+}
@@ -4,11 +4,17 @@
 //! notes to myself for when I forgot how to use them. The documentation is plenty so an idiot like
 //! me knows how to use them. 
 //! 
-//! To initialise, use ```Database::init_db().await?```
+//! To initialise, use ```Database::init_db(&config.database).await?```. That also runs any
+//! pending migrations, so don't go creating tables in here, add a migration instead.
+//! 
+//! `init_db` hands back a `Db`, which is the shared connection everything outside a transaction
+//! goes through (it derefs to `Connection`, so it goes wherever one of those does). A transaction
+//! gets a connection of its own though, otherwise whatever else the server is doing at the time
+//! would end up in it, and get rolled back with it.
 //! 
 //! Transactions: 
 //! ```rust
-//! let tx = Database::start_transaction(&conn).await?;
+//! let tx = Database::start_transaction(&db).await?;
 //! {
 //!     // Put what you need here
 //! }
@@ -16,35 +22,77 @@
 //! ```
 //! 
 
+use std::{ops::Deref, time::Duration};
+
 use argon2::{password_hash::{rand_core::OsRng, SaltString}, Argon2, PasswordHasher};
 use chrono::{DateTime, Utc};
-use libsql::{params, Builder, Connection, Transaction};
+use libsql::{params, Builder, Connection, Transaction, TransactionBehavior};
+
+use crate::{config::DatabaseConfig, identity, migrations, models::Visibility, serial};
 
 pub struct Database;
 
-impl Database {
-    pub async fn init_db() -> anyhow::Result<Connection> {
-        // TODO: make this more secure like come on man what the shit?!?
-        let db = Builder::new_local("winklink.db").build().await?;
-        let conn = db.connect()?;
-
-        conn.execute("CREATE TABLE IF NOT EXISTS users (
-                            id INTEGER PRIMARY KEY AUTOINCREMENT,
-                            uuid TEXT UNIQUE NOT NULL,
-                            serial_number TEXT UNIQUE NOT NULL,
-                            device_name TEXT,
-                            device_owner TEXT,  -- This is your username field
-                            email TEXT UNIQUE NOT NULL,
-                            password_hash TEXT,  -- Renamed from password to password_hash for clarity
-                            created_at TEXT NOT NULL
-                        )", ()).await?;
-        
-        log::debug!("Initialised sqlite3 database");
+/// How long a connection waits for another one's transaction to finish before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The database plus the connection everything outside a transaction shares
+pub struct Db {
+    database: libsql::Database,
+    conn: Connection,
+}
+
+impl Db {
+    async fn open(database: libsql::Database) -> anyhow::Result<Db> {
+        let conn = Db::configure(database.connect()?).await?;
+
+        // So the shared connection can keep reading while a transaction is writing
+        conn.query("PRAGMA journal_mode = WAL", ()).await?;
+
+        Ok(Db { database, conn })
+    }
+
+    /// A fresh connection, set up the same way as the shared one
+    pub async fn connect(&self) -> anyhow::Result<Connection> {
+        Db::configure(self.database.connect()?).await
+    }
+
+    async fn configure(conn: Connection) -> anyhow::Result<Connection> {
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+
+        // Without this sqlite happily ignores every REFERENCES in the schema. It's per
+        // connection and can't be changed inside a transaction.
+        conn.execute("PRAGMA foreign_keys = ON", ()).await?;
+
         Ok(conn)
     }
+}
+
+impl Deref for Db {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl Database {
+    pub async fn init_db(config: &DatabaseConfig) -> anyhow::Result<Db> {
+        let db = Db::open(Builder::new_local(&config.path).build().await?).await?;
+
+        // Schema lives in src/migrations, see migrations.rs
+        migrations::run(&db).await?;
+        // Needs Rust to normalise, so it can't be part of the migration, see identity.rs
+        identity::backfill_keys(&db).await?;
+
+        log::debug!("Initialised sqlite3 database at {}", config.path.display());
+        Ok(db)
+    }
 
-    pub async fn start_transaction(conn: &Connection) -> anyhow::Result<Transaction> {
-        let tx = conn.transaction().await?;
+    /// Starts a transaction on a connection of its own. It takes the write lock straight away,
+    /// so two of them wait their turn instead of one failing halfway through.
+    pub async fn start_transaction(db: &Db) -> anyhow::Result<Transaction> {
+        let conn = db.connect().await?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate).await?;
 
         Ok(tx)
     }
@@ -57,29 +105,44 @@ impl Database {
         // tx finna get dropped here, it aint gunna be here no more
     }
 
+    /// If `e` is a unique index saying no, the `table.column` it said no about. For inserts
+    /// that were checked up front but raced someone else doing the same thing.
+    pub fn unique_violation(e: &anyhow::Error) -> Option<&str> {
+        // SQLITE_CONSTRAINT, the extended code in the upper bits says which kind
+        const SQLITE_CONSTRAINT: i32 = 19;
+
+        e.chain().find_map(|cause| match cause.downcast_ref::<libsql::Error>() {
+            Some(libsql::Error::SqliteFailure(code, message)) if code & 0xff == SQLITE_CONSTRAINT => {
+                message.strip_prefix("UNIQUE constraint failed: ")
+            }
+            _ => None,
+        })
+    }
+
     pub async fn keyword_exists(
         conn: &Connection,
         keyword: WLdbKeyword,
     ) -> anyhow::Result<bool> {
         let query = match keyword {
             WLdbKeyword::SerialNumber(value) => {
-                ("SELECT COUNT(*) FROM users WHERE serial_number = ?", value)
+                ("SELECT COUNT(*) FROM devices WHERE serial_number = ?", value)
             }
+            // Emails and usernames compare normalised, see identity.rs
             WLdbKeyword::Email(value) => {
-                ("SELECT COUNT(*) FROM users WHERE email = ?", value)
+                ("SELECT COUNT(*) FROM accounts WHERE email_key = ?", identity::email_key(&value))
             }
             WLdbKeyword::DeviceName(value) => {
-                ("SELECT COUNT(*) FROM users WHERE device_name = ?", value)
+                ("SELECT COUNT(*) FROM devices WHERE device_name = ?", value)
             }
-            WLdbKeyword::DeviceOwner(value) => {
-                ("SELECT COUNT(*) FROM users WHERE device_owner = ?", value)
+            WLdbKeyword::Username(value) => {
+                ("SELECT COUNT(*) FROM accounts WHERE username_key = ?", identity::username_key(&value))
             }
-            WLdbKeyword::UUID(value) => {
-                ("SELECT COUNT(*) FROM users WHERE uuid = ?", value)
+            WLdbKeyword::Uuid(value) => {
+                ("SELECT COUNT(*) FROM accounts WHERE uuid = ?", value)
             }
         };
 
-        let mut stmt = conn.prepare(query.0).await?;
+        let stmt = conn.prepare(query.0).await?;
         let mut rows = stmt.query(params![query.1]).await?;
 
         if let Some(row) = rows.next().await? {
@@ -94,100 +157,1243 @@ impl Database {
 pub struct Register;
 
 impl Register {
-    /// step 1, inserts the serial number and email
-    /// 
+    /// step 1, creates the account (email, username and password)
+    ///
     /// Sample usage:
     /// ```
-    /// if let Err(e) = Register::insert_serial_and_email(&tx, serial_number, email).await {
-    ///     tx.rollback().await?;
-    ///     return Err(e);
-    /// }
+    /// let uuid = match Register::create_account(&tx, email, username, password).await {
+    ///     Ok(uuid) => uuid,
+    ///     Err(e) => {
+    ///         tx.rollback().await?;
+    ///         return Err(e);
+    ///     }
+    /// };
     /// ```
-    /// Return value: ```anyhow::Result<String>```
-    pub async fn insert_serial_and_email(
+    /// Return value: ```anyhow::Result<String>``` (the new account's uuid)
+    pub async fn create_account(
         tx: &libsql::Transaction,
-        serial_number: &str,
         email: &str,
+        username: &str,
+        password: &str,
     ) -> anyhow::Result<String> {
-        if serial_number.len() > 12 {
-            return Err(anyhow::anyhow!("Serial number must be at most 12 characters long"));
-        }
-
-        let uuid = uuid::Uuid::new_v4();
-        let uuid_string = uuid.clone().to_string();
+        // Hash the password before storing it
+        let password_hash = Self::hash_password(password)
+            .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?;
 
+        let uuid = uuid::Uuid::new_v4().to_string();
         let created_at: DateTime<Utc> = Utc::now();
         let created_at_str = created_at.to_rfc3339();
 
-        tx.execute("INSERT INTO users (uuid, serial_number, email, created_at) VALUES (?, ?, ?, ?)",
-            params![uuid_string, serial_number, email, created_at_str]).await?;
+        tx.execute("INSERT INTO accounts (uuid, email, username, password_hash, created_at, email_key, username_key) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![uuid.clone(), email, username, password_hash, created_at_str, identity::email_key(email), identity::username_key(username)]).await?;
 
-        Ok(uuid.to_string())
+        Ok(uuid)
     }
 
-    /// step 2, inserts username and password
-    /// 
-    /// Sample usage: 
-    /// ```rust
-    /// if let Err(e) = Register::update_username_and_password(&tx, &uuid, username, password).await {
-    ///     tx.rollback().await?;
-    ///     return Err(e);
-    /// }
-    /// ```
-    pub async fn update_username_and_password(
-        tx: &libsql::Transaction,
-        uuid: &str,
-        username: &str,
-        password: &str,
-    ) -> anyhow::Result<()> {
-        // Hash the password before storing it
-        let password_hash = Self::hash_password(password)
-            .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?;
-            
-        // Update the database with the hashed password
-        tx.execute("UPDATE users SET device_owner = ?, password_hash = ? WHERE uuid = ?",
-            params![username, password_hash, uuid]).await?;
+    /// Argon2 with a fresh salt, also used for anything else we only ever need to verify
+    pub fn hash_password(password: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2 = Argon2::default();
+        
+        let password_hash = argon2.hash_password(password.as_bytes(), &salt)
+            .map_err(|e| Box::<dyn std::error::Error>::from(e.to_string()))?
+            .to_string();
+        
+        Ok(password_hash)
+    }
+
+    /// What login needs to check a password, `None` if nobody has that email. Matches on the
+    /// normalised email, or the exact one for an old account the backfill couldn't give a key.
+    /// An exact match wins, so that old account isn't shadowed by the one that got the key.
+    pub async fn find_login(conn: &Connection, email: &str) -> anyhow::Result<Option<LoginRecord>> {
+        let mut rows = conn.query("SELECT uuid, username, password_hash FROM accounts
+                                   WHERE email_key = ?1 OR (email_key IS NULL AND email = ?2)
+                                   ORDER BY email = ?2 DESC LIMIT 1",
+            params![identity::email_key(email), email]).await?;
+
+        match rows.next().await? {
+            Some(row) => Ok(Some(LoginRecord {
+                uuid: row.get(0)?,
+                username: row.get(1)?,
+                password_hash: row.get(2)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Accounts missing `email_key` or `username_key`, oldest first so they win any collisions
+    pub async fn missing_keys(conn: &Connection) -> anyhow::Result<Vec<UnkeyedAccount>> {
+        let mut rows = conn.query("SELECT uuid, email, username, email_key, username_key FROM accounts
+                                   WHERE email_key IS NULL OR username_key IS NULL ORDER BY id", ()).await?;
+
+        let mut accounts = Vec::new();
+        while let Some(row) = rows.next().await? {
+            accounts.push(UnkeyedAccount {
+                uuid: row.get(0)?,
+                email: row.get(1)?,
+                username: row.get(2)?,
+                email_key: row.get(3)?,
+                username_key: row.get(4)?,
+            });
+        }
 
+        Ok(accounts)
+    }
+
+    pub async fn email_key_taken(conn: &Connection, email_key: &str) -> anyhow::Result<bool> {
+        let mut rows = conn.query("SELECT 1 FROM accounts WHERE email_key = ?", params![email_key]).await?;
+        Ok(rows.next().await?.is_some())
+    }
+
+    pub async fn username_key_taken(conn: &Connection, username_key: &str) -> anyhow::Result<bool> {
+        let mut rows = conn.query("SELECT 1 FROM accounts WHERE username_key = ?", params![username_key]).await?;
+        Ok(rows.next().await?.is_some())
+    }
+
+    pub async fn set_email_key(conn: &Connection, uuid: &str, email_key: &str) -> anyhow::Result<()> {
+        conn.execute("UPDATE accounts SET email_key = ? WHERE uuid = ?", params![email_key, uuid]).await?;
+        Ok(())
+    }
+
+    pub async fn set_username_key(conn: &Connection, uuid: &str, username_key: &str) -> anyhow::Result<()> {
+        conn.execute("UPDATE accounts SET username_key = ? WHERE uuid = ?", params![username_key, uuid]).await?;
         Ok(())
     }
-    
+}
+
+pub struct LoginRecord {
+    pub uuid: String,
+    pub username: String,
+    pub password_hash: String,
+}
+
+/// An account missing a normalised key, for [`identity::backfill_keys`]
+pub struct UnkeyedAccount {
+    pub uuid: String,
+    pub email: String,
+    pub username: String,
+    pub email_key: Option<String>,
+    pub username_key: Option<String>,
+}
 
-    /// step 3, inserts device name
+pub struct Devices;
+
+impl Devices {
+    /// step 2, claims a device for an account
     ///
-    /// Sample usage: 
+    /// Sample usage:
     /// ```rust
-    /// if let Err(e) = Register::update_device_name(&tx, &uuid, device_name).await {
+    /// if let Err(e) = Devices::claim(&tx, &uuid, serial_number, device_name).await {
     ///     tx.rollback().await?;
     ///     return Err(e);
     /// }
     /// ```
-    pub async fn update_device_name(
+    pub async fn claim(
         tx: &libsql::Transaction,
-        uuid: &str,
+        account_uuid: &str,
+        serial_number: &str,
         device_name: &str,
     ) -> anyhow::Result<()> {
-        tx.execute("UPDATE users SET device_name = ? WHERE uuid = ?",
-            params![device_name, uuid]).await?;
+        Self::check_serial_number(serial_number)?;
+
+        let claimed_at: DateTime<Utc> = Utc::now();
+        let claimed_at_str = claimed_at.to_rfc3339();
+
+        tx.execute("INSERT INTO devices (serial_number, account_uuid, device_name, claimed_at) VALUES (?, ?, ?, ?)",
+            params![serial_number, account_uuid, device_name, claimed_at_str]).await?;
+        OwnershipHistory::acquired(tx, serial_number, account_uuid, "claim").await?;
 
         Ok(())
     }
 
-    fn hash_password(password: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-        
-        let password_hash = argon2.hash_password(password.as_bytes(), &salt)
-            .map_err(|e| Box::<dyn std::error::Error>::from(e.to_string()))?
-            .to_string();
-        
-        Ok(password_hash)
+    /// Who owns a device right now, `None` if nobody has claimed it
+    pub async fn owner_of(conn: &Connection, serial_number: &str) -> anyhow::Result<Option<String>> {
+        let stmt = conn.prepare("SELECT account_uuid FROM devices WHERE serial_number = ?").await?;
+        let mut rows = stmt.query(params![serial_number]).await?;
+
+        match rows.next().await? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The hash of the device's reset token, `None` if it never had one provisioned
+    pub async fn reset_token_hash(conn: &Connection, serial_number: &str) -> anyhow::Result<Option<String>> {
+        let stmt = conn.prepare("SELECT reset_token_hash FROM devices WHERE serial_number = ?").await?;
+        let mut rows = stmt.query(params![serial_number]).await?;
+
+        match rows.next().await? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set_reset_token_hash(conn: &Connection, serial_number: &str, reset_token_hash: &str) -> anyhow::Result<()> {
+        conn.execute("UPDATE devices SET reset_token_hash = ? WHERE serial_number = ?",
+            params![reset_token_hash, serial_number]).await?;
+
+        Ok(())
+    }
+
+    /// Unbinds a device from its owner, after this the serial can be registered again. Returns
+    /// false if `account_uuid` doesn't own it (anymore).
+    pub async fn release(tx: &libsql::Transaction, serial_number: &str, account_uuid: &str) -> anyhow::Result<bool> {
+        let released = tx.execute("DELETE FROM devices WHERE serial_number = ? AND account_uuid = ?",
+            params![serial_number, account_uuid]).await?;
+
+        Ok(released > 0)
+    }
+
+    /// Moves a device to another account, keeping its name unless a new one is given.
+    /// Doesn't touch the history, callers do that so they can say why it moved.
+    pub async fn set_owner(
+        tx: &libsql::Transaction,
+        serial_number: &str,
+        account_uuid: &str,
+        device_name: Option<&str>,
+    ) -> anyhow::Result<()> {
+        // The new owner provisions their own reset token
+        tx.execute("UPDATE devices SET account_uuid = ?, device_name = COALESCE(?, device_name), claimed_at = ?, reset_token_hash = NULL WHERE serial_number = ?",
+            params![account_uuid, device_name, Utc::now().to_rfc3339(), serial_number]).await?;
+
+        Ok(())
+    }
+
+    /// The sanity check `claim` does, exposed so handlers can turn it into a 400 up front.
+    /// This is just the format, `registry::check_claimable` is what knows if the device is real.
+    pub fn check_serial_number(serial_number: &str) -> anyhow::Result<()> {
+        serial::validate(serial_number)?;
+
+        Ok(())
+    }
+
+    pub async fn find(conn: &Connection, serial_number: &str) -> anyhow::Result<Option<DeviceRecord>> {
+        let stmt = conn.prepare("SELECT serial_number, device_name, claimed_at, visibility FROM devices WHERE serial_number = ?").await?;
+        let mut rows = stmt.query(params![serial_number]).await?;
+
+        let Some(row) = rows.next().await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(DeviceRecord {
+            serial_number: row.get(0)?,
+            device_name: row.get(1)?,
+            claimed_at: row.get(2)?,
+            visibility: Visibility::from_db(&row.get::<String>(3)?),
+        }))
+    }
+
+    /// Every claimed device out of `serial_numbers`, in one query. `account_uuid` is whoever's
+    /// asking, if anyone, see `lookup.rs` for what they get to see.
+    pub async fn lookup(conn: &Connection, serial_numbers: &[String], account_uuid: Option<&str>) -> anyhow::Result<Vec<LookupRecord>> {
+        if serial_numbers.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // ?1 is the caller, the serial numbers start at ?2
+        let placeholders = (2..serial_numbers.len() + 2).map(|i| format!("?{}", i)).collect::<Vec<_>>().join(", ");
+        let stmt = conn.prepare(&format!("SELECT d.serial_number, a.username, d.device_name, d.account_uuid, d.last_seen_at, d.firmware_version, d.visibility,
+                                     EXISTS (SELECT 1 FROM device_links l
+                                             JOIN devices c ON c.serial_number IN (l.serial_a, l.serial_b) AND c.serial_number != d.serial_number
+                                             WHERE l.status = 'accepted' AND d.serial_number IN (l.serial_a, l.serial_b) AND c.account_uuid = ?1),
+                                     EXISTS (SELECT 1 FROM device_links l
+                                             JOIN devices c ON c.serial_number IN (l.serial_a, l.serial_b) AND c.serial_number != d.serial_number
+                                             WHERE l.status = 'blocked' AND l.blocked_by = d.serial_number AND c.account_uuid = ?1)
+                                 FROM devices d JOIN accounts a ON a.uuid = d.account_uuid
+                                 WHERE d.serial_number IN ({})", placeholders)).await?;
+
+        let mut values = vec![account_uuid.map_or(libsql::Value::Null, |uuid| libsql::Value::Text(uuid.to_string()))];
+        values.extend(serial_numbers.iter().map(|serial_number| libsql::Value::Text(serial_number.clone())));
+        let mut rows = stmt.query(libsql::params_from_iter(values)).await?;
+
+        let mut devices = Vec::new();
+        while let Some(row) = rows.next().await? {
+            devices.push(LookupRecord {
+                serial_number: row.get(0)?,
+                owner_username: row.get(1)?,
+                device_name: row.get(2)?,
+                presence: DevicePresence {
+                    account_uuid: row.get(3)?,
+                    last_seen_at: row.get(4)?,
+                    firmware_version: row.get(5)?,
+                },
+                visibility: Visibility::from_db(&row.get::<String>(6)?),
+                is_contact: row.get::<i64>(7)? != 0,
+                blocks_caller: row.get::<i64>(8)? != 0,
+            });
+        }
+
+        Ok(devices)
+    }
+
+    pub async fn presence(conn: &Connection, serial_number: &str) -> anyhow::Result<Option<DevicePresence>> {
+        let stmt = conn.prepare("SELECT account_uuid, last_seen_at, firmware_version FROM devices WHERE serial_number = ?").await?;
+        let mut rows = stmt.query(params![serial_number]).await?;
+
+        let Some(row) = rows.next().await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(DevicePresence {
+            account_uuid: row.get(0)?,
+            last_seen_at: row.get(1)?,
+            firmware_version: row.get(2)?,
+        }))
+    }
+
+    pub async fn set_visibility(conn: &Connection, serial_number: &str, visibility: &str) -> anyhow::Result<()> {
+        conn.execute("UPDATE devices SET visibility = ? WHERE serial_number = ?", params![visibility, serial_number]).await?;
+
+        Ok(())
+    }
+
+    /// Stamps `last_seen_at` with now, and the firmware version if there is one
+    pub async fn record_seen(conn: &Connection, serial_number: &str, firmware_version: Option<&str>) -> anyhow::Result<()> {
+        conn.execute("UPDATE devices SET last_seen_at = ?, firmware_version = COALESCE(?, firmware_version) WHERE serial_number = ?",
+            params![Utc::now().timestamp(), firmware_version, serial_number]).await?;
+
+        Ok(())
+    }
+
+    /// Devices last seen after `after` and no later than `up_to` (unix seconds), with their owners
+    pub async fn last_seen_between(conn: &Connection, after: i64, up_to: i64) -> anyhow::Result<Vec<(String, String)>> {
+        let stmt = conn.prepare("SELECT serial_number, account_uuid FROM devices WHERE last_seen_at > ? AND last_seen_at <= ?").await?;
+        let mut rows = stmt.query(params![after, up_to]).await?;
+
+        let mut devices = Vec::new();
+        while let Some(row) = rows.next().await? {
+            devices.push((row.get(0)?, row.get(1)?));
+        }
+
+        Ok(devices)
+    }
+
+    /// Every device an account owns, oldest first
+    pub async fn list_for_account(conn: &Connection, account_uuid: &str) -> anyhow::Result<Vec<DeviceRecord>> {
+        let stmt = conn.prepare("SELECT serial_number, device_name, claimed_at, visibility FROM devices WHERE account_uuid = ? ORDER BY id").await?;
+        let mut rows = stmt.query(params![account_uuid]).await?;
+
+        let mut devices = Vec::new();
+        while let Some(row) = rows.next().await? {
+            devices.push(DeviceRecord {
+                serial_number: row.get(0)?,
+                device_name: row.get(1)?,
+                claimed_at: row.get(2)?,
+                visibility: Visibility::from_db(&row.get::<String>(3)?),
+            });
+        }
+
+        Ok(devices)
+    }
+}
+
+/// A row out of `devices`
+pub struct DeviceRecord {
+    pub serial_number: String,
+    pub device_name: String,
+    pub claimed_at: String,
+    pub visibility: Visibility,
+}
+
+/// One device out of `Devices::lookup`, along with how it relates to whoever asked
+pub struct LookupRecord {
+    pub serial_number: String,
+    pub owner_username: String,
+    pub device_name: String,
+    pub presence: DevicePresence,
+    pub visibility: Visibility,
+    /// One of the caller's devices is its contact
+    pub is_contact: bool,
+    /// It has blocked one of the caller's devices
+    pub blocks_caller: bool,
+}
+
+/// What `presence.rs` needs to know about a device
+pub struct DevicePresence {
+    pub account_uuid: String,
+    /// Unix seconds
+    pub last_seen_at: Option<i64>,
+    pub firmware_version: Option<String>,
+}
+
+/// `device_ownership_history`, only ever appended to (and closed off)
+pub struct OwnershipHistory;
+
+impl OwnershipHistory {
+    /// Opens a row for a new owner. `via` is how they got it: claim, transfer
+    pub async fn acquired(
+        tx: &libsql::Transaction,
+        serial_number: &str,
+        account_uuid: &str,
+        via: &str,
+    ) -> anyhow::Result<()> {
+        tx.execute("INSERT INTO device_ownership_history (serial_number, account_uuid, acquired_at, acquired_via) VALUES (?, ?, ?, ?)",
+            params![serial_number, account_uuid, Utc::now().to_rfc3339(), via]).await?;
+
+        Ok(())
+    }
+
+    /// Closes off the current owner's row. `via` is why: transfer, release, factory_reset
+    pub async fn released(tx: &libsql::Transaction, serial_number: &str, via: &str) -> anyhow::Result<()> {
+        tx.execute("UPDATE device_ownership_history SET released_at = ?, released_via = ? WHERE serial_number = ? AND released_at IS NULL",
+            params![Utc::now().to_rfc3339(), via, serial_number]).await?;
+
+        Ok(())
+    }
+}
+
+/// A row out of `device_transfers`
+pub struct TransferRecord {
+    pub id: i64,
+    pub uuid: String,
+    pub from_account_uuid: String,
+    pub code_hash: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub struct Transfers;
+
+impl Transfers {
+    pub async fn insert(
+        tx: &libsql::Transaction,
+        serial_number: &str,
+        from_account_uuid: &str,
+        code_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> anyhow::Result<String> {
+        let uuid = uuid::Uuid::new_v4().to_string();
+
+        tx.execute("INSERT INTO device_transfers (uuid, serial_number, from_account_uuid, code_hash, status, created_at, expires_at) VALUES (?, ?, ?, ?, 'pending', ?, ?)",
+            params![uuid.clone(), serial_number, from_account_uuid, code_hash, Utc::now().to_rfc3339(), expires_at.to_rfc3339()]).await?;
+
+        Ok(uuid)
+    }
+
+    /// The open offer for a device, if there is one. There's never more than one.
+    pub async fn find_pending(conn: &Connection, serial_number: &str) -> anyhow::Result<Option<TransferRecord>> {
+        let stmt = conn.prepare("SELECT id, uuid, from_account_uuid, code_hash, expires_at FROM device_transfers WHERE serial_number = ? AND status = 'pending'").await?;
+        let mut rows = stmt.query(params![serial_number]).await?;
+
+        let Some(row) = rows.next().await? else {
+            return Ok(None);
+        };
+
+        let expires_at: String = row.get(4)?;
+        Ok(Some(TransferRecord {
+            id: row.get(0)?,
+            uuid: row.get(1)?,
+            from_account_uuid: row.get(2)?,
+            code_hash: row.get(3)?,
+            expires_at: DateTime::parse_from_rfc3339(&expires_at)?.with_timezone(&Utc),
+        }))
+    }
+
+    /// Cancels any open offer for a device, returns how many there were
+    pub async fn cancel_pending(conn: &Connection, serial_number: &str) -> anyhow::Result<u64> {
+        let cancelled = conn.execute("UPDATE device_transfers SET status = 'cancelled', completed_at = ? WHERE serial_number = ? AND status = 'pending'",
+            params![Utc::now().to_rfc3339(), serial_number]).await?;
+
+        Ok(cancelled)
+    }
+
+    pub async fn mark_accepted(tx: &libsql::Transaction, id: i64, to_account_uuid: &str) -> anyhow::Result<()> {
+        tx.execute("UPDATE device_transfers SET status = 'accepted', to_account_uuid = ?, completed_at = ? WHERE id = ?",
+            params![to_account_uuid, Utc::now().to_rfc3339(), id]).await?;
+
+        Ok(())
+    }
+}
+
+/// A row out of `refresh_tokens`
+pub struct StoredRefreshToken {
+    pub id: i64,
+    pub user_uuid: String,
+    pub family_id: String,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<String>,
+    pub revoked_at: Option<String>,
+}
+
+pub struct RefreshTokens;
+
+impl RefreshTokens {
+    /// Stores a new refresh token, `token_hash` is what `auth::hash_refresh_token` spits out
+    pub async fn insert(
+        conn: &Connection,
+        user_uuid: &str,
+        family_id: &str,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        conn.execute("INSERT INTO refresh_tokens (token_hash, user_uuid, family_id, created_at, expires_at) VALUES (?, ?, ?, ?, ?)",
+            params![token_hash, user_uuid, family_id, Utc::now().to_rfc3339(), expires_at.to_rfc3339()]).await?;
+
+        Ok(())
+    }
+
+    pub async fn find(conn: &Connection, token_hash: &str) -> anyhow::Result<Option<StoredRefreshToken>> {
+        let stmt = conn.prepare("SELECT id, user_uuid, family_id, expires_at, used_at, revoked_at FROM refresh_tokens WHERE token_hash = ?").await?;
+        let mut rows = stmt.query(params![token_hash]).await?;
+
+        let Some(row) = rows.next().await? else {
+            return Ok(None);
+        };
+
+        let expires_at: String = row.get(3)?;
+        Ok(Some(StoredRefreshToken {
+            id: row.get(0)?,
+            user_uuid: row.get(1)?,
+            family_id: row.get(2)?,
+            expires_at: DateTime::parse_from_rfc3339(&expires_at)?.with_timezone(&Utc),
+            used_at: row.get(4)?,
+            revoked_at: row.get(5)?,
+        }))
+    }
+
+    /// Flags a token as swapped, presenting it again after this counts as reuse
+    pub async fn mark_used(conn: &Connection, id: i64) -> anyhow::Result<()> {
+        conn.execute("UPDATE refresh_tokens SET used_at = ? WHERE id = ?",
+            params![Utc::now().to_rfc3339(), id]).await?;
+
+        Ok(())
+    }
+
+    /// Kills every token descended from the same login
+    pub async fn revoke_family(conn: &Connection, family_id: &str) -> anyhow::Result<u64> {
+        let revoked = conn.execute("UPDATE refresh_tokens SET revoked_at = ? WHERE family_id = ? AND revoked_at IS NULL",
+            params![Utc::now().to_rfc3339(), family_id]).await?;
+
+        Ok(revoked)
+    }
+
+    /// Whether the login `family_id` came from has been logged out, or burnt for reuse. Access
+    /// tokens carry it as `sid`.
+    pub async fn family_revoked(conn: &Connection, family_id: &str) -> anyhow::Result<bool> {
+        let stmt = conn.prepare("SELECT EXISTS (SELECT 1 FROM refresh_tokens WHERE family_id = ? AND revoked_at IS NOT NULL)").await?;
+        let mut rows = stmt.query(params![family_id]).await?;
+
+        match rows.next().await? {
+            Some(row) => Ok(row.get::<i64>(0)? != 0),
+            None => Ok(false),
+        }
+    }
+
+    /// Kills every refresh token a user has, across all their logins
+    pub async fn revoke_all_for_user(conn: &Connection, user_uuid: &str) -> anyhow::Result<u64> {
+        let revoked = conn.execute("UPDATE refresh_tokens SET revoked_at = ? WHERE user_uuid = ? AND revoked_at IS NULL",
+            params![Utc::now().to_rfc3339(), user_uuid]).await?;
+
+        Ok(revoked)
+    }
+}
+
+pub struct Revocations;
+
+impl Revocations {
+    /// `None` if the user doesn't exist (anymore)
+    pub async fn token_generation(conn: &Connection, user_uuid: &str) -> anyhow::Result<Option<i64>> {
+        let stmt = conn.prepare("SELECT token_generation FROM accounts WHERE uuid = ?").await?;
+        let mut rows = stmt.query(params![user_uuid]).await?;
+
+        match rows.next().await? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Invalidates every access token the user currently has
+    pub async fn bump_token_generation(conn: &Connection, user_uuid: &str) -> anyhow::Result<()> {
+        conn.execute("UPDATE accounts SET token_generation = token_generation + 1 WHERE uuid = ?",
+            params![user_uuid]).await?;
+
+        Ok(())
+    }
+}
+
+/// A row out of `manufactured_devices`
+pub struct ManufacturedDevice {
+    pub serial_number: String,
+    pub hardware_revision: String,
+    pub batch: String,
+    pub device_secret: String,
+    pub status: String,
+}
+
+pub struct ManufacturedDevices;
+
+impl ManufacturedDevices {
+    /// Adds a device to the registry, returns false if the serial was already there
+    pub async fn insert(conn: &Connection, device: &ManufacturedDevice) -> anyhow::Result<bool> {
+        let inserted = conn.execute("INSERT INTO manufactured_devices (serial_number, hardware_revision, batch, device_secret, status, imported_at) VALUES (?, ?, ?, ?, ?, ?)
+                                     ON CONFLICT (serial_number) DO NOTHING",
+            params![device.serial_number.as_str(), device.hardware_revision.as_str(), device.batch.as_str(), device.device_secret.as_str(), device.status.as_str(), Utc::now().to_rfc3339()]).await?;
+
+        Ok(inserted > 0)
+    }
+
+    pub async fn find(conn: &Connection, serial_number: &str) -> anyhow::Result<Option<ManufacturedDevice>> {
+        let stmt = conn.prepare("SELECT serial_number, hardware_revision, batch, device_secret, status FROM manufactured_devices WHERE serial_number = ?").await?;
+        let mut rows = stmt.query(params![serial_number]).await?;
+
+        let Some(row) = rows.next().await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(ManufacturedDevice {
+            serial_number: row.get(0)?,
+            hardware_revision: row.get(1)?,
+            batch: row.get(2)?,
+            device_secret: row.get(3)?,
+            status: row.get(4)?,
+        }))
+    }
+
+    /// Marks a device as never to be registered again (stolen stock, failed QA, ...).
+    /// Returns false if it wasn't in the registry or was already revoked.
+    pub async fn revoke(conn: &Connection, serial_number: &str) -> anyhow::Result<bool> {
+        let revoked = conn.execute("UPDATE manufactured_devices SET status = 'revoked', revoked_at = ? WHERE serial_number = ? AND status != 'revoked'",
+            params![Utc::now().to_rfc3339(), serial_number]).await?;
+
+        Ok(revoked > 0)
+    }
+}
+
+pub struct DeviceChallenges;
+
+impl DeviceChallenges {
+    pub async fn insert(conn: &Connection, nonce: &str, serial_number: &str, expires_at: DateTime<Utc>) -> anyhow::Result<()> {
+        conn.execute("INSERT INTO device_challenges (nonce, serial_number, created_at, expires_at) VALUES (?, ?, ?, ?)",
+            params![nonce, serial_number, Utc::now().to_rfc3339(), expires_at.timestamp()]).await?;
+
+        Ok(())
+    }
+
+    /// Uses up a nonce. Returns false if it doesn't exist, is for another device, has expired
+    /// or was already used.
+    pub async fn consume(conn: &Connection, nonce: &str, serial_number: &str) -> anyhow::Result<bool> {
+        let consumed = conn.execute("UPDATE device_challenges SET used_at = ? WHERE nonce = ? AND serial_number = ? AND used_at IS NULL AND expires_at > ?",
+            params![Utc::now().to_rfc3339(), nonce, serial_number, Utc::now().timestamp()]).await?;
+
+        Ok(consumed > 0)
+    }
+
+    /// Used or not, an expired nonce is no good to anyone
+    pub async fn prune_expired(conn: &Connection) -> anyhow::Result<u64> {
+        let pruned = conn.execute("DELETE FROM device_challenges WHERE expires_at < ?",
+            params![Utc::now().timestamp()]).await?;
+
+        Ok(pruned)
+    }
+}
+
+/// An unrevoked row out of `device_credentials`
+pub struct StoredDeviceCredential {
+    pub key_id: String,
+    pub serial_number: String,
+    pub secret_hash: String,
+}
+
+pub struct DeviceCredentials;
+
+impl DeviceCredentials {
+    /// `secret_hash` is what `Register::hash_password` spits out
+    pub async fn insert(conn: &Connection, key_id: &str, serial_number: &str, secret_hash: &str) -> anyhow::Result<()> {
+        conn.execute("INSERT INTO device_credentials (key_id, serial_number, secret_hash, created_at) VALUES (?, ?, ?, ?)",
+            params![key_id, serial_number, secret_hash, Utc::now().to_rfc3339()]).await?;
+
+        Ok(())
+    }
+
+    /// `None` if there's no such key or it has been revoked
+    pub async fn find_active(conn: &Connection, key_id: &str) -> anyhow::Result<Option<StoredDeviceCredential>> {
+        let stmt = conn.prepare("SELECT key_id, serial_number, secret_hash FROM device_credentials WHERE key_id = ? AND revoked_at IS NULL").await?;
+        let mut rows = stmt.query(params![key_id]).await?;
+
+        let Some(row) = rows.next().await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(StoredDeviceCredential {
+            key_id: row.get(0)?,
+            serial_number: row.get(1)?,
+            secret_hash: row.get(2)?,
+        }))
+    }
+
+    pub async fn touch(conn: &Connection, key_id: &str) -> anyhow::Result<()> {
+        conn.execute("UPDATE device_credentials SET last_used_at = ? WHERE key_id = ?",
+            params![Utc::now().to_rfc3339(), key_id]).await?;
+
+        Ok(())
+    }
+
+    /// Revokes every credential a device has, returns how many there were
+    pub async fn revoke_all_for_device(conn: &Connection, serial_number: &str) -> anyhow::Result<u64> {
+        let revoked = conn.execute("UPDATE device_credentials SET revoked_at = ? WHERE serial_number = ? AND revoked_at IS NULL",
+            params![Utc::now().to_rfc3339(), serial_number]).await?;
+
+        Ok(revoked)
+    }
+}
+
+/// A row out of `winks`
+pub struct WinkRecord {
+    pub id: i64,
+    pub uuid: String,
+    pub from_serial_number: String,
+    pub sent_by: String,
+    pub to_serial_number: String,
+    pub kind: String,
+    pub payload: Option<String>,
+    /// Unix seconds
+    pub created_at: i64,
+    pub delivered_at: Option<String>,
+    pub read_at: Option<String>,
+    /// Unix seconds
+    pub expires_at: i64,
+    pub delivery_attempts: i64,
+}
+
+impl WinkRecord {
+    const COLUMNS: &'static str = "id, uuid, from_serial_number, sent_by, to_serial_number, kind, payload, created_at, delivered_at, read_at, expires_at, delivery_attempts";
+
+    fn from_row(row: &libsql::Row) -> anyhow::Result<Self> {
+        Ok(WinkRecord {
+            id: row.get(0)?,
+            uuid: row.get(1)?,
+            from_serial_number: row.get(2)?,
+            sent_by: row.get(3)?,
+            to_serial_number: row.get(4)?,
+            kind: row.get(5)?,
+            payload: row.get(6)?,
+            created_at: row.get(7)?,
+            delivered_at: row.get(8)?,
+            read_at: row.get(9)?,
+            expires_at: row.get(10)?,
+            delivery_attempts: row.get(11)?,
+        })
     }
 }
 
+/// What `Winks::insert` needs, the rest it fills in
+pub struct NewWink<'a> {
+    pub from_serial_number: &'a str,
+    pub from_account_uuid: &'a str,
+    pub sent_by: &'a str,
+    pub to_serial_number: &'a str,
+    pub kind: &'a str,
+    pub payload: Option<&'a str>,
+    pub ttl_secs: u64,
+}
+
+pub struct Winks;
+
+impl Winks {
+    /// Stores the wink unless `from_serial_number` has already sent `max_sent` of them since
+    /// `sent_after` (unix seconds), in which case it's `None`. The count and the insert are the
+    /// one statement, so two sends at once can't both squeeze into the last slot.
+    pub async fn insert(conn: &Connection, wink: NewWink<'_>, sent_after: i64, max_sent: u32) -> anyhow::Result<Option<WinkRecord>> {
+        let uuid = uuid::Uuid::new_v4().to_string();
+        let created_at = Utc::now().timestamp();
+        let expires_at = created_at + wink.ttl_secs as i64;
+
+        // The id has to come back from the insert, `last_insert_rowid` on the shared connection
+        // could already be someone else's
+        let mut rows = conn.query("INSERT INTO winks (uuid, from_serial_number, from_account_uuid, sent_by, to_serial_number, kind, payload, created_at, expires_at)
+                                   SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9
+                                   WHERE (SELECT COUNT(*) FROM winks WHERE from_serial_number = ?2 AND created_at > ?10) < ?11
+                                   RETURNING id",
+            params![uuid.clone(), wink.from_serial_number, wink.from_account_uuid, wink.sent_by, wink.to_serial_number, wink.kind, wink.payload, created_at, expires_at, sent_after, max_sent]).await?;
+        let Some(row) = rows.next().await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(WinkRecord {
+            id: row.get(0)?,
+            uuid,
+            from_serial_number: wink.from_serial_number.to_string(),
+            sent_by: wink.sent_by.to_string(),
+            to_serial_number: wink.to_serial_number.to_string(),
+            kind: wink.kind.to_string(),
+            payload: wink.payload.map(str::to_string),
+            created_at,
+            delivered_at: None,
+            read_at: None,
+            expires_at,
+            delivery_attempts: 0,
+        }))
+    }
+
+    /// When the oldest wink a device has sent since `since` (unix seconds) went out
+    pub async fn oldest_sent_since(conn: &Connection, from_serial_number: &str, since: i64) -> anyhow::Result<Option<i64>> {
+        let stmt = conn.prepare("SELECT MIN(created_at) FROM winks WHERE from_serial_number = ? AND created_at > ?").await?;
+        let mut rows = stmt.query(params![from_serial_number, since]).await?;
+
+        match rows.next().await? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(None),
+        }
+    }
+
+    /// Newest first. `before` is the `id` of the last wink on the previous page. Winks that
+    /// expired before they were delivered are dead letters and left out.
+    pub async fn inbox(conn: &Connection, to_serial_number: &str, before: Option<i64>, limit: u32) -> anyhow::Result<Vec<WinkRecord>> {
+        let stmt = conn.prepare(&format!("SELECT {} FROM winks
+                                 WHERE to_serial_number = ?1 AND id < ?2
+                                   AND (delivered_at IS NOT NULL OR expires_at > ?4)
+                                   -- Nothing from devices the recipient has since blocked
+                                   AND NOT EXISTS (SELECT 1 FROM device_links l
+                                                   WHERE l.status = 'blocked' AND l.blocked_by = ?1
+                                                     AND from_serial_number IN (l.serial_a, l.serial_b))
+                                 ORDER BY id DESC LIMIT ?3", WinkRecord::COLUMNS)).await?;
+        let mut rows = stmt.query(params![to_serial_number, before.unwrap_or(i64::MAX), limit, Utc::now().timestamp()]).await?;
+
+        let mut winks = Vec::new();
+        while let Some(row) = rows.next().await? {
+            winks.push(WinkRecord::from_row(&row)?);
+        }
+
+        Ok(winks)
+    }
+
+    /// Oldest first, the undelivered and unexpired winks waiting for a device. Counts as a
+    /// delivery attempt for each of them, so only call it when they're about to be sent.
+    pub async fn take_pending(tx: &Transaction, to_serial_number: &str, limit: u32) -> anyhow::Result<Vec<WinkRecord>> {
+        let now = Utc::now().timestamp();
+        let stmt = tx.prepare(&format!("SELECT {} FROM winks
+                                 WHERE to_serial_number = ?1 AND delivered_at IS NULL AND expires_at > ?2
+                                   AND NOT EXISTS (SELECT 1 FROM device_links l
+                                                   WHERE l.status = 'blocked' AND l.blocked_by = ?1
+                                                     AND from_serial_number IN (l.serial_a, l.serial_b))
+                                 ORDER BY id LIMIT ?3", WinkRecord::COLUMNS)).await?;
+        let mut rows = stmt.query(params![to_serial_number, now, limit]).await?;
+
+        let mut winks = Vec::new();
+        while let Some(row) = rows.next().await? {
+            winks.push(WinkRecord::from_row(&row)?);
+        }
+        for wink in &mut winks {
+            Self::record_attempt(tx, wink.id).await?;
+            wink.delivery_attempts += 1;
+        }
+
+        Ok(winks)
+    }
+
+    pub async fn record_attempt(conn: &Connection, wink_id: i64) -> anyhow::Result<()> {
+        conn.execute("UPDATE winks SET delivery_attempts = delivery_attempts + 1, last_attempt_at = ? WHERE id = ?",
+            params![Utc::now().timestamp(), wink_id]).await?;
+
+        Ok(())
+    }
+
+    /// Newest first, winks `from_serial_number` sent that expired before they were delivered.
+    /// Only the ones sent for its current owner, a previous owner's stay theirs.
+    pub async fn dead_letters(conn: &Connection, from_serial_number: &str, before: Option<i64>, limit: u32) -> anyhow::Result<Vec<WinkRecord>> {
+        let stmt = conn.prepare(&format!("SELECT {} FROM winks
+                                 WHERE from_serial_number = ?1 AND id < ?2 AND delivered_at IS NULL AND expires_at <= ?3
+                                   AND from_account_uuid = (SELECT account_uuid FROM devices WHERE serial_number = ?1)
+                                 ORDER BY id DESC LIMIT ?4", WinkRecord::COLUMNS)).await?;
+        let mut rows = stmt.query(params![from_serial_number, before.unwrap_or(i64::MAX), Utc::now().timestamp(), limit]).await?;
+
+        let mut winks = Vec::new();
+        while let Some(row) = rows.next().await? {
+            winks.push(WinkRecord::from_row(&row)?);
+        }
+
+        Ok(winks)
+    }
+
+    /// When a device changes hands the winks in its inbox were for the old owner, not the new
+    /// one. What it sent stays in the recipients' inboxes.
+    pub async fn remove_all_to_device(conn: &Connection, serial_number: &str) -> anyhow::Result<u64> {
+        let removed = conn.execute("DELETE FROM winks WHERE to_serial_number = ?", params![serial_number]).await?;
+
+        Ok(removed)
+    }
+
+    /// Deletes dead letters that expired before `before` (unix seconds)
+    pub async fn prune_dead_letters(conn: &Connection, before: i64) -> anyhow::Result<u64> {
+        let pruned = conn.execute("DELETE FROM winks WHERE delivered_at IS NULL AND expires_at < ?", params![before]).await?;
+
+        Ok(pruned)
+    }
+
+    /// Only touches winks that were actually sent to `to_serial_number`, aren't delivered yet
+    /// and haven't expired. Returns whether it changed anything.
+    pub async fn mark_delivered(conn: &Connection, to_serial_number: &str, wink_uuid: &str) -> anyhow::Result<bool> {
+        let updated = conn.execute("UPDATE winks SET delivered_at = ? WHERE uuid = ? AND to_serial_number = ? AND delivered_at IS NULL AND expires_at > ?",
+            params![Utc::now().to_rfc3339(), wink_uuid, to_serial_number, Utc::now().timestamp()]).await?;
+
+        Ok(updated > 0)
+    }
+
+    /// Reading a wink implies it was delivered, so that gets filled in too if it wasn't already
+    pub async fn mark_read(conn: &Connection, to_serial_number: &str, wink_uuid: &str) -> anyhow::Result<bool> {
+        let now = Utc::now();
+        let updated = conn.execute("UPDATE winks SET read_at = ?1, delivered_at = COALESCE(delivered_at, ?1)
+                                    WHERE uuid = ?2 AND to_serial_number = ?3 AND read_at IS NULL
+                                      AND (delivered_at IS NOT NULL OR expires_at > ?4)",
+            params![now.to_rfc3339(), wink_uuid, to_serial_number, now.timestamp()]).await?;
+
+        Ok(updated > 0)
+    }
+}
+
+/// A row out of `device_links`
+pub struct LinkRecord {
+    pub status: String,
+    pub requested_by: String,
+    pub blocked_by: Option<String>,
+}
+
+/// One of a device's links seen from its side
+pub struct ContactRecord {
+    /// The device on the other end
+    pub serial_number: String,
+    pub status: String,
+    pub requested_by: String,
+    pub blocked_by: Option<String>,
+    pub updated_at: String,
+    pub device_name: Option<String>,
+    pub owner_username: Option<String>,
+}
+
+pub struct DeviceLinks;
+
+impl DeviceLinks {
+    /// Rows are keyed on the pair in sorted order, see the migration
+    fn pair<'a>(serial_number: &'a str, other_serial_number: &'a str) -> (&'a str, &'a str) {
+        if serial_number < other_serial_number {
+            (serial_number, other_serial_number)
+        } else {
+            (other_serial_number, serial_number)
+        }
+    }
+
+    pub async fn find(conn: &Connection, serial_number: &str, other_serial_number: &str) -> anyhow::Result<Option<LinkRecord>> {
+        let (a, b) = Self::pair(serial_number, other_serial_number);
+        let stmt = conn.prepare("SELECT status, requested_by, blocked_by FROM device_links WHERE serial_a = ? AND serial_b = ?").await?;
+        let mut rows = stmt.query(params![a, b]).await?;
+
+        let Some(row) = rows.next().await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(LinkRecord {
+            status: row.get(0)?,
+            requested_by: row.get(1)?,
+            blocked_by: row.get(2)?,
+        }))
+    }
+
+    /// Creates or overwrites the link between two devices
+    pub async fn set(
+        conn: &Connection,
+        serial_number: &str,
+        other_serial_number: &str,
+        status: &str,
+        requested_by: &str,
+        blocked_by: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let (a, b) = Self::pair(serial_number, other_serial_number);
+        let now = Utc::now().to_rfc3339();
+
+        conn.execute("INSERT INTO device_links (serial_a, serial_b, status, requested_by, blocked_by, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?)
+                      ON CONFLICT (serial_a, serial_b) DO UPDATE SET status = excluded.status, requested_by = excluded.requested_by,
+                                                                     blocked_by = excluded.blocked_by, updated_at = excluded.updated_at",
+            params![a, b, status, requested_by, blocked_by, now.clone(), now]).await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(conn: &Connection, serial_number: &str, other_serial_number: &str) -> anyhow::Result<()> {
+        let (a, b) = Self::pair(serial_number, other_serial_number);
+        conn.execute("DELETE FROM device_links WHERE serial_a = ? AND serial_b = ?", params![a, b]).await?;
+
+        Ok(())
+    }
+
+    /// Everything a device is linked to, most recently changed first. The other device's name
+    /// and owner come along, callers decide whether to show them.
+    pub async fn list_for_device(conn: &Connection, serial_number: &str) -> anyhow::Result<Vec<ContactRecord>> {
+        let stmt = conn.prepare("SELECT l.other, l.status, l.requested_by, l.blocked_by, l.updated_at, d.device_name, a.username
+                                 FROM (SELECT CASE WHEN serial_a = ?1 THEN serial_b ELSE serial_a END AS other, status, requested_by, blocked_by, updated_at
+                                       FROM device_links WHERE serial_a = ?1 OR serial_b = ?1) l
+                                 LEFT JOIN devices d ON d.serial_number = l.other
+                                 LEFT JOIN accounts a ON a.uuid = d.account_uuid
+                                 ORDER BY l.updated_at DESC").await?;
+        let mut rows = stmt.query(params![serial_number]).await?;
+
+        let mut contacts = Vec::new();
+        while let Some(row) = rows.next().await? {
+            contacts.push(ContactRecord {
+                serial_number: row.get(0)?,
+                status: row.get(1)?,
+                requested_by: row.get(2)?,
+                blocked_by: row.get(3)?,
+                updated_at: row.get(4)?,
+                device_name: row.get(5)?,
+                owner_username: row.get(6)?,
+            });
+        }
+
+        Ok(contacts)
+    }
+
+    /// When a device changes hands its contacts (and blocks) don't go with it
+    pub async fn remove_all_for_device(conn: &Connection, serial_number: &str) -> anyhow::Result<u64> {
+        let removed = conn.execute("DELETE FROM device_links WHERE serial_a = ?1 OR serial_b = ?1", params![serial_number]).await?;
+
+        Ok(removed)
+    }
+}
+
+/// A row out of `events`
+pub struct EventRecord {
+    pub id: i64,
+    pub kind: String,
+    pub data: String,
+}
+
+pub struct Events;
+
+impl Events {
+    /// Returns the new event's id. That comes back from the insert itself, the shared
+    /// connection's `last_insert_rowid` could already belong to someone else's insert.
+    pub async fn insert(conn: &Connection, account_uuid: &str, kind: &str, data: &str) -> anyhow::Result<i64> {
+        let mut rows = conn.query("INSERT INTO events (account_uuid, kind, data, created_at) VALUES (?, ?, ?, ?) RETURNING id",
+            params![account_uuid, kind, data, Utc::now().timestamp()]).await?;
+
+        let row = rows.next().await?.ok_or_else(|| anyhow::anyhow!("Insert returned no row"))?;
+        Ok(row.get(0)?)
+    }
+
+    /// Oldest first, only the ones after `after_id`
+    pub async fn since(conn: &Connection, account_uuid: &str, after_id: i64, limit: u32) -> anyhow::Result<Vec<EventRecord>> {
+        let stmt = conn.prepare("SELECT id, kind, data FROM events WHERE account_uuid = ? AND id > ? ORDER BY id LIMIT ?").await?;
+        let mut rows = stmt.query(params![account_uuid, after_id, limit]).await?;
+
+        let mut events = Vec::new();
+        while let Some(row) = rows.next().await? {
+            events.push(EventRecord {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                data: row.get(2)?,
+            });
+        }
+
+        Ok(events)
+    }
+
+    /// Drops events older than `before` (unix seconds)
+    pub async fn prune_before(conn: &Connection, before: i64) -> anyhow::Result<u64> {
+        let pruned = conn.execute("DELETE FROM events WHERE created_at < ?", params![before]).await?;
+
+        Ok(pruned)
+    }
+}
+
+#[allow(dead_code)]
 pub enum WLdbKeyword {
     SerialNumber(String),
     Email(String),
     DeviceName(String),
-    DeviceOwner(String),
-    UUID(String),
-}
\ No newline at end of file
+    Username(String),
+    Uuid(String),
+}
+
+pub struct LoginThrottles;
+
+impl LoginThrottles {
+    /// When the lock on an email or IP (`kind` is `email` or `ip`) runs out, 0 if it never had one
+    pub async fn locked_until(conn: &Connection, kind: &str, subject: &str) -> anyhow::Result<i64> {
+        let mut rows = conn.query("SELECT locked_until FROM login_throttles WHERE kind = ? AND subject = ?",
+            params![kind, subject]).await?;
+
+        match rows.next().await? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Counts an attempt as a failure before the password has been checked, and returns how many
+    /// there have been. If the last one was at or before `forget_before` the count starts again
+    /// from this one. `None` if it's locked, or already at `max_failures` (or whatever the last
+    /// lock allows) with the attempt that got there still being checked.
+    pub async fn reserve(
+        conn: &Connection,
+        kind: &str,
+        subject: &str,
+        now: i64,
+        forget_before: i64,
+        max_failures: u32,
+    ) -> anyhow::Result<Option<i64>> {
+        let mut rows = conn.query(
+            "INSERT INTO login_throttles (kind, subject, failures, last_failure_at) VALUES (?1, ?2, 1, ?3)
+             ON CONFLICT (kind, subject) DO UPDATE SET
+                 failures = CASE WHEN last_failure_at <= ?4 THEN 1 ELSE failures + 1 END,
+                 allowed_failures = CASE WHEN last_failure_at <= ?4 THEN 0 ELSE allowed_failures END,
+                 last_failure_at = excluded.last_failure_at
+             WHERE locked_until <= ?3 AND (last_failure_at <= ?4 OR failures < MAX(?5, allowed_failures))
+             RETURNING failures",
+            params![kind, subject, now, forget_before, max_failures],
+        ).await?;
+
+        match rows.next().await? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Hands back an attempt `reserve` counted that turned out not to be a failure
+    pub async fn release(conn: &Connection, kind: &str, subject: &str) -> anyhow::Result<()> {
+        conn.execute("UPDATE login_throttles SET failures = failures - 1 WHERE kind = ? AND subject = ? AND failures > 0",
+            params![kind, subject]).await?;
+
+        Ok(())
+    }
+
+    /// Locks until `until`, after which one more attempt is allowed
+    pub async fn lock(conn: &Connection, kind: &str, subject: &str, until: i64) -> anyhow::Result<()> {
+        conn.execute("UPDATE login_throttles SET locked_until = ?, allowed_failures = failures + 1 WHERE kind = ? AND subject = ?",
+            params![until, kind, subject]).await?;
+
+        Ok(())
+    }
+
+    /// Forgets the failures and any lock. Returns whether there was anything to forget.
+    pub async fn clear(conn: &Connection, kind: &str, subject: &str) -> anyhow::Result<bool> {
+        let cleared = conn.execute("DELETE FROM login_throttles WHERE kind = ? AND subject = ?", params![kind, subject]).await?;
+
+        Ok(cleared > 0)
+    }
+
+    /// Drops throttles nobody has failed against since `before` and that aren't locked
+    pub async fn prune(conn: &Connection, before: i64, now: i64) -> anyhow::Result<u64> {
+        let pruned = conn.execute("DELETE FROM login_throttles WHERE last_failure_at < ? AND locked_until <= ?",
+            params![before, now]).await?;
+
+        Ok(pruned)
+    }
+}
+
+pub struct LoginFailures;
+
+impl LoginFailures {
+    pub async fn insert(
+        conn: &Connection,
+        email: &str,
+        account_uuid: Option<&str>,
+        ip: &str,
+        reason: &str,
+        at: i64,
+    ) -> anyhow::Result<()> {
+        conn.execute("INSERT INTO login_failures (email, account_uuid, ip, reason, created_at) VALUES (?, ?, ?, ?, ?)",
+            params![email, account_uuid, ip, reason, at]).await?;
+
+        Ok(())
+    }
+
+    pub async fn prune_before(conn: &Connection, before: i64) -> anyhow::Result<u64> {
+        let pruned = conn.execute("DELETE FROM login_failures WHERE created_at < ?", params![before]).await?;
+
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::task::JoinSet;
+
+    use super::*;
+    use crate::{device_auth, test_support::{account, device, test_db}, wink};
+
+    async fn count(conn: &Connection, sql: &str) -> i64 {
+        conn.query(sql, ()).await.unwrap().next().await.unwrap().unwrap().get(0).unwrap()
+    }
+
+    /// Devices reconnecting (which redelivers in a transaction) while other requests run their
+    /// own transactions and write through the shared connection. None of them should fail or
+    /// lose anything.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_reconnects_and_requests() {
+        let db = test_db().await;
+        let owner = account(&db, "alice").await;
+        let (a, b) = (device(&db, &owner).await, device(&db, &owner).await);
+
+        let mut tasks = JoinSet::new();
+        for i in 0..20 {
+            tasks.spawn({
+                let (db, a) = (db.clone(), a.clone());
+                async move { wink::redeliver(&db, &a).await.map(drop) }
+            });
+            // Only a few of these, argon2 is slow in debug builds and it hashes inside the
+            // transaction
+            if i % 5 == 0 {
+                tasks.spawn({
+                    let (db, b) = (db.clone(), b.clone());
+                    async move { device_auth::rotate(&db, &b).await.map(drop) }
+                });
+            }
+            tasks.spawn({
+                let (db, a, b, owner) = (db.clone(), a.clone(), b.clone(), owner.clone());
+                async move {
+                    Winks::insert(&db, NewWink {
+                        from_serial_number: &b,
+                        from_account_uuid: &owner,
+                        sent_by: "device",
+                        to_serial_number: &a,
+                        kind: "wink",
+                        payload: None,
+                        ttl_secs: 60,
+                    }, 0, u32::MAX).await.map(drop)
+                }
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            result.unwrap().unwrap();
+        }
+
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM winks").await, 20);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM device_credentials WHERE revoked_at IS NULL").await, 1);
+    }
+
+    /// A statement on the shared connection waits for an open transaction instead of joining
+    /// it, so it survives the transaction being rolled back
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn rollback_only_undoes_the_transaction() {
+        let db = test_db().await;
+        let owner = account(&db, "alice").await;
+
+        let tx = Database::start_transaction(&db).await.unwrap();
+        Revocations::bump_token_generation(&tx, &owner).await.unwrap();
+
+        // Someone else logging out everywhere at the same time
+        let (shared, uuid) = (db.clone(), owner.clone());
+        let elsewhere = tokio::spawn(async move { Revocations::bump_token_generation(&shared, &uuid).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        tx.rollback().await.unwrap();
+
+        elsewhere.await.unwrap().unwrap();
+        assert_eq!(Revocations::token_generation(&db, &owner).await.unwrap(), Some(1));
+    }
+
+    /// What registration falls back on when two people sign up with the same email or
+    /// username at once and both get past the up front checks
+    #[tokio::test]
+    async fn duplicate_keys_are_unique_violations() {
+        let db = test_db().await;
+        account(&db, "alice").await;
+
+        for (email, username, column) in [("ALICE@example.com", "alice2", "accounts.email_key"), ("a2@example.com", "Alice", "accounts.username_key")] {
+            let tx = Database::start_transaction(&db).await.unwrap();
+            let e = Register::create_account(&tx, email, username, "correct horse 1").await.unwrap_err();
+            tx.rollback().await.unwrap();
+
+            assert_eq!(Database::unique_violation(&e.context("Failed to create account")), Some(column));
+        }
+    }
+}
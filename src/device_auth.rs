@@ -0,0 +1,289 @@
+//! Device authentication module
+//!
+//! Lets a WinkLink talk to the API as itself rather than as its owner. Each device gets a
+//! credential when it's claimed, which looks like `<key_id>.<secret>` and is sent as:
+//!
+//! ```text
+//! Authorization: Device 3f9c0a1b2c3d4e5f.6a7b...
+//! ```
+//!
+//! Only the argon2 hash of the secret is stored (same as passwords, see
+//! `Register::hash_password`), the key id is just there so we know which hash to check. Routes
+//! for devices get `with_device_auth(conn)` (in `main.rs`) and the handler gets an
+//! [`AuthDevice`]. It's a separate filter from `with_auth` on purpose, a device credential
+//! can't be used on the human endpoints and a user's JWT can't be used on the device ones.
+//!
+//! Credentials get replaced with [`rotate`] (by the owner, or by the device with its current
+//! one) and revoked with [`revoke_all`]. Releasing or transferring a device revokes them too,
+//! the next owner provisions their own.
+
+use std::sync::Arc;
+
+use argon2::{password_hash::rand_core::{OsRng, RngCore}, Argon2, PasswordHash, PasswordVerifier};
+use libsql::Connection;
+use warp::Rejection;
+
+use crate::database::{Database, Db, DeviceCredentials, Devices, Register};
+
+/// The caller of a route guarded by `with_device_auth()`
+#[derive(Debug, Clone)]
+pub struct AuthDevice {
+    pub serial_number: String,
+    /// Which of its credentials it used
+    pub key_id: String,
+}
+
+#[derive(Debug)]
+pub enum DeviceAuthError {
+    /// No `Authorization` header at all
+    MissingCredential,
+    /// There is a header, but it isn't `Device <key_id>.<secret>`
+    MalformedHeader,
+    /// Unknown key, wrong secret, revoked, or the device has since been released
+    InvalidCredential,
+    /// Couldn't check the credential, we fail closed
+    Unavailable,
+}
+
+impl DeviceAuthError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            DeviceAuthError::MissingCredential => "Device credential is missing",
+            DeviceAuthError::MalformedHeader => "Authorization header must be in the form 'Device <credential>'",
+            DeviceAuthError::InvalidCredential => "Device credential is invalid",
+            DeviceAuthError::Unavailable => "Unable to verify device credential",
+        }
+    }
+}
+
+impl warp::reject::Reject for DeviceAuthError {}
+
+#[derive(Debug)]
+pub enum CredentialError {
+    NoSuchDevice,
+    NotOwner,
+    Internal(anyhow::Error),
+}
+
+impl CredentialError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            CredentialError::NoSuchDevice => "Device with this serial number not found",
+            CredentialError::NotOwner => "You do not own this device",
+            CredentialError::Internal(_) => "Internal error",
+        }
+    }
+}
+
+impl From<anyhow::Error> for CredentialError {
+    fn from(e: anyhow::Error) -> Self {
+        CredentialError::Internal(e)
+    }
+}
+
+/// Makes a new credential for a device and returns it. This is the only time the secret is
+/// ever seen, so it has to go straight back to whoever is setting the device up.
+/// Pass `&tx` to do it as part of a claim.
+pub async fn issue_credential(conn: &Connection, serial_number: &str) -> anyhow::Result<String> {
+    let mut key_id = [0u8; 8];
+    OsRng.fill_bytes(&mut key_id);
+    let key_id = hex::encode(key_id);
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let secret = hex::encode(secret);
+
+    let secret_hash = Register::hash_password(&secret)
+        .map_err(|e| anyhow::anyhow!("Failed to hash device secret: {}", e))?;
+    DeviceCredentials::insert(conn, &key_id, serial_number, &secret_hash).await?;
+
+    Ok(format!("{}.{}", key_id, secret))
+}
+
+/// Revokes everything the device had and issues a fresh credential
+pub async fn rotate(conn: &Db, serial_number: &str) -> anyhow::Result<String> {
+    let tx = Database::start_transaction(conn).await?;
+    let result = async {
+        DeviceCredentials::revoke_all_for_device(&tx, serial_number).await?;
+        issue_credential(&tx, serial_number).await
+    }.await;
+
+    match result {
+        Ok(credential) => {
+            Database::commit_transaction(tx).await?;
+            log::info!("Rotated credentials for device {}", serial_number);
+            Ok(credential)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+/// Afterwards the device can't authenticate until its owner rotates in a new credential
+pub async fn revoke_all(conn: &Connection, serial_number: &str) -> anyhow::Result<u64> {
+    let revoked = DeviceCredentials::revoke_all_for_device(conn, serial_number).await?;
+    log::info!("Revoked {} credential(s) for device {}", revoked, serial_number);
+
+    Ok(revoked)
+}
+
+/// For the owner-facing endpoints, makes sure the caller owns the device
+pub async fn check_owner(conn: &Connection, account_uuid: &str, serial_number: &str) -> Result<(), CredentialError> {
+    match Devices::owner_of(conn, serial_number).await? {
+        None => Err(CredentialError::NoSuchDevice),
+        Some(owner) if owner != account_uuid => Err(CredentialError::NotOwner),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Filter body behind `with_device_auth()`, turns an `Authorization: Device ...` header into
+/// an [`AuthDevice`] or rejects with a [`DeviceAuthError`]
+pub async fn authorize_device(header: Option<String>, conn: Arc<Db>) -> Result<AuthDevice, Rejection> {
+    let header = header.ok_or_else(|| warp::reject::custom(DeviceAuthError::MissingCredential))?;
+    let credential = header
+        .strip_prefix("Device ")
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .ok_or_else(|| warp::reject::custom(DeviceAuthError::MalformedHeader))?;
+    let (key_id, secret) = credential
+        .split_once('.')
+        .ok_or_else(|| warp::reject::custom(DeviceAuthError::MalformedHeader))?;
+
+    let stored = match DeviceCredentials::find_active(&conn, key_id).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return Err(warp::reject::custom(DeviceAuthError::InvalidCredential)),
+        Err(e) => {
+            log::error!("Failed to look up device credential: {}", e);
+            return Err(warp::reject::custom(DeviceAuthError::Unavailable));
+        }
+    };
+
+    let parsed_hash = PasswordHash::new(&stored.secret_hash).map_err(|e| {
+        log::error!("Stored hash for device credential {} is unreadable: {}", key_id, e);
+        warp::reject::custom(DeviceAuthError::Unavailable)
+    })?;
+    if Argon2::default().verify_password(secret.as_bytes(), &parsed_hash).is_err() {
+        return Err(warp::reject::custom(DeviceAuthError::InvalidCredential));
+    }
+
+    // Credentials get revoked on release, but don't trust that alone
+    match Devices::owner_of(&conn, &stored.serial_number).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(warp::reject::custom(DeviceAuthError::InvalidCredential)),
+        Err(e) => {
+            log::error!("Failed to look up owner of device {}: {}", stored.serial_number, e);
+            return Err(warp::reject::custom(DeviceAuthError::Unavailable));
+        }
+    }
+
+    if let Err(e) = DeviceCredentials::touch(&conn, key_id).await {
+        log::warn!("Failed to record use of device credential {}: {}", key_id, e);
+    }
+
+    Ok(AuthDevice {
+        serial_number: stored.serial_number,
+        key_id: stored.key_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{account, device, test_db};
+
+    async fn authorize_credential(db: &Arc<Db>, credential: &str) -> Result<AuthDevice, Rejection> {
+        authorize_device(Some(format!("Device {}", credential)), db.clone()).await
+    }
+
+    fn device_error(result: Result<AuthDevice, Rejection>) -> Option<&'static str> {
+        result.err().and_then(|rejection| rejection.find::<DeviceAuthError>().map(DeviceAuthError::message))
+    }
+
+    #[tokio::test]
+    async fn credential_authenticates_its_device() {
+        let db = test_db().await;
+        let alice = account(&db, "alice").await;
+        let serial_number = device(&db, &alice).await;
+        let credential = issue_credential(&db, &serial_number).await.unwrap();
+
+        let authed = authorize_credential(&db, &credential).await.unwrap();
+        assert_eq!(authed.serial_number, serial_number);
+        assert_eq!(credential.split_once('.').map(|(key_id, _)| key_id), Some(authed.key_id.as_str()));
+    }
+
+    #[tokio::test]
+    async fn bad_headers() {
+        let db = test_db().await;
+
+        assert_eq!(device_error(authorize_device(None, db.clone()).await), Some(DeviceAuthError::MissingCredential.message()));
+        for header in ["Bearer abc.def", "Device ", "Device no-dot-here"] {
+            assert_eq!(device_error(authorize_device(Some(header.to_string()), db.clone()).await),
+                Some(DeviceAuthError::MalformedHeader.message()), "{}", header);
+        }
+        assert_eq!(device_error(authorize_credential(&db, "0011223344556677.secret").await), Some(DeviceAuthError::InvalidCredential.message()));
+    }
+
+    #[tokio::test]
+    async fn wrong_secret() {
+        let db = test_db().await;
+        let alice = account(&db, "alice").await;
+        let serial_number = device(&db, &alice).await;
+        let credential = issue_credential(&db, &serial_number).await.unwrap();
+        let (key_id, _) = credential.split_once('.').unwrap();
+
+        let guess = format!("{}.{}", key_id, "0".repeat(64));
+        assert_eq!(device_error(authorize_credential(&db, &guess).await), Some(DeviceAuthError::InvalidCredential.message()));
+    }
+
+    #[tokio::test]
+    async fn rotating_replaces_the_credential() {
+        let db = test_db().await;
+        let alice = account(&db, "alice").await;
+        let serial_number = device(&db, &alice).await;
+        let old = issue_credential(&db, &serial_number).await.unwrap();
+
+        let new = rotate(&db, &serial_number).await.unwrap();
+
+        assert_eq!(device_error(authorize_credential(&db, &old).await), Some(DeviceAuthError::InvalidCredential.message()));
+        assert!(authorize_credential(&db, &new).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn revoked_credentials_are_refused() {
+        let db = test_db().await;
+        let alice = account(&db, "alice").await;
+        let serial_number = device(&db, &alice).await;
+        let credential = issue_credential(&db, &serial_number).await.unwrap();
+
+        assert_eq!(revoke_all(&db, &serial_number).await.unwrap(), 1);
+
+        assert_eq!(device_error(authorize_credential(&db, &credential).await), Some(DeviceAuthError::InvalidCredential.message()));
+    }
+
+    /// Even if the credential somehow wasn't revoked along with the release
+    #[tokio::test]
+    async fn released_devices_are_refused() {
+        let db = test_db().await;
+        let alice = account(&db, "alice").await;
+        let serial_number = device(&db, &alice).await;
+        let credential = issue_credential(&db, &serial_number).await.unwrap();
+
+        let tx = Database::start_transaction(&db).await.unwrap();
+        assert!(Devices::release(&tx, &serial_number, &alice).await.unwrap());
+        Database::commit_transaction(tx).await.unwrap();
+
+        assert_eq!(device_error(authorize_credential(&db, &credential).await), Some(DeviceAuthError::InvalidCredential.message()));
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_manages_credentials() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let serial_number = device(&db, &alice).await;
+
+        check_owner(&db, &alice, &serial_number).await.unwrap();
+        assert!(matches!(check_owner(&db, &bob, &serial_number).await, Err(CredentialError::NotOwner)));
+        assert!(matches!(check_owner(&db, &bob, "WNK000000019").await, Err(CredentialError::NoSuchDevice)));
+    }
+}
@@ -0,0 +1,461 @@
+//! Error module
+//!
+//! [`ApiError`] is what handlers fail with and what `handle_rejection` turns warp's rejections
+//! into, so every error the API sends looks the same:
+//!
+//! ```json
+//! {
+//!   "status": "fail",
+//!   "code": "wink_ttl_invalid",
+//!   "message": "ttl_secs must be between 1 and the server's maximum",
+//!   "details": [{ "field": "ttl_secs", "code": "out_of_range", "message": "..." }]
+//! }
+//! ```
+//!
+//! `status` is `fail` when it's the caller's fault and `error` when it's ours. `code` is stable,
+//! clients should match on it rather than on `message`, which is for people and may change.
+//! `details` is only there when we can say which fields were wrong.
+//!
+//! Each module's own error converts into an [`ApiError`] below, that's where its codes live.
+//! Internal errors are logged with an id, the client only ever sees the id as `error_id`.
+
+use uuid::Uuid;
+use validator::ValidationErrors;
+use warp::{
+    http::{header, HeaderValue, StatusCode},
+    reject::Rejection,
+    reply::{json, with_status, Reply, Response},
+};
+
+use crate::{
+    auth::{AuthError, LoginError, RefreshError},
+    contacts::ContactError,
+    device_auth::{CredentialError, DeviceAuthError},
+    possession::ProofError,
+    presence::PresenceError,
+    privacy::PrivacyError,
+    registry::RegistryError,
+    release::ReleaseError,
+    response::{ErrorResponse, FieldError},
+    transfer::TransferError,
+    wink::WinkError,
+};
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// 400
+    BadRequest { code: &'static str, message: String, details: Vec<FieldError> },
+    /// 401, `scheme` is what goes in `WWW-Authenticate`
+    Unauthorized { code: &'static str, message: String, scheme: &'static str },
+    /// 403
+    Forbidden { code: &'static str, message: String },
+    /// 404
+    NotFound { code: &'static str, message: String },
+    /// 405
+    MethodNotAllowed,
+    /// 409
+    Conflict { code: &'static str, message: String },
+    /// 411
+    LengthRequired { code: &'static str, message: String },
+    /// 413
+    PayloadTooLarge { code: &'static str, message: String },
+    /// 415
+    UnsupportedMediaType { code: &'static str, message: String },
+    /// 429
+    TooManyRequests { code: &'static str, message: String, retry_after_secs: u64 },
+    /// 503, couldn't check something we'd rather fail than guess about
+    Unavailable { code: &'static str, message: String },
+    /// 500, what went wrong is logged and never sent
+    Internal(anyhow::Error),
+}
+
+impl ApiError {
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::BadRequest { code, message: message.into(), details: Vec::new() }
+    }
+
+    /// A 400 for one field that's wrong
+    pub fn invalid_field(code: &'static str, field: &str, field_code: &str, message: impl Into<String>) -> Self {
+        let message = message.into();
+        ApiError::BadRequest {
+            code,
+            details: vec![FieldError {
+                field: field.to_string(),
+                code: field_code.to_string(),
+                message: message.clone(),
+            }],
+            message,
+        }
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::Forbidden { code, message: message.into() }
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::NotFound { code, message: message.into() }
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::Conflict { code, message: message.into() }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            ApiError::NotFound { .. } => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::Conflict { .. } => StatusCode::CONFLICT,
+            ApiError::LengthRequired { .. } => StatusCode::LENGTH_REQUIRED,
+            ApiError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Reply for ApiError {
+    fn into_response(self) -> Response {
+        let status_code = self.status_code();
+        let mut headers = Vec::new();
+        let mut error_id = None;
+
+        let (code, message, details) = match self {
+            ApiError::BadRequest { code, message, details } => (code, message, details),
+            ApiError::Unauthorized { code, message, scheme } => {
+                // Let clients know what kind of credentials we want
+                headers.push((header::WWW_AUTHENTICATE, HeaderValue::from_static(scheme)));
+                (code, message, Vec::new())
+            },
+            ApiError::Forbidden { code, message }
+            | ApiError::NotFound { code, message }
+            | ApiError::Conflict { code, message }
+            | ApiError::LengthRequired { code, message }
+            | ApiError::PayloadTooLarge { code, message }
+            | ApiError::UnsupportedMediaType { code, message }
+            | ApiError::Unavailable { code, message } => (code, message, Vec::new()),
+            ApiError::MethodNotAllowed => ("method_not_allowed", "Method not allowed".to_string(), Vec::new()),
+            ApiError::TooManyRequests { code, message, retry_after_secs } => {
+                headers.push((header::RETRY_AFTER, HeaderValue::from(retry_after_secs)));
+                (code, message, Vec::new())
+            },
+            ApiError::Internal(e) => {
+                let id = Uuid::new_v4().simple().to_string();
+                log::error!("Internal error {}: {:#}", id, e);
+                error_id = Some(id);
+                ("internal_error", "Internal server error".to_string(), Vec::new())
+            },
+        };
+
+        let error_response = ErrorResponse {
+            status: if status_code.is_server_error() { "error" } else { "fail" }.to_string(),
+            code: code.to_string(),
+            message,
+            details,
+            error_id,
+        };
+        let mut response = with_status(json(&error_response), status_code).into_response();
+        response.headers_mut().extend(headers);
+        response
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError::Internal(e)
+    }
+}
+
+/// Every broken rule from `#[derive(Validate)]`, see `validation.rs`
+impl From<ValidationErrors> for ApiError {
+    fn from(e: ValidationErrors) -> Self {
+        let mut details: Vec<FieldError> = e
+            .field_errors()
+            .into_iter()
+            .flat_map(|(field, errors)| {
+                errors.iter().map(move |error| FieldError {
+                    // Rules on the whole struct (`__all__`) say which field they're about themselves
+                    field: error.params.get("field").and_then(|f| f.as_str()).unwrap_or(&field).to_string(),
+                    code: error.code.to_string(),
+                    message: error.message.as_deref().unwrap_or("Invalid value").to_string(),
+                })
+            })
+            .collect();
+        // The errors come out of a HashMap, keep the order the same from one request to the next
+        details.sort_by(|a, b| a.field.cmp(&b.field));
+
+        ApiError::BadRequest {
+            code: "validation_failed",
+            message: "Some fields are invalid".to_string(),
+            details,
+        }
+    }
+}
+
+impl From<libsql::Error> for ApiError {
+    fn from(e: libsql::Error) -> Self {
+        ApiError::Internal(e.into())
+    }
+}
+
+impl From<&AuthError> for ApiError {
+    fn from(e: &AuthError) -> Self {
+        let code = match e {
+            AuthError::MissingToken => "token_missing",
+            AuthError::MalformedHeader => "authorization_malformed",
+            AuthError::InvalidToken => "token_invalid",
+            AuthError::ExpiredToken => "token_expired",
+            AuthError::RevokedToken => "token_revoked",
+            AuthError::Unavailable => {
+                return ApiError::Unavailable { code: "auth_unavailable", message: e.message().to_string() };
+            },
+        };
+        ApiError::Unauthorized { code, message: e.message().to_string(), scheme: "Bearer" }
+    }
+}
+
+impl From<&DeviceAuthError> for ApiError {
+    fn from(e: &DeviceAuthError) -> Self {
+        let code = match e {
+            DeviceAuthError::MissingCredential => "credential_missing",
+            DeviceAuthError::MalformedHeader => "authorization_malformed",
+            DeviceAuthError::InvalidCredential => "credential_invalid",
+            DeviceAuthError::Unavailable => {
+                return ApiError::Unavailable { code: "auth_unavailable", message: e.message().to_string() };
+            },
+        };
+        ApiError::Unauthorized { code, message: e.message().to_string(), scheme: "Device" }
+    }
+}
+
+impl From<LoginError> for ApiError {
+    fn from(e: LoginError) -> Self {
+        match e {
+            LoginError::InvalidCredentials => ApiError::Unauthorized {
+                code: "credentials_invalid",
+                message: e.message().to_string(),
+                scheme: "Bearer",
+            },
+            LoginError::Locked { retry_after_secs } => ApiError::TooManyRequests {
+                code: "login_locked",
+                message: e.message().to_string(),
+                retry_after_secs,
+            },
+            LoginError::Internal(e) => ApiError::Internal(e.context("Login failed")),
+        }
+    }
+}
+
+impl From<RefreshError> for ApiError {
+    fn from(e: RefreshError) -> Self {
+        let message = e.message();
+        let code = match e {
+            RefreshError::Invalid => "refresh_token_invalid",
+            RefreshError::Expired => "refresh_token_expired",
+            RefreshError::Reused => "refresh_token_reused",
+            RefreshError::Internal(e) => return ApiError::Internal(e.context("Failed to refresh token")),
+        };
+        ApiError::Unauthorized { code, message: message.to_string(), scheme: "Bearer" }
+    }
+}
+
+impl From<RegistryError> for ApiError {
+    fn from(e: RegistryError) -> Self {
+        let message = e.to_string();
+        match e {
+            RegistryError::InvalidFormat(_) => ApiError::invalid_field("serial_number_invalid", "serial_number", "invalid", message),
+            RegistryError::Unknown => ApiError::bad_request("serial_number_unknown", message),
+            RegistryError::Revoked => ApiError::forbidden("device_revoked", message),
+            RegistryError::AlreadyClaimed => ApiError::conflict("device_already_claimed", message),
+            RegistryError::Internal(e) => ApiError::Internal(e.context("Failed to check device registry")),
+        }
+    }
+}
+
+impl From<ProofError> for ApiError {
+    fn from(e: ProofError) -> Self {
+        let message = e.message();
+        match e {
+            ProofError::InvalidClaimCode => ApiError::forbidden("claim_code_invalid", message),
+            ProofError::InvalidChallenge => ApiError::forbidden("challenge_invalid", message),
+            ProofError::InvalidSignature => ApiError::forbidden("signature_invalid", message),
+            ProofError::Internal(e) => ApiError::Internal(e.context("Failed to verify proof of possession")),
+        }
+    }
+}
+
+impl From<TransferError> for ApiError {
+    fn from(e: TransferError) -> Self {
+        let message = e.message();
+        match e {
+            TransferError::NoSuchDevice => ApiError::not_found("device_not_found", message),
+            TransferError::NoPendingOffer => ApiError::not_found("transfer_not_found", message),
+            TransferError::NotOwner => ApiError::forbidden("not_device_owner", message),
+            TransferError::InvalidCode => ApiError::bad_request("transfer_code_invalid", message),
+            TransferError::Expired => ApiError::bad_request("transfer_code_expired", message),
+            TransferError::AlreadyOwner => ApiError::conflict("already_device_owner", message),
+            TransferError::Internal(e) => ApiError::Internal(e.context("Device transfer failed")),
+        }
+    }
+}
+
+impl From<CredentialError> for ApiError {
+    fn from(e: CredentialError) -> Self {
+        let message = e.message();
+        match e {
+            CredentialError::NoSuchDevice => ApiError::not_found("device_not_found", message),
+            CredentialError::NotOwner => ApiError::forbidden("not_device_owner", message),
+            CredentialError::Internal(e) => ApiError::Internal(e.context("Device credential change failed")),
+        }
+    }
+}
+
+impl From<WinkError> for ApiError {
+    fn from(e: WinkError) -> Self {
+        let message = e.message();
+        match e {
+            WinkError::NoSuchDevice => ApiError::not_found("device_not_found", message),
+            WinkError::RecipientNotFound => ApiError::not_found("recipient_not_found", message),
+            WinkError::NotOwner => ApiError::forbidden("not_device_owner", message),
+            WinkError::WrongDevice => ApiError::forbidden("wrong_device", message),
+            WinkError::NotContacts => ApiError::forbidden("not_contacts", message),
+            WinkError::SerialNumberRequired => ApiError::invalid_field("serial_number_required", "serial_number", "required", message),
+            WinkError::CannotWinkSelf => ApiError::bad_request("cannot_wink_self", message),
+            WinkError::InvalidKind => ApiError::invalid_field("wink_kind_invalid", "kind", "invalid", message),
+            WinkError::PayloadTooLong => ApiError::invalid_field("wink_payload_too_long", "payload", "too_long", message),
+            WinkError::InvalidTtl => ApiError::invalid_field("wink_ttl_invalid", "ttl_secs", "out_of_range", message),
+            WinkError::TooManyWinkIds => ApiError::invalid_field("too_many_wink_ids", "wink_ids", "too_many", message),
+            WinkError::RateLimited { retry_after_secs } => ApiError::TooManyRequests {
+                code: "rate_limited",
+                message: message.to_string(),
+                retry_after_secs,
+            },
+            WinkError::Internal(e) => ApiError::Internal(e.context("Wink request failed")),
+        }
+    }
+}
+
+impl From<ContactError> for ApiError {
+    fn from(e: ContactError) -> Self {
+        let message = e.message();
+        match e {
+            ContactError::NoSuchDevice => ApiError::not_found("device_not_found", message),
+            ContactError::ContactNotFound => ApiError::not_found("contact_not_found", message),
+            ContactError::NoPendingRequest => ApiError::not_found("contact_request_not_found", message),
+            ContactError::NotContacts => ApiError::not_found("not_contacts", message),
+            ContactError::NotBlocked => ApiError::not_found("not_blocked", message),
+            ContactError::NotOwner => ApiError::forbidden("not_device_owner", message),
+            ContactError::CannotContactSelf => ApiError::bad_request("cannot_contact_self", message),
+            ContactError::AlreadyRequested => ApiError::conflict("contact_request_pending", message),
+            ContactError::AlreadyContacts => ApiError::conflict("already_contacts", message),
+            ContactError::BlockedByYou => ApiError::conflict("blocked_by_you", message),
+            ContactError::Internal(e) => ApiError::Internal(e.context("Contact request failed")),
+        }
+    }
+}
+
+impl From<ReleaseError> for ApiError {
+    fn from(e: ReleaseError) -> Self {
+        let message = e.message();
+        match e {
+            ReleaseError::NoSuchDevice => ApiError::not_found("device_not_found", message),
+            ReleaseError::NotOwner => ApiError::forbidden("not_device_owner", message),
+            ReleaseError::ResetTokenRequired => ApiError::forbidden("reset_token_required", message),
+            ReleaseError::InvalidResetToken => ApiError::forbidden("reset_token_invalid", message),
+            ReleaseError::Internal(e) => ApiError::Internal(e.context("Device release failed")),
+        }
+    }
+}
+
+impl From<PresenceError> for ApiError {
+    fn from(e: PresenceError) -> Self {
+        let message = e.message();
+        match e {
+            PresenceError::NoSuchDevice => ApiError::not_found("device_not_found", message),
+            PresenceError::InvalidFirmwareVersion => {
+                ApiError::invalid_field("firmware_version_invalid", "firmware_version", "invalid", message)
+            },
+            PresenceError::Internal(e) => ApiError::Internal(e.context("Failed to record heartbeat")),
+        }
+    }
+}
+
+impl From<PrivacyError> for ApiError {
+    fn from(e: PrivacyError) -> Self {
+        let message = e.message();
+        match e {
+            PrivacyError::NoSuchDevice => ApiError::not_found("device_not_found", message),
+            PrivacyError::Internal(e) => ApiError::Internal(e.context("Failed to set device visibility")),
+        }
+    }
+}
+
+/// Turns rejections (auth failures, unknown routes, bad bodies and headers) into the same
+/// replies as handlers' errors. Anything warp rejects that isn't listed here is a bug on our
+/// side, so it's a 500.
+pub async fn handle_rejection(err: Rejection) -> Result<Response, std::convert::Infallible> {
+    let error = if let Some(e) = err.find::<AuthError>() {
+        ApiError::from(e)
+    } else if let Some(e) = err.find::<DeviceAuthError>() {
+        ApiError::from(e)
+    } else if err.is_not_found() {
+        ApiError::not_found("not_found", "Not found")
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        ApiError::bad_request("invalid_body", format!("Invalid request body: {}", e))
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        ApiError::bad_request("invalid_query", format!("Invalid query string: {}", e))
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        ApiError::MethodNotAllowed
+    } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        ApiError::UnsupportedMediaType { code: "unsupported_media_type", message: "Request body must be application/json".to_string() }
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        ApiError::PayloadTooLarge { code: "payload_too_large", message: "Request body is too large".to_string() }
+    } else if err.find::<warp::reject::LengthRequired>().is_some() {
+        ApiError::LengthRequired { code: "length_required", message: "Request needs a Content-Length header".to_string() }
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        ApiError::bad_request("missing_header", format!("Missing request header {}", e.name()))
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        ApiError::bad_request("invalid_header", format!("Invalid request header {}", e.name()))
+    } else if err.find::<warp::ws::MissingConnectionUpgrade>().is_some() {
+        ApiError::bad_request("websocket_upgrade_required", "This endpoint only takes WebSocket connections")
+    } else {
+        ApiError::Internal(anyhow::anyhow!("Unhandled rejection: {:?}", err))
+    };
+
+    Ok(error.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::{test::request, Filter};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rejections_keep_their_status() {
+        let json = warp::path!("json").and(warp::body::json()).map(|_: serde_json::Value| "ok");
+        let limited = warp::path!("limited").and(warp::body::content_length_limit(8)).and(warp::body::json()).map(|_: serde_json::Value| "ok");
+        let header = warp::path!("header").and(warp::header::<u32>("x-count")).map(|_| "ok");
+        let ws = warp::path!("ws").and(warp::ws()).map(|ws: warp::ws::Ws| ws.on_upgrade(|_| async {}));
+        let routes = json.or(limited).or(header).or(ws).recover(handle_rejection);
+
+        let cases = [
+            (request().method("POST").path("/json").header("content-type", "text/plain").body("{}"), 415, "unsupported_media_type"),
+            (request().method("POST").path("/limited").json(&serde_json::json!({ "too": "long" })), 413, "payload_too_large"),
+            (request().method("POST").path("/limited"), 411, "length_required"),
+            (request().path("/header"), 400, "missing_header"),
+            (request().path("/header").header("x-count", "lots"), 400, "invalid_header"),
+            // A plain request to the WebSocket falls at the `Connection: upgrade` check
+            (request().path("/ws"), 400, "invalid_header"),
+        ];
+        for (request, status, code) in cases {
+            let response = request.reply(&routes).await;
+            let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!((response.status().as_u16(), body["code"].as_str()), (status, Some(code)));
+        }
+    }
+}
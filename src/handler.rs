@@ -1,25 +1,12 @@
-use std::{sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+use std::{net::IpAddr, sync::Arc};
 
-use argon2::{password_hash::{rand_core::OsRng, Salt, SaltString}, Argon2, Params, PasswordHash, PasswordVerifier};
-use chrono::{DateTime, NaiveDateTime, Utc};
-use jsonwebtoken::{encode, EncodingKey, Header};
-use libsql::{params, Connection, Transaction};
-use serde::{Deserialize, Serialize};
-use argon2::PasswordHasher;
-use warp::{http::StatusCode, reply::{json, with_status, Reply}};
+use anyhow::Context;
+use chrono::Utc;
+use libsql::params;
+use validator::Validate;
+use warp::{http::StatusCode, reply::{json, with_header, with_status, Json, Reply, WithStatus}};
 
-use crate::{database::{Database, Register, WLdbKeyword}, models::{DeviceRequest, LoginRequest, WLRegister}, response::{GenericResponse, LoginResponse, WLDeviceResponse}, WebResult};
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String,   // Subject (user ID)
-    exp: usize,    // Expiration time
-    iat: usize,    // Issued at
-}
-#[derive(Debug)]
-struct ServerError;
-
-impl warp::reject::Reject for ServerError {}
+use crate::{auth::{self, AuthUser}, config::Config, contacts::{self, ContactAction, Outcome}, database::{Database, Db, Devices, Register, WLdbKeyword, Winks}, device_auth::{self, AuthDevice}, error::ApiError, lookup::{self, LookupQuota}, models::{AckWinksRequest, BatchLookupRequest, ClaimDeviceRequest, ContactRequest, DeviceRequest, HeartbeatRequest, InboxQuery, LoginRequest, PrivacyRequest, RefreshRequest, ReleaseDeviceRequest, SendWinkRequest, TransferAcceptRequest, TransferOfferRequest, WLRegister, WinkState}, possession, presence, privacy, ratelimit::RateLimiter, realtime::{self, CloseReason, Event, Hub, Subscriber, Topic}, response::{AccountDevice, AccountResponse, BatchLookupResponse, ChallengeResponse, ContactResponse, ContactsResponse, DeviceCredentialResponse, GenericResponse, HeartbeatResponse, InboxResponse, LoginResponse, ResetTokenResponse, SendWinkResponse, TransferOfferResponse}, registry, release, transfer, wink::{self, WinkError, WinkSender}, WebResult};
 
 pub async fn health_checker_handler() -> WebResult<impl Reply> {
     const MESSAGE: &str = "WinkLink Simple API";
@@ -31,241 +18,652 @@ pub async fn health_checker_handler() -> WebResult<impl Reply> {
     Ok(json(response_json))
 }
 
-pub async fn register_handler(body: WLRegister, conn: Arc<libsql::Connection>) -> WebResult<impl Reply> {
-    // Check if the serial number already exists
-    if let Ok(val) = Database::keyword_exists(&conn, WLdbKeyword::SerialNumber(body.serial_number.clone())).await {
-        if val {
-            let error_response = GenericResponse {
-                status: "fail".to_string(),
-                message: "Serial number already exists".to_string(),
-            };
-            return Ok(with_status(json(&error_response), StatusCode::CONFLICT));
-        }
-    }
+pub async fn register_handler(body: WLRegister, conn: Arc<Db>) -> WebResult<impl Reply> {
+    // Email, username, password and device name, see validation.rs
+    body.validate()?;
+
+    // Check that whoever is registering the device actually has it, and only then tell them
+    // whether it's a real device nobody has claimed yet
+    registry::check_format(&body.serial_number)?;
+    possession::verify(&conn, &body.serial_number, &body.proof).await?;
+    registry::check_claimable(&conn, &body.serial_number).await?;
 
     // Check if the email already exists
-    if let Ok(val) = Database::keyword_exists(&conn, WLdbKeyword::Email(body.email.clone())).await {
-        if val {
-            let error_response = GenericResponse {
-                status: "fail".to_string(),
-                message: "Email already exists".to_string(),
-            };
-            return Ok(with_status(json(&error_response), StatusCode::CONFLICT));
-        }
+    if Database::keyword_exists(&conn, WLdbKeyword::Email(body.email.clone())).await? {
+        return Err(ApiError::conflict("email_taken", "Email already exists"));
     }
 
     // Check if the username already exists
-    if let Ok(val) = Database::keyword_exists(&conn, WLdbKeyword::DeviceOwner(body.username.clone())).await {
-        if val {
-            let error_response = GenericResponse {
-                status: "fail".to_string(),
-                message: "Username already exists".to_string(),
-            };
-            return Ok(with_status(json(&error_response), StatusCode::CONFLICT));
-        }
+    if Database::keyword_exists(&conn, WLdbKeyword::Username(body.username.clone())).await? {
+        return Err(ApiError::conflict("username_taken", "Username already exists"));
     }
 
     // Start a transaction
-    let tx = match Database::start_transaction(&conn).await {
-        Ok(tx) => tx,
+    let tx = Database::start_transaction(&conn).await.context("Failed to start transaction")?;
+
+    let device_credential = match create_account(&tx, &body).await {
+        Ok(device_credential) => device_credential,
         Err(e) => {
-            let error_response = GenericResponse {
-                status: "error".to_string(),
-                message: format!("Failed to start transaction: {}", e),
-            };
-            return Ok(with_status(json(&error_response), StatusCode::INTERNAL_SERVER_ERROR));
+            let _ = tx.rollback().await; // Rollback the transaction on failure
+
+            // Someone else registered the same email or username since the checks above
+            return Err(match Database::unique_violation(&e) {
+                Some("accounts.email_key") => ApiError::conflict("email_taken", "Email already exists"),
+                Some("accounts.username_key") => ApiError::conflict("username_taken", "Username already exists"),
+                _ => e.into(),
+            });
         }
     };
 
-    // Insert user data into the database (Step 1)
-    let uuid = match Register::insert_serial_and_email(&tx, &body.serial_number, &body.email).await {
-        Ok(uuid) => uuid,
+    // Commit the transaction
+    Database::commit_transaction(tx).await.context("Failed to commit transaction")?;
+
+    // Success response
+    let json_response = DeviceCredentialResponse {
+        status: "success".to_string(),
+        message: format!("User {} has been created at {} [utc]", body.username, Utc::now()),
+        serial_number: body.serial_number,
+        device_credential,
+    };
+
+    Ok(with_status(json(&json_response), StatusCode::CREATED))
+}
+
+/// The account, its first device and the device's credential, returns the credential
+async fn create_account(tx: &libsql::Transaction, body: &WLRegister) -> anyhow::Result<String> {
+    // Create the account (Step 1)
+    let uuid = Register::create_account(tx, &body.email, &body.username, &body.password).await
+        .context("Failed to create account")?;
+
+    // Claim their first device and give it its own credentials (Steps 2 and 3)
+    claim_device(tx, &uuid, &body.serial_number, &body.device_name).await
+}
+
+/// Claims a device and gives it its own credentials, returns the credential
+async fn claim_device(tx: &libsql::Transaction, account_uuid: &str, serial_number: &str, device_name: &str) -> anyhow::Result<String> {
+    Devices::claim(tx, account_uuid, serial_number, device_name).await
+        .context("Failed to claim device")?;
+    device_auth::issue_credential(tx, serial_number).await
+        .context("Failed to issue device credential")
+}
+
+pub async fn claim_device_handler(user: AuthUser, body: ClaimDeviceRequest, conn: Arc<Db>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    body.validate()?;
+
+    // Same as registering, proof first so the serial's status only goes to whoever has it
+    registry::check_format(&body.serial_number)?;
+    possession::verify(&conn, &body.serial_number, &body.proof).await?;
+    registry::check_claimable(&conn, &body.serial_number).await?;
+
+    let tx = Database::start_transaction(&conn).await.context("Failed to start transaction")?;
+
+    let device_credential = match claim_device(&tx, &user.uuid.to_string(), &body.serial_number, &body.device_name).await {
+        Ok(device_credential) => device_credential,
         Err(e) => {
             let _ = tx.rollback().await; // Rollback the transaction on failure
-            let error_response = GenericResponse {
-                status: "error".to_string(),
-                message: format!("Failed to insert user data: {}", e),
-            };
-            return Ok(with_status(json(&error_response), StatusCode::INTERNAL_SERVER_ERROR));
+            return Err(e.into());
         }
     };
 
-    // Update username and password (Step 2)
-    if let Err(e) = Register::update_username_and_password(&tx, &uuid, &body.username, &body.password).await {
-        let _ = tx.rollback().await; // Rollback the transaction on failure
-        let error_response = GenericResponse {
-            status: "error".to_string(),
-            message: format!("Failed to update username and password: {}", e),
-        };
-        return Ok(with_status(json(&error_response), StatusCode::INTERNAL_SERVER_ERROR));
-    }
+    Database::commit_transaction(tx).await.context("Failed to commit transaction")?;
 
-    // Update device name (Step 3)
-    if let Err(e) = Register::update_device_name(&tx, &uuid, &body.device_name).await {
-        let _ = tx.rollback().await; // Rollback the transaction on failure
-        let error_response = GenericResponse {
-            status: "error".to_string(),
-            message: format!("Failed to update device name: {}", e),
-        };
-        return Ok(with_status(json(&error_response), StatusCode::INTERNAL_SERVER_ERROR));
-    }
+    realtime::publish_to_account(&hub, &conn, &user.uuid.to_string(), &Event::DeviceClaimed { serial_number: body.serial_number.clone() }).await;
 
-    // Commit the transaction
-    if let Err(e) = Database::commit_transaction(tx).await {
-        let error_response = GenericResponse {
-            status: "error".to_string(),
-            message: format!("Failed to commit transaction: {}", e),
-        };
-        return Ok(with_status(json(&error_response), StatusCode::INTERNAL_SERVER_ERROR));
-    }
+    let json_response = DeviceCredentialResponse {
+        status: "success".to_string(),
+        message: format!("Device {} has been claimed", body.serial_number),
+        serial_number: body.serial_number,
+        device_credential,
+    };
+    Ok(with_status(json(&json_response), StatusCode::CREATED))
+}
+
+pub async fn challenge_handler(
+    serial_number: String,
+    client_ip: IpAddr,
+    limiter: Arc<RateLimiter<IpAddr>>,
+    conn: Arc<Db>,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    // Every challenge is a row, so don't let anyone hammer out as many as they like
+    limiter.check(client_ip, 1).map_err(|retry_after_secs| ApiError::TooManyRequests {
+        code: "rate_limited",
+        message: "Too many challenges, slow down".to_string(),
+        retry_after_secs,
+    })?;
+
+    // Every well formed serial gets a nonce, known or not, claimed or not. Anything else would
+    // tell anyone who asks which serials exist. `possession::verify` sorts out the rest.
+    registry::check_format(&serial_number)?;
+
+    let challenge = possession::issue_challenge(&conn, &config.devices, &serial_number).await
+        .with_context(|| format!("Failed to issue challenge for {}", serial_number))?;
+
+    let response = ChallengeResponse {
+        status: "success".to_string(),
+        serial_number,
+        nonce: challenge.nonce,
+        expires_at: challenge.expires_at.to_rfc3339(),
+    };
+    Ok(with_status(json(&response), StatusCode::CREATED))
+}
+
+pub async fn transfer_offer_handler(user: AuthUser, body: TransferOfferRequest, conn: Arc<Db>, config: Arc<Config>) -> WebResult<impl Reply> {
+    let offer = transfer::create_offer(&conn, &config.devices, &user.uuid.to_string(), &body.serial_number).await?;
+
+    let response = TransferOfferResponse {
+        status: "success".to_string(),
+        message: format!("Transfer of device {} has been offered", body.serial_number),
+        transfer_id: offer.transfer_id,
+        code: offer.code,
+        expires_at: offer.expires_at.to_rfc3339(),
+    };
+    Ok(with_status(json(&response), StatusCode::CREATED))
+}
+
+pub async fn transfer_accept_handler(user: AuthUser, body: TransferAcceptRequest, conn: Arc<Db>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    body.validate()?;
+
+    let previous_owner = transfer::accept_offer(&conn, &user.uuid.to_string(), &body.serial_number, &body.code, body.device_name.as_deref()).await?;
+
+    hub.disconnect(&Topic::Device(body.serial_number.clone()), CloseReason::Revoked);
+    realtime::publish_to_account(&hub, &conn, &previous_owner, &Event::DeviceTransferred { serial_number: body.serial_number.clone() }).await;
+    realtime::publish_to_account(&hub, &conn, &user.uuid.to_string(), &Event::DeviceClaimed { serial_number: body.serial_number.clone() }).await;
 
-    // Success response
     let json_response = GenericResponse {
         status: "success".to_string(),
-        message: format!("User {} has been created at {} [utc]", body.username, Utc::now()),
+        message: format!("Device {} has been transferred to you", body.serial_number),
+    };
+    Ok(with_status(json(&json_response), StatusCode::OK))
+}
+
+pub async fn transfer_cancel_handler(user: AuthUser, body: TransferOfferRequest, conn: Arc<Db>) -> WebResult<impl Reply> {
+    transfer::cancel_offer(&conn, &user.uuid.to_string(), &body.serial_number).await?;
+
+    let json_response = GenericResponse {
+        status: "success".to_string(),
+        message: format!("Transfer of device {} has been cancelled", body.serial_number),
     };
+    Ok(with_status(json(&json_response), StatusCode::OK))
+}
 
-    Ok(with_status(json(&json_response), StatusCode::CREATED))
+/// The owner replacing the device's credentials, e.g. after a transfer or if they leaked
+pub async fn rotate_credentials_handler(user: AuthUser, body: DeviceRequest, conn: Arc<Db>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    device_auth::check_owner(&conn, &user.uuid.to_string(), &body.serial_number).await?;
+
+    let device_credential = device_auth::rotate(&conn, &body.serial_number).await
+        .context("Device credential change failed")?;
+    hub.disconnect(&Topic::Device(body.serial_number.clone()), CloseReason::Revoked);
+
+    let response = DeviceCredentialResponse {
+        status: "success".to_string(),
+        message: format!("New credential issued for device {}", body.serial_number),
+        serial_number: body.serial_number,
+        device_credential,
+    };
+    Ok(with_status(json(&response), StatusCode::CREATED))
 }
 
-pub async fn device_lookup_handler(body: DeviceRequest, conn: Arc<libsql::Connection>) -> WebResult<impl Reply> {
-    if let Ok(val) = Database::keyword_exists(&conn, WLdbKeyword::SerialNumber(body.serial_number.clone())).await {
-        if !val {
-            let error_response = GenericResponse {
-                status: "fail".to_string(),
-                message: "Device with this serial number not found".to_string(),
-            };
-            return Ok(with_status(json(&error_response), StatusCode::NOT_FOUND));
-        }
-    } else {
-        // Error checking if serial number exists
-        let error_response = GenericResponse {
-            status: "error".to_string(),
-            message: "Failed to query database".to_string(),
-        };
-        return Ok(with_status(json(&error_response), StatusCode::INTERNAL_SERVER_ERROR));
+pub async fn revoke_credentials_handler(user: AuthUser, body: DeviceRequest, conn: Arc<Db>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    device_auth::check_owner(&conn, &user.uuid.to_string(), &body.serial_number).await?;
+
+    let revoked = device_auth::revoke_all(&conn, &body.serial_number).await
+        .context("Device credential change failed")?;
+    hub.disconnect(&Topic::Device(body.serial_number.clone()), CloseReason::Revoked);
+
+    let json_response = GenericResponse {
+        status: "success".to_string(),
+        message: format!("Revoked {} credential(s) for device {}", revoked, body.serial_number),
+    };
+    Ok(with_status(json(&json_response), StatusCode::OK))
+}
+
+/// A device asking about itself
+pub async fn device_self_handler(device: AuthDevice, conn: Arc<Db>) -> WebResult<impl Reply> {
+    let record = Devices::find(&conn, &device.serial_number).await
+        .context("Failed to fetch device")?
+        // Released between the filter and here
+        .ok_or_else(|| ApiError::not_found("device_not_found", "Device not found"))?;
+
+    let response = AccountDevice {
+        serial_number: record.serial_number,
+        device_name: record.device_name,
+        claimed_at: record.claimed_at,
+        visibility: record.visibility,
+    };
+    Ok(with_status(json(&response), StatusCode::OK))
+}
+
+/// A device swapping its own credential for a new one, the one it used stops working
+pub async fn device_rotate_credentials_handler(device: AuthDevice, conn: Arc<Db>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    let device_credential = device_auth::rotate(&conn, &device.serial_number).await
+        .context("Device credential change failed")?;
+    hub.disconnect(&Topic::Device(device.serial_number.clone()), CloseReason::Revoked);
+
+    let response = DeviceCredentialResponse {
+        status: "success".to_string(),
+        message: format!("Credential {} has been replaced", device.key_id),
+        serial_number: device.serial_number,
+        device_credential,
+    };
+    Ok(with_status(json(&response), StatusCode::CREATED))
+}
+
+/// An owner sending a wink from one of their devices
+pub async fn send_wink_handler(user: AuthUser, body: SendWinkRequest, conn: Arc<Db>, config: Arc<Config>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    let sender = wink::owner_sender(&conn, &user.uuid.to_string(), body.from_serial_number.as_deref()).await;
+    send_wink_reply(&conn, &config, &hub, sender, body).await
+}
+
+/// A device sending a wink itself
+pub async fn device_send_wink_handler(device: AuthDevice, body: SendWinkRequest, conn: Arc<Db>, config: Arc<Config>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    let sender = wink::device_sender(&conn, &device, body.from_serial_number.as_deref()).await;
+    send_wink_reply(&conn, &config, &hub, sender, body).await
+}
+
+async fn send_wink_reply(
+    conn: &libsql::Connection,
+    config: &Config,
+    hub: &Hub,
+    sender: Result<WinkSender, WinkError>,
+    body: SendWinkRequest,
+) -> WebResult<WithStatus<Json>> {
+    let sent = wink::send(conn, &config.winks, &sender?, &body.to_serial_number, &body.kind, body.payload.as_deref(), body.ttl_secs).await?;
+
+    // Only counts as an attempt if there's someone there to get it
+    let pushed = hub.is_connected(&sent.to_serial_number);
+    realtime::notify_device(hub, conn, &sent.to_serial_number, Event::Wink { wink: wink::response(&sent) }).await;
+    if pushed && let Err(e) = Winks::record_attempt(conn, sent.id).await {
+        log::warn!("Failed to record delivery attempt for wink {}: {}", sent.uuid, e);
     }
 
-    // Query for device details
-    let mut stmt = match conn.prepare("SELECT device_owner, device_name FROM users WHERE serial_number = ?").await {
-        Ok(stmt) => stmt,
-        Err(_) => {
-            let error_response = GenericResponse {
-                status: "error".to_string(),
-                message: "Failed to prepare database query".to_string(),
-            };
-            return Ok(with_status(json(&error_response), StatusCode::INTERNAL_SERVER_ERROR));
-        }
+    let response = SendWinkResponse {
+        status: "success".to_string(),
+        message: format!("Winked at {}", body.to_serial_number),
+        wink_id: sent.uuid,
     };
-    let mut rows = match stmt.query(params![body.serial_number]).await {
-        Ok(rows) => rows,
-        Err(_) => {
-            let error_response = GenericResponse {
-                status: "error".to_string(),
-                message: "Database query failed".to_string(),
-            };
-            return Ok(with_status(json(&error_response), StatusCode::INTERNAL_SERVER_ERROR));
-        }
+    Ok(with_status(json(&response), StatusCode::CREATED))
+}
+
+pub async fn inbox_handler(user: AuthUser, query: InboxQuery, conn: Arc<Db>) -> WebResult<impl Reply> {
+    let serial_number = wink::owner_device(&conn, &user.uuid.to_string(), query.serial_number.as_deref()).await;
+    inbox_reply(&conn, serial_number, query).await
+}
+
+pub async fn device_inbox_handler(device: AuthDevice, query: InboxQuery, conn: Arc<Db>) -> WebResult<impl Reply> {
+    let serial_number = wink::own_device(&device, query.serial_number.as_deref());
+    inbox_reply(&conn, serial_number, query).await
+}
+
+async fn inbox_reply(conn: &libsql::Connection, serial_number: Result<String, WinkError>, query: InboxQuery) -> WebResult<WithStatus<Json>> {
+    let page = wink::inbox(conn, &serial_number?, query.before, query.limit).await?;
+
+    let response = InboxResponse {
+        status: "success".to_string(),
+        winks: page.winks.iter().map(wink::response).collect(),
+        next_cursor: page.next_cursor,
     };
+    Ok(with_status(json(&response), StatusCode::OK))
+}
 
-    // Try to get the first row
-    match rows.next().await {
-        Ok(Some(row)) => {
-            // Extract the values from the row
-            let device_owner: String = match row.get(0) {
-                Ok(value) => value,
-                Err(_) => String::new(), // Default value if null
-            };
-            
-            let device_name: String = match row.get(1) {
-                Ok(value) => value,
-                Err(_) => String::new(), // Default value if null
-            };
-            
-            let response = WLDeviceResponse {
-                device_owner,
-                device_name,
-            };
-            
-            Ok(with_status(json(&response), StatusCode::OK))
-        },
-        Ok(None) => {
-            // This shouldn't happen since we checked existence above, but just in case
-            let error_response = GenericResponse {
-                status: "fail".to_string(),
-                message: "Device not found".to_string(),
-            };
-            Ok(with_status(json(&error_response), StatusCode::NOT_FOUND))
-        },
-        Err(_) => {
-            let error_response = GenericResponse {
-                status: "error".to_string(),
-                message: "Failed to retrieve device data".to_string(),
-            };
-            Ok(with_status(json(&error_response), StatusCode::INTERNAL_SERVER_ERROR))
-        }
+/// `GET /api/winks/dead-letters?serial_number=...`, winks one of your devices sent that were
+/// never delivered
+pub async fn dead_letters_handler(user: AuthUser, query: InboxQuery, conn: Arc<Db>) -> WebResult<impl Reply> {
+    let serial_number = wink::owner_device(&conn, &user.uuid.to_string(), query.serial_number.as_deref()).await;
+    dead_letters_reply(&conn, serial_number, query).await
+}
+
+pub async fn device_dead_letters_handler(device: AuthDevice, query: InboxQuery, conn: Arc<Db>) -> WebResult<impl Reply> {
+    let serial_number = wink::own_device(&device, query.serial_number.as_deref());
+    dead_letters_reply(&conn, serial_number, query).await
+}
+
+async fn dead_letters_reply(conn: &libsql::Connection, serial_number: Result<String, WinkError>, query: InboxQuery) -> WebResult<WithStatus<Json>> {
+    let page = wink::dead_letters(conn, &serial_number?, query.before, query.limit).await?;
+
+    let response = InboxResponse {
+        status: "success".to_string(),
+        winks: page.winks.iter().map(wink::response).collect(),
+        next_cursor: page.next_cursor,
+    };
+    Ok(with_status(json(&response), StatusCode::OK))
+}
+
+pub async fn ack_winks_handler(user: AuthUser, body: AckWinksRequest, conn: Arc<Db>) -> WebResult<impl Reply> {
+    let serial_number = wink::owner_device(&conn, &user.uuid.to_string(), body.serial_number.as_deref()).await;
+    ack_winks_reply(&conn, serial_number, body).await
+}
+
+pub async fn device_ack_winks_handler(device: AuthDevice, body: AckWinksRequest, conn: Arc<Db>) -> WebResult<impl Reply> {
+    let serial_number = wink::own_device(&device, body.serial_number.as_deref());
+    ack_winks_reply(&conn, serial_number, body).await
+}
+
+async fn ack_winks_reply(conn: &libsql::Connection, serial_number: Result<String, WinkError>, body: AckWinksRequest) -> WebResult<WithStatus<Json>> {
+    let changed = wink::acknowledge(conn, &serial_number?, &body.wink_ids, body.state).await?;
+
+    let state = match body.state {
+        WinkState::Delivered => "delivered",
+        WinkState::Read => "read",
+    };
+    let json_response = GenericResponse {
+        status: "success".to_string(),
+        message: format!("Marked {} wink(s) as {}", changed, state),
+    };
+    Ok(with_status(json(&json_response), StatusCode::OK))
+}
+
+/// `POST /api/contacts/<action>`, see `contacts::ContactAction` for the actions
+pub async fn contact_action_handler(action: String, user: AuthUser, body: ContactRequest, conn: Arc<Db>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    let Some(action) = ContactAction::from_path(&action) else {
+        return Err(ApiError::not_found("not_found", "Not found"));
+    };
+
+    let outcome = contacts::apply(&conn, &user.uuid.to_string(), &body.serial_number, &body.contact_serial_number, action).await?;
+
+    // Only the good news gets passed on, declines and blocks stay quiet
+    let event = match outcome {
+        Outcome::Requested => Some(Event::ContactRequest {
+            serial_number: body.contact_serial_number.clone(),
+            from_serial_number: body.serial_number.clone(),
+        }),
+        Outcome::Connected => Some(Event::ContactAccepted {
+            serial_number: body.contact_serial_number.clone(),
+            contact_serial_number: body.serial_number.clone(),
+        }),
+        _ => None,
+    };
+    if let Some(event) = event {
+        realtime::notify_device(&hub, &conn, &body.contact_serial_number, event).await;
     }
+
+    let json_response = GenericResponse {
+        status: "success".to_string(),
+        message: outcome.message().to_string(),
+    };
+    Ok(with_status(json(&json_response), StatusCode::OK))
+}
+
+pub async fn contacts_handler(user: AuthUser, query: DeviceRequest, conn: Arc<Db>) -> WebResult<impl Reply> {
+    contacts::check_owner(&conn, &user.uuid.to_string(), &query.serial_number).await?;
+    contacts_reply(&conn, query.serial_number).await
 }
 
-pub async fn login_handler(body: LoginRequest, conn: Arc<libsql::Connection>) -> WebResult<impl Reply> {
-    // Add basic validation for request body
-    if body.email.is_empty() || body.password.is_empty() {
-        let error_response = GenericResponse {
-            status: "fail".to_string(),
-            message: "Email and password are required".to_string(),
-        };
-        return Ok(with_status(json(&error_response), StatusCode::BAD_REQUEST));
+/// `POST /api/devices/self/heartbeat`, keeps a device online without a socket
+pub async fn heartbeat_handler(device: AuthDevice, body: HeartbeatRequest, conn: Arc<Db>, config: Arc<Config>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    let timeout = config.devices.online_timeout_secs;
+    presence::heartbeat(&conn, &hub, timeout, &device.serial_number, body.firmware_version.as_deref()).await?;
+
+    let response = HeartbeatResponse {
+        status: "success".to_string(),
+        // Half the timeout leaves room for one to go missing
+        next_heartbeat_secs: (timeout / 2).max(1),
+    };
+    Ok(with_status(json(&response), StatusCode::OK))
+}
+
+pub async fn device_contacts_handler(device: AuthDevice, conn: Arc<Db>) -> WebResult<impl Reply> {
+    contacts_reply(&conn, device.serial_number).await
+}
+
+async fn contacts_reply(conn: &libsql::Connection, serial_number: String) -> WebResult<WithStatus<Json>> {
+    let list = contacts::list(conn, &serial_number).await.context("Contact request failed")?;
+
+    let response = ContactsResponse {
+        status: "success".to_string(),
+        contacts: list.iter().map(|c| ContactResponse {
+            serial_number: c.serial_number.clone(),
+            state: contacts::state_of(&serial_number, c).to_string(),
+            device_name: c.device_name.clone(),
+            device_owner: c.owner_username.clone(),
+            updated_at: c.updated_at.clone(),
+        }).collect(),
+        serial_number,
+    };
+    Ok(with_status(json(&response), StatusCode::OK))
+}
+
+pub async fn reset_token_handler(user: AuthUser, body: DeviceRequest, conn: Arc<Db>) -> WebResult<impl Reply> {
+    let reset_token = release::issue_reset_token(&conn, &user.uuid.to_string(), &body.serial_number).await?;
+
+    let response = ResetTokenResponse {
+        status: "success".to_string(),
+        message: format!("Reset token issued for device {}", body.serial_number),
+        reset_token,
+    };
+    Ok(with_status(json(&response), StatusCode::CREATED))
+}
+
+/// `POST /api/devices/privacy`, who can look one of your devices up
+pub async fn privacy_handler(user: AuthUser, body: PrivacyRequest, conn: Arc<Db>) -> WebResult<impl Reply> {
+    privacy::set_visibility(&conn, &user.uuid.to_string(), &body.serial_number, body.visibility).await?;
+
+    let json_response = GenericResponse {
+        status: "success".to_string(),
+        message: format!("Device {} is now {}", body.serial_number, body.visibility.as_str()),
+    };
+    Ok(with_status(json(&json_response), StatusCode::OK))
+}
+
+pub async fn release_device_handler(user: AuthUser, body: ReleaseDeviceRequest, conn: Arc<Db>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    release::release_device(&conn, &user.uuid.to_string(), &body.serial_number, body.reset_token.as_deref()).await?;
+
+    hub.disconnect(&Topic::Device(body.serial_number.clone()), CloseReason::Revoked);
+    realtime::publish_to_account(&hub, &conn, &user.uuid.to_string(), &Event::DeviceReleased { serial_number: body.serial_number.clone() }).await;
+
+    let json_response = GenericResponse {
+        status: "success".to_string(),
+        message: format!("Device {} has been released", body.serial_number),
+    };
+    Ok(with_status(json(&json_response), StatusCode::OK))
+}
+
+/// `GET /api/devices/{serial}`, see `lookup.rs`
+pub async fn get_device_handler(
+    serial_number: String,
+    user: Option<AuthUser>,
+    quota: LookupQuota,
+    if_none_match: Option<String>,
+    conn: Arc<Db>,
+    config: Arc<Config>,
+    hub: Arc<Hub>,
+) -> WebResult<impl Reply> {
+    device_lookup_reply(&conn, &config, &hub, user, &quota, &serial_number, if_none_match.as_deref()).await
+}
+
+/// `POST /api/device`, the deprecated way of doing `GET /api/devices/{serial}`
+pub async fn device_lookup_handler(
+    user: Option<AuthUser>,
+    quota: LookupQuota,
+    body: DeviceRequest,
+    conn: Arc<Db>,
+    config: Arc<Config>,
+    hub: Arc<Hub>,
+) -> WebResult<impl Reply> {
+    // Errors get the headers too
+    let reply = device_lookup_reply(&conn, &config, &hub, user, &quota, &body.serial_number, None).await;
+    let reply = with_header(reply, "Deprecation", "true");
+    let successor = format!("</api/devices/{}>; rel=\"successor-version\"", body.serial_number);
+    Ok(with_header(reply, "Link", successor))
+}
+
+async fn device_lookup_reply(
+    conn: &libsql::Connection,
+    config: &Config,
+    hub: &Hub,
+    user: Option<AuthUser>,
+    quota: &LookupQuota,
+    serial_number: &str,
+    if_none_match: Option<&str>,
+) -> WebResult<warp::reply::Response> {
+    quota.take(user.is_none(), 1).map_err(lookup_rate_limited)?;
+
+    let account_uuid = user.map(|u| u.uuid.to_string());
+    let serial_numbers = [serial_number.to_string()];
+    let mut devices = lookup::lookup(conn, hub, config.devices.online_timeout_secs, account_uuid.as_deref(), &serial_numbers).await
+        .with_context(|| format!("Failed to look up {}", serial_number))?;
+    // Looks exactly like a device nobody has claimed, see privacy.rs
+    let device = devices.remove(serial_number)
+        .ok_or_else(|| ApiError::not_found("device_not_found", "Device with this serial number not found"))?;
+
+    let body = serde_json::to_vec(&device).unwrap_or_default();
+    let etag = lookup::etag(&body);
+    let reply = if lookup::not_modified(if_none_match, &etag) {
+        with_status(warp::reply(), StatusCode::NOT_MODIFIED).into_response()
+    } else {
+        with_status(json(&device), StatusCode::OK).into_response()
+    };
+
+    // What's in it depends on who's asking, so only their own cache gets to keep it
+    let reply = with_header(reply, "ETag", etag);
+    let reply = with_header(reply, "Cache-Control", "private, no-cache");
+    Ok(with_header(reply, "Vary", "Authorization").into_response())
+}
+
+/// `POST /api/devices:batchLookup`
+pub async fn batch_lookup_handler(
+    user: Option<AuthUser>,
+    quota: LookupQuota,
+    body: BatchLookupRequest,
+    conn: Arc<Db>,
+    config: Arc<Config>,
+    hub: Arc<Hub>,
+) -> WebResult<impl Reply> {
+    let mut serial_numbers = body.serial_numbers;
+    serial_numbers.sort();
+    serial_numbers.dedup();
+
+    let max = config.devices.batch_lookup_max;
+    if serial_numbers.is_empty() || serial_numbers.len() > max {
+        let message = format!("Send between 1 and {} serial numbers", max);
+        return Err(ApiError::invalid_field("batch_size_invalid", "serial_numbers", "out_of_range", message));
     }
-    
-    // Wrap the entire handler in a try-catch to prevent server crashes
-    match login_user(&body, &conn).await {
-        Ok(response) => {
-            return Ok(with_status(json(&response), StatusCode::OK));
-        },
-        Err(e) => {
-            let error_response = GenericResponse {
-                status: "error".to_string(),
-                message: format!("Login failed: {}", e),
-            };
-            return Ok(with_status(json(&error_response), StatusCode::INTERNAL_SERVER_ERROR));
-        }
+
+    quota.take(user.is_none(), serial_numbers.len()).map_err(lookup_rate_limited)?;
+
+    let account_uuid = user.map(|u| u.uuid.to_string());
+    let devices = lookup::lookup(&conn, &hub, config.devices.online_timeout_secs, account_uuid.as_deref(), &serial_numbers).await
+        .with_context(|| format!("Failed to look up {} devices", serial_numbers.len()))?;
+
+    let response = BatchLookupResponse {
+        status: "success".to_string(),
+        devices,
+    };
+    Ok(with_status(json(&response), StatusCode::OK))
+}
+
+fn lookup_rate_limited(retry_after_secs: u64) -> ApiError {
+    ApiError::TooManyRequests {
+        code: "rate_limited",
+        message: "Too many lookups, slow down".to_string(),
+        retry_after_secs,
     }
 }
 
-async fn login_user(body: &LoginRequest, conn: &Arc<libsql::Connection>) -> Result<LoginResponse, Box<dyn std::error::Error>> {
-    // Query user by email
-    let query = "SELECT uuid, email, device_owner, password_hash FROM users WHERE email = ?";
-    let mut stmt = conn.prepare(query).await?;
-    let row = stmt.query_row([body.email.clone()]).await?;
-
-    let user_id: String = row.get(0)?;
-    let stored_hash: String = row.get(3)?;
-    let username: String = row.get(2)?;
-
-    // Verify password using Argon2
-    let parsed_hash = PasswordHash::new(&stored_hash).map_err(|e| Box::<dyn std::error::Error>::from(e.to_string()))?;
-    if Argon2::default().verify_password(body.password.as_bytes(), &parsed_hash).is_ok() {
-        // Generate JWT token
-        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-        let claims = Claims {
-            sub: user_id.clone(),
-            exp: (now + 7 * 24 * 60 * 60) as usize,
-            iat: now as usize,
-        };
-        
-        let token = encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret("your_secret_key".as_ref())
-        )?;
-
-        Ok(LoginResponse {
-            status: "success".to_string(),
-            message: format!("User {} logged in successfully", username),
-            token,
-            user_id,
-        })
-    } else {
-        Err("Invalid email or password".into())
+pub async fn login_handler(
+    body: LoginRequest,
+    client_ip: IpAddr,
+    conn: Arc<Db>,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    body.validate()?;
+
+    let logged_in = auth::login(&conn, &config, &body.email, &body.password, client_ip).await?;
+
+    let response = LoginResponse {
+        status: "success".to_string(),
+        message: format!("User {} logged in successfully", logged_in.username),
+        token: logged_in.tokens.access_token,
+        refresh_token: logged_in.tokens.refresh_token,
+        expires_in: logged_in.tokens.expires_in,
+        user_id: logged_in.user_id,
+    };
+    Ok(with_status(json(&response), StatusCode::OK))
+}
+
+pub async fn refresh_handler(body: RefreshRequest, conn: Arc<Db>, config: Arc<Config>) -> WebResult<impl Reply> {
+    if body.refresh_token.is_empty() {
+        return Err(ApiError::invalid_field("refresh_token_required", "refresh_token", "required", "Refresh token is required"));
     }
-}
\ No newline at end of file
+
+    let (user_id, tokens) = auth::rotate_refresh_token(&conn, &config.auth, &body.refresh_token).await?;
+
+    let response = LoginResponse {
+        status: "success".to_string(),
+        message: "Token refreshed successfully".to_string(),
+        token: tokens.access_token,
+        refresh_token: tokens.refresh_token,
+        expires_in: tokens.expires_in,
+        user_id,
+    };
+    Ok(with_status(json(&response), StatusCode::OK))
+}
+
+pub async fn logout_handler(user: AuthUser, conn: Arc<Db>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    auth::logout(&conn, &user).await
+        .with_context(|| format!("Failed to log out user {}", user.uuid))?;
+    hub.disconnect_session(&user.uuid.to_string(), &user.session_id, CloseReason::Revoked);
+
+    let json_response = GenericResponse {
+        status: "success".to_string(),
+        message: "Logged out successfully".to_string(),
+    };
+    Ok(with_status(json(&json_response), StatusCode::OK))
+}
+
+pub async fn logout_all_handler(user: AuthUser, conn: Arc<Db>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    auth::logout_everywhere(&conn, &user).await
+        .with_context(|| format!("Failed to log out user {} everywhere", user.uuid))?;
+    hub.disconnect(&Topic::Account(user.uuid.to_string()), CloseReason::Revoked);
+
+    let json_response = GenericResponse {
+        status: "success".to_string(),
+        message: "Logged out of all sessions".to_string(),
+    };
+    Ok(with_status(json(&json_response), StatusCode::OK))
+}
+
+/// `GET /api/ws`, the socket itself is looked after by `realtime::serve`
+pub async fn realtime_handler(ws: warp::ws::Ws, subscriber: Subscriber, conn: Arc<Db>, config: Arc<Config>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    Ok(ws.on_upgrade(move |socket| realtime::serve(socket, hub, conn, config, subscriber)))
+}
+
+/// `GET /api/events`, the SSE version of `/api/ws` for the web UI
+pub async fn events_handler(user: AuthUser, last_event_id: Option<String>, conn: Arc<Db>, hub: Arc<Hub>) -> WebResult<impl Reply> {
+    let last_event_id = match last_event_id.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(id) => Some(id.parse::<i64>()
+            .map_err(|_| ApiError::bad_request("last_event_id_invalid", "Last-Event-ID must be an event id"))?),
+    };
+
+    let stream = realtime::event_stream(hub.clone(), &conn, &user, last_event_id).await
+        .with_context(|| format!("Failed to start event stream for {}", user.uuid))?;
+    let stream = warp::sse::keep_alive().interval(hub.heartbeat_interval()).stream(stream);
+    Ok(warp::sse::reply(stream).into_response())
+}
+
+pub async fn me_handler(user: AuthUser, conn: Arc<Db>) -> WebResult<impl Reply> {
+    let stmt = conn.prepare("SELECT uuid, username, email, created_at FROM accounts WHERE uuid = ?").await?;
+    let mut rows = stmt.query(params![user.uuid.to_string()]).await?;
+
+    // Valid token, but the account has since been deleted
+    let row = rows.next().await?
+        .ok_or_else(|| ApiError::not_found("account_not_found", "Account not found"))?;
+
+    let devices = Devices::list_for_account(&conn, &user.uuid.to_string()).await
+        .context("Failed to retrieve devices")?;
+
+    let response = AccountResponse {
+        user_id: row.get(0).unwrap_or_default(),
+        username: row.get(1).unwrap_or_default(),
+        email: row.get(2).unwrap_or_default(),
+        created_at: row.get(3).unwrap_or_default(),
+        devices: devices.into_iter().map(|device| AccountDevice {
+            serial_number: device.serial_number,
+            device_name: device.device_name,
+            claimed_at: device.claimed_at,
+            visibility: device.visibility,
+        }).collect(),
+    };
+    Ok(with_status(json(&response), StatusCode::OK))
+}
@@ -0,0 +1,122 @@
+//! Identity module
+//!
+//! What makes two emails or two usernames "the same" for uniqueness and for logging in. The
+//! columns keep whatever the person typed, for showing back to them, and `email_key` /
+//! `username_key` next to them hold the normalised form, which is what has the unique index and
+//! what lookups go through.
+//!
+//! - Emails are NFKC normalised and lowercased, so `Bob@x.com`, `bob@x.com` and `ｂｏｂ@x.com` are
+//!   one address.
+//! - Usernames get the same treatment and then the UTS #39 confusable skeleton on top (lowercased
+//!   again, the skeleton likes capitals), so `paypal` and `pаypal` (with a Cyrillic `а`), `bob`
+//!   and `b0b`, or `modern` and `rnodern` are one username.
+//!
+//! Accounts from before the key columns existed are filled in by [`backfill_keys`] on startup.
+//! If two old accounts turn out to share a key, the oldest one gets it and the other is logged
+//! and left without, it can still log in with its email exactly as it was registered.
+
+use libsql::Connection;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+
+use crate::database::Register;
+
+/// What emails are compared by
+pub fn email_key(email: &str) -> String {
+    fold(email.trim())
+}
+
+/// What usernames are compared by, look-alikes come out the same
+pub fn username_key(username: &str) -> String {
+    skeleton(&fold(username.trim())).collect::<String>().to_lowercase()
+}
+
+/// NFKC and lowercase. Lowercasing can undo the normalisation for a handful of characters, hence
+/// the second pass.
+fn fold(value: &str) -> String {
+    value.nfkc().collect::<String>().to_lowercase().nfkc().collect()
+}
+
+/// Fills in `email_key` and `username_key` for accounts that don't have them yet, oldest first
+pub async fn backfill_keys(conn: &Connection) -> anyhow::Result<()> {
+    for account in Register::missing_keys(conn).await? {
+        if account.email_key.is_none() {
+            let key = email_key(&account.email);
+            if Register::email_key_taken(conn, &key).await? {
+                log::warn!("Account {} has the same email as an older one once normalised ({}), leaving its email_key empty", account.uuid, key);
+            } else {
+                Register::set_email_key(conn, &account.uuid, &key).await?;
+            }
+        }
+        if account.username_key.is_none() {
+            let key = username_key(&account.username);
+            if Register::username_key_taken(conn, &key).await? {
+                log::warn!("Account {} has a username confusable with an older one ({}), leaving its username_key empty", account.uuid, account.username);
+            } else {
+                Register::set_username_key(conn, &account.uuid, &key).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database::{Database, WLdbKeyword}, test_support::{account, test_db}};
+
+    #[test]
+    fn emails() {
+        for same in ["Bob@x.com", "BOB@X.COM", "ｂｏｂ@x.com", " bob@x.com "] {
+            assert_eq!(email_key(same), "bob@x.com", "{}", same);
+        }
+        assert_ne!(email_key("bob@y.com"), email_key("bob@x.com"));
+    }
+
+    #[test]
+    fn look_alike_usernames() {
+        for (a, b) in [("paypal", "pаypal"), ("bob", "b0b"), ("modern", "rnodern"), ("Bob", "bob"), ("bob", "ｂｏｂ")] {
+            assert_eq!(username_key(a), username_key(b), "{} and {}", a, b);
+        }
+        for (a, b) in [("alice", "alicia"), ("bob", "rob"), ("bob_1", "bob_2")] {
+            assert_ne!(username_key(a), username_key(b), "{} and {}", a, b);
+        }
+    }
+
+    #[tokio::test]
+    async fn taken_goes_by_key() {
+        let db = test_db().await;
+        account(&db, "bob").await;
+
+        assert!(Database::keyword_exists(&db, WLdbKeyword::Email("BOB@Example.com".to_string())).await.unwrap());
+        assert!(Database::keyword_exists(&db, WLdbKeyword::Username("B0B".to_string())).await.unwrap());
+        assert!(!Database::keyword_exists(&db, WLdbKeyword::Username("rob".to_string())).await.unwrap());
+
+        // The unique index catches whatever gets past the check
+        let tx = Database::start_transaction(&db).await.unwrap();
+        assert!(Register::create_account(&tx, "other@example.com", "bоb", "correct horse 1").await.is_err());
+        let _ = tx.rollback().await;
+    }
+
+    /// Two old accounts that only differ by case: the older one gets the keys, the other can
+    /// still log in with its email exactly as it was
+    #[tokio::test]
+    async fn backfill_collisions() {
+        let db = test_db().await;
+        for (uuid, email, username) in [("older", "Bob@example.com", "bob"), ("newer", "bob@example.com", "b0b")] {
+            db.execute("INSERT INTO accounts (uuid, email, username, password_hash, created_at) VALUES (?, ?, ?, 'x', '2020-01-01T00:00:00Z')",
+                [uuid, email, username]).await.unwrap();
+        }
+
+        backfill_keys(&db).await.unwrap();
+        // Again is harmless, the newer one is just logged again
+        backfill_keys(&db).await.unwrap();
+
+        let keys = Register::missing_keys(&db).await.unwrap();
+        assert_eq!(keys.iter().map(|a| (a.uuid.as_str(), a.email_key.as_deref(), a.username_key.as_deref())).collect::<Vec<_>>(),
+            [("newer", None, None)]);
+        assert_eq!(Register::find_login(&db, "BOB@example.com").await.unwrap().map(|a| a.uuid).as_deref(), Some("older"));
+        assert_eq!(Register::find_login(&db, "bob@example.com").await.unwrap().map(|a| a.uuid).as_deref(), Some("newer"));
+    }
+}
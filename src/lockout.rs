@@ -0,0 +1,284 @@
+//! Lockout module
+//!
+//! Keeps people from guessing passwords at `/api/login`. Failed logins are counted per email and
+//! per IP in `login_throttles`, so a restart doesn't hand out a fresh set of guesses.
+//!
+//! - An email is counted whether or not anyone has it, otherwise getting locked out would tell
+//!   you there's an account behind it.
+//! - After `login.max_failures` in a row an email is locked for `login.lockout_secs`. Every
+//!   failure once the lock runs out doubles it, up to `login.max_lockout_secs`.
+//! - Same for an IP after `login.max_failures_per_ip`, across whatever emails it tries.
+//! - Every attempt is counted as a failure before the password is checked, and handed back if
+//!   it turns out right. That's a single upsert per email and IP, so parallel guesses can't all
+//!   get in before the first failure lands. Once a lock runs out, one more guess gets in.
+//! - While either is locked, or out of guesses with the last one still being checked, logins
+//!   fail with a `429` without the password being looked at, and those don't count towards the
+//!   next lock.
+//! - Logging in clears the email's count but not the IP's, one good password shouldn't buy a
+//!   spraying IP another round.
+//! - Failures are forgotten after `login.failure_window_secs` without another one.
+//!
+//! Every failed login, locked out or not, ends up in `login_failures` for
+//! `login.audit_retention_secs`. To unlock before the time's up, run
+//! `Winklink-Web-API unlock-login <email or IP>`.
+
+use std::net::IpAddr;
+
+use libsql::Connection;
+
+use crate::{config::LoginConfig, database::{Database, Db, LoginFailures, LoginThrottles}, identity::email_key};
+
+const EMAIL: &str = "email";
+const IP: &str = "ip";
+
+/// Why a login failed, for the audit trail
+#[derive(Debug, Clone, Copy)]
+pub enum FailureReason {
+    InvalidCredentials,
+    Locked,
+}
+
+impl FailureReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureReason::InvalidCredentials => "invalid_credentials",
+            FailureReason::Locked => "locked",
+        }
+    }
+}
+
+/// If the email or the IP is locked out, seconds until both are free again
+pub async fn locked_for(conn: &Connection, email: &str, ip: IpAddr) -> anyhow::Result<Option<u64>> {
+    let now = chrono::Utc::now().timestamp();
+
+    let mut locked_until = 0;
+    for (kind, subject) in [(EMAIL, email_key(email)), (IP, ip.to_string())] {
+        locked_until = locked_until.max(LoginThrottles::locked_until(conn, kind, &subject).await?);
+    }
+
+    Ok((locked_until > now).then(|| (locked_until - now) as u64))
+}
+
+/// A login attempt that has already been counted against the email and the IP, see `reserve`
+pub struct Reservation {
+    email: String,
+    ip: String,
+    email_failures: i64,
+    ip_failures: i64,
+}
+
+/// Counts an attempt against the email and the IP, before the password gets checked. `None` if
+/// either is locked out or has no guesses left.
+pub async fn reserve(conn: &Db, config: &LoginConfig, email: &str, ip: IpAddr) -> anyhow::Result<Option<Reservation>> {
+    let now = chrono::Utc::now().timestamp();
+    let forget_before = now - config.failure_window_secs as i64;
+    let email = email_key(email);
+    let ip = ip.to_string();
+
+    // Both or neither, a locked IP shouldn't use up one of the email's guesses
+    let tx = Database::start_transaction(conn).await?;
+    let reserved = async {
+        let Some(email_failures) = LoginThrottles::reserve(&tx, EMAIL, &email, now, forget_before, config.max_failures).await? else {
+            return Ok(None);
+        };
+        let Some(ip_failures) = LoginThrottles::reserve(&tx, IP, &ip, now, forget_before, config.max_failures_per_ip).await? else {
+            return Ok(None);
+        };
+        anyhow::Ok(Some((email_failures, ip_failures)))
+    }.await;
+
+    match reserved {
+        Ok(Some((email_failures, ip_failures))) => {
+            Database::commit_transaction(tx).await?;
+            Ok(Some(Reservation { email, ip, email_failures, ip_failures }))
+        }
+        Ok(None) => {
+            let _ = tx.rollback().await;
+            Ok(None)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+/// Writes a login turned away by a lockout to the audit trail, it doesn't count towards the next
+/// lock
+pub async fn record_locked(conn: &Connection, email: &str, account_uuid: Option<&str>, ip: IpAddr) -> anyhow::Result<()> {
+    let now = chrono::Utc::now().timestamp();
+    LoginFailures::insert(conn, &email_key(email), account_uuid, &ip.to_string(), FailureReason::Locked.as_str(), now).await
+}
+
+/// Writes a wrong password to the audit trail, and locks the email or IP if its reserved attempt
+/// was one too many
+pub async fn record_failure(
+    conn: &Connection,
+    config: &LoginConfig,
+    reservation: Reservation,
+    account_uuid: Option<&str>,
+) -> anyhow::Result<()> {
+    let now = chrono::Utc::now().timestamp();
+    let Reservation { email, ip, email_failures, ip_failures } = reservation;
+
+    LoginFailures::insert(conn, &email, account_uuid, &ip, FailureReason::InvalidCredentials.as_str(), now).await?;
+
+    for (kind, subject, failures, max_failures) in [(EMAIL, &email, email_failures, config.max_failures), (IP, &ip, ip_failures, config.max_failures_per_ip)] {
+        if let Some(lockout) = lockout_secs(config, failures, max_failures) {
+            LoginThrottles::lock(conn, kind, subject, now + lockout as i64).await?;
+            log::warn!("Locked out {} {} for {}s after {} failed login(s)", kind, subject, lockout, failures);
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts the email's count from scratch after a successful login, and hands the IP back the
+/// attempt it was charged
+pub async fn record_success(conn: &Connection, reservation: Reservation) -> anyhow::Result<()> {
+    LoginThrottles::clear(conn, EMAIL, &reservation.email).await?;
+    LoginThrottles::release(conn, IP, &reservation.ip).await?;
+    Ok(())
+}
+
+/// Lifts the lockout and forgets the failures of an email or IP, for the `unlock-login` admin
+/// command. Returns whether there was anything to forget.
+pub async fn unlock(conn: &Connection, email_or_ip: &str) -> anyhow::Result<bool> {
+    match email_or_ip.parse::<IpAddr>() {
+        Ok(ip) => LoginThrottles::clear(conn, IP, &ip.to_string()).await,
+        Err(_) => LoginThrottles::clear(conn, EMAIL, &email_key(email_or_ip)).await,
+    }
+}
+
+/// Drops counts that have been forgotten anyway and audit rows past their retention.
+/// Returns how many of each went.
+pub async fn prune(conn: &Connection, config: &LoginConfig) -> anyhow::Result<(u64, u64)> {
+    let now = chrono::Utc::now().timestamp();
+
+    let throttles = LoginThrottles::prune(conn, now - config.failure_window_secs as i64, now).await?;
+    let failures = LoginFailures::prune_before(conn, now - config.audit_retention_secs as i64).await?;
+
+    Ok((throttles, failures))
+}
+
+/// How long to lock for after `failures` in a row, if that's enough to lock at all
+fn lockout_secs(config: &LoginConfig, failures: i64, max_failures: u32) -> Option<u64> {
+    let past_limit = failures.checked_sub(max_failures as i64).filter(|past| *past >= 0)?;
+
+    // Doubling more than 32 times overshoots any sane maximum anyway
+    let lockout = config.lockout_secs.saturating_mul(1 << past_limit.min(32));
+    Some(lockout.min(config.max_lockout_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::task::JoinSet;
+
+    use super::*;
+    use crate::{auth::{self, LoginError}, config::Config, test_support::{account, test_db}};
+
+    async fn count(conn: &Connection, sql: &str) -> i64 {
+        conn.query(sql, ()).await.unwrap().next().await.unwrap().unwrap().get(0).unwrap()
+    }
+
+    /// Fires `n` wrong passwords at alice@example.com at once, returns how many got as far as
+    /// the password check and how many were turned away
+    async fn guess_at_once(db: &Arc<Db>, config: &Arc<Config>, n: usize) -> (usize, usize) {
+        let mut tasks = JoinSet::new();
+        for i in 0..n {
+            tasks.spawn({
+                let (db, config) = (db.clone(), config.clone());
+                async move {
+                    let ip = format!("10.0.0.{}", i).parse().unwrap();
+                    auth::login(&db, &config, "alice@example.com", "wrong password", ip).await
+                }
+            });
+        }
+
+        let (mut checked, mut locked) = (0, 0);
+        while let Some(result) = tasks.join_next().await {
+            match result.unwrap() {
+                Err(LoginError::InvalidCredentials) => checked += 1,
+                Err(LoginError::Locked { .. }) => locked += 1,
+                other => panic!("Unexpected login result {:?}", other.map(|_| ())),
+            }
+        }
+        (checked, locked)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_guesses_stay_within_the_limit() {
+        let db = test_db().await;
+        account(&db, "alice").await;
+        let mut config = Config::default();
+        config.login.max_failures = 3;
+        let config = Arc::new(config);
+
+        assert_eq!(guess_at_once(&db, &config, 12).await, (3, 9));
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM login_failures WHERE reason = 'invalid_credentials'").await, 3);
+        assert!(locked_for(&db, "alice@example.com", "10.0.0.1".parse().unwrap()).await.unwrap().is_some());
+    }
+
+    /// Once a lock runs out one more guess gets in, and getting that wrong doubles the lock
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn one_guess_after_a_lock_runs_out() {
+        let db = test_db().await;
+        account(&db, "alice").await;
+        let mut config = Config::default();
+        config.login.max_failures = 2;
+        let config = Arc::new(config);
+
+        assert_eq!(guess_at_once(&db, &config, 4).await, (2, 2));
+        db.execute("UPDATE login_throttles SET locked_until = 1 WHERE kind = 'email'", ()).await.unwrap();
+
+        assert_eq!(guess_at_once(&db, &config, 4).await, (1, 3));
+        let locked_until = LoginThrottles::locked_until(&db, EMAIL, "alice@example.com").await.unwrap();
+        let lockout = locked_until - chrono::Utc::now().timestamp();
+        assert!(lockout > config.login.lockout_secs as i64 && lockout <= 2 * config.login.lockout_secs as i64);
+    }
+
+    /// The right password gets the IP's attempt back and wipes the email's count
+    #[tokio::test]
+    async fn logging_in_hands_the_attempt_back() {
+        let db = test_db().await;
+        account(&db, "alice").await;
+        let config = Config::default();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let _ = auth::login(&db, &config, "alice@example.com", "wrong password", ip).await;
+        auth::login(&db, &config, "alice@example.com", "correct horse 1", ip).await.unwrap();
+
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM login_throttles WHERE kind = 'email'").await, 0);
+        assert_eq!(count(&db, "SELECT failures FROM login_throttles WHERE kind = 'ip'").await, 1);
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_the_cap() {
+        let config = LoginConfig { lockout_secs: 60, max_lockout_secs: 300, ..LoginConfig::default() };
+
+        assert_eq!(lockout_secs(&config, 4, 5), None);
+        assert_eq!(lockout_secs(&config, 5, 5), Some(60));
+        assert_eq!(lockout_secs(&config, 6, 5), Some(120));
+        assert_eq!(lockout_secs(&config, 7, 5), Some(240));
+        assert_eq!(lockout_secs(&config, 8, 5), Some(300));
+        assert_eq!(lockout_secs(&config, i64::MAX, 5), Some(300));
+    }
+
+    /// One IP guessing at lots of emails gets locked out even though no email is
+    #[tokio::test]
+    async fn ip_limit_spans_emails() {
+        let db = test_db().await;
+        let mut config = Config::default();
+        config.login.max_failures_per_ip = 2;
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        for email in ["a@example.com", "b@example.com"] {
+            assert!(matches!(auth::login(&db, &config, email, "password 1", ip).await, Err(LoginError::InvalidCredentials)));
+        }
+        assert!(matches!(auth::login(&db, &config, "c@example.com", "password 1", ip).await, Err(LoginError::Locked { .. })));
+        assert!(matches!(auth::login(&db, &config, "c@example.com", "password 1", "10.0.0.2".parse().unwrap()).await,
+            Err(LoginError::InvalidCredentials)));
+    }
+}
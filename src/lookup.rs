@@ -0,0 +1,200 @@
+//! Lookup module
+//!
+//! Looking devices up by serial number:
+//!
+//! - `GET /api/devices/{serial}` for one device. The reply has an `ETag`, send it back in
+//!   `If-None-Match` and you get a `304` if nothing changed.
+//! - `POST /api/devices:batchLookup` for up to `devices.batch_lookup_max` at once, in one query.
+//! - `POST /api/device` is the old way and does the same as the `GET`. It's deprecated and says
+//!   so in its headers.
+//!
+//! What anyone gets to see is decided here, from the device's visibility (see `privacy.rs`), any
+//! blocks (see `contacts.rs`) and presence (see `presence.rs`). A device the caller can't see
+//! is left out exactly as if nobody had claimed it. Anonymous callers are rate limited per IP,
+//! each serial number in a batch counts as a lookup.
+
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc};
+
+use libsql::Connection;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    database::{Devices, LookupRecord},
+    presence,
+    privacy::{self, Viewer},
+    ratelimit::RateLimiter,
+    realtime::Hub,
+    response::WLDeviceResponse,
+};
+
+/// Who's asking, for the anonymous rate limit
+#[derive(Clone)]
+pub struct LookupQuota {
+    pub limiter: Arc<RateLimiter<IpAddr>>,
+    pub client_ip: IpAddr,
+}
+
+impl LookupQuota {
+    /// Takes `lookups` out of the caller's allowance. Logged in callers can be dealt with if they
+    /// misbehave, so it's only anonymous ones who have one. On `Err`, seconds until there's room.
+    pub fn take(&self, anonymous: bool, lookups: usize) -> Result<(), u64> {
+        if !anonymous {
+            return Ok(());
+        }
+        self.limiter.check(self.client_ip, lookups)
+    }
+}
+
+/// The devices out of `serial_numbers` that `account_uuid` (if anyone's logged in) can see
+pub async fn lookup(
+    conn: &Connection,
+    hub: &Hub,
+    online_timeout_secs: u64,
+    account_uuid: Option<&str>,
+    serial_numbers: &[String],
+) -> anyhow::Result<BTreeMap<String, WLDeviceResponse>> {
+    let records = Devices::lookup(conn, serial_numbers, account_uuid).await?;
+
+    Ok(records.into_iter()
+        .filter_map(|record| visible(hub, online_timeout_secs, account_uuid, record))
+        .collect())
+}
+
+fn visible(hub: &Hub, online_timeout_secs: u64, account_uuid: Option<&str>, record: LookupRecord) -> Option<(String, WLDeviceResponse)> {
+    // If the device has blocked one of the caller's, it doesn't exist as far as they're concerned
+    if record.blocks_caller {
+        return None;
+    }
+    let viewer = Viewer::of(&record.presence.account_uuid, account_uuid, record.is_contact);
+    if !privacy::can_see(record.visibility, viewer) {
+        return None;
+    }
+
+    // Presence is for the owner and the owners of its contacts, nobody else
+    let status = (viewer != Viewer::Other)
+        .then(|| presence::status(hub, online_timeout_secs, &record.serial_number, &record.presence));
+
+    let response = WLDeviceResponse {
+        device_owner: record.owner_username,
+        device_name: record.device_name,
+        status: status.as_ref().map(|s| s.label().to_string()),
+        last_seen_at: status.as_ref().and_then(|s| s.last_seen_at).map(|t| t.to_rfc3339()),
+        firmware_version: status.and_then(|s| s.firmware_version).filter(|_| viewer == Viewer::Owner),
+    };
+    Some((record.serial_number, response))
+}
+
+/// A strong ETag for a response body
+pub fn etag(body: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(&Sha256::digest(body)[..16]))
+}
+
+/// Does an `If-None-Match` header match `etag`? Weak comparison, like the spec asks for.
+pub fn not_modified(if_none_match: Option<&str>, etag: &str) -> bool {
+    let Some(if_none_match) = if_none_match else {
+        return false;
+    };
+
+    if_none_match.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use warp::{http::StatusCode, Reply};
+
+    use super::*;
+    use crate::{config::{Config, RealtimeConfig}, database::DeviceLinks, handler, models::Visibility, test_support::{account, device, test_db}};
+
+    fn quota() -> LookupQuota {
+        LookupQuota {
+            limiter: Arc::new(RateLimiter::new(1000, Duration::from_secs(60))),
+            client_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        }
+    }
+
+    #[test]
+    fn etags_follow_the_body() {
+        let etag = etag(b"{\"device_name\":\"Kitchen\"}");
+
+        assert!(etag.starts_with('"') && etag.ends_with('"') && etag.len() == 34);
+        assert_eq!(etag, super::etag(b"{\"device_name\":\"Kitchen\"}"));
+        assert_ne!(etag, super::etag(b"{\"device_name\":\"Hallway\"}"));
+    }
+
+    #[test]
+    fn if_none_match() {
+        let etag = etag(b"body");
+
+        assert!(!not_modified(None, &etag));
+        assert!(not_modified(Some(&etag), &etag));
+        assert!(not_modified(Some(&format!("W/{}", etag)), &etag));
+        assert!(not_modified(Some(&format!("\"stale\", {}", etag)), &etag));
+        assert!(not_modified(Some("*"), &etag));
+        assert!(!not_modified(Some("\"stale\""), &etag));
+        assert!(!not_modified(Some(""), &etag));
+    }
+
+    #[test]
+    fn only_anonymous_lookups_are_limited() {
+        let quota = LookupQuota {
+            limiter: Arc::new(RateLimiter::new(2, Duration::from_secs(60))),
+            client_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        };
+
+        quota.take(true, 2).unwrap();
+        assert!(quota.take(true, 1).is_err());
+        quota.take(false, 100).unwrap();
+    }
+
+    /// Bob's device has blocked Alice's, so as far as Alice is concerned it doesn't exist
+    #[tokio::test]
+    async fn blocked_callers_dont_find_the_device() {
+        let db = test_db().await;
+        let hub = Hub::new(RealtimeConfig::default());
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let (alices, bobs) = (device(&db, &alice).await, device(&db, &bob).await);
+        privacy::set_visibility(&db, &bob, &bobs, Visibility::Public).await.unwrap();
+        DeviceLinks::set(&db, &bobs, &alices, "blocked", &bobs, Some(&bobs)).await.unwrap();
+
+        let serial_numbers = [bobs.clone()];
+        assert!(lookup(&db, &hub, 60, Some(&alice), &serial_numbers).await.unwrap().is_empty());
+        assert!(lookup(&db, &hub, 60, None, &serial_numbers).await.unwrap().contains_key(&bobs));
+    }
+
+    /// The ETag changes with the device, and a matching `If-None-Match` gets a 304 without a body
+    #[tokio::test]
+    async fn etag_and_not_modified() {
+        let db = test_db().await;
+        let config = Arc::new(Config::default());
+        let hub = Arc::new(Hub::new(RealtimeConfig::default()));
+        let alice = account(&db, "alice").await;
+        let serial_number = device(&db, &alice).await;
+        privacy::set_visibility(&db, &alice, &serial_number, Visibility::Public).await.unwrap();
+
+        let get = |if_none_match: Option<String>| {
+            let (db, config, hub, serial_number) = (db.clone(), config.clone(), hub.clone(), serial_number.clone());
+            async move {
+                handler::get_device_handler(serial_number, None, quota(), if_none_match, db, config, hub).await.unwrap().into_response()
+            }
+        };
+
+        let first = get(None).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()["Cache-Control"], "private, no-cache");
+        let etag = first.headers()["ETag"].to_str().unwrap().to_string();
+
+        let again = get(Some(etag.clone())).await;
+        assert_eq!(again.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(again.headers()["ETag"], etag.as_str());
+        assert!(warp::hyper::body::to_bytes(again.into_body()).await.unwrap().is_empty());
+
+        db.execute("UPDATE devices SET device_name = 'Hallway' WHERE serial_number = ?", [serial_number.as_str()]).await.unwrap();
+        let changed = get(Some(etag.clone())).await;
+        assert_eq!(changed.status(), StatusCode::OK);
+        assert_ne!(changed.headers()["ETag"], etag.as_str());
+    }
+}
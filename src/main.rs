@@ -1,60 +1,453 @@
-use std::sync::Arc;
+// warp nests a type per `.or()`, the route list outgrew the default
+#![recursion_limit = "256"]
 
-use libsql::Connection;
-use warp::{http::Method, Filter, Rejection, Reply};
-use crate::database::Database;
-use crate::models::DeviceRequest;
+use std::{net::IpAddr, path::Path, sync::Arc, time::Duration};
 
+use warp::{http::Method, Filter, Rejection};
+use crate::auth::AuthUser;
+use crate::device_auth::AuthDevice;
+use crate::error::ApiError;
+use crate::config::Config;
+use crate::database::{Database, Db, DeviceChallenges, Events, ManufacturedDevices, Winks};
+use crate::lookup::LookupQuota;
+use crate::models::{BatchLookupRequest, DeviceRequest, HeartbeatRequest, InboxQuery, RealtimeQuery};
+use crate::ratelimit::RateLimiter;
+use crate::realtime::{Hub, Subscriber};
+
+mod auth;
+mod config;
+mod contacts;
 mod database;
+mod device_auth;
+mod error;
 mod handler;
+mod identity;
+mod lockout;
+mod lookup;
+mod migrations;
 mod models;
+mod possession;
+mod presence;
+mod privacy;
+mod ratelimit;
+mod realtime;
+mod registry;
+mod release;
 mod response;
+mod serial;
+#[cfg(test)]
+mod test_support;
+mod transfer;
+mod validation;
+mod wink;
 
-type WebResult<T> = std::result::Result<T, Rejection>;
+type WebResult<T> = std::result::Result<T, ApiError>;
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     pretty_env_logger::init();
 
+    // Load and validate the config, refuses to start if anything is off
+    let config = Arc::new(Config::load()?);
+    log::debug!("Running with {:?}", config);
+
     // Initialize the database connection and wrap it in an Arc
-    let conn = Arc::new(Database::init_db().await?);
+    let conn = Arc::new(Database::init_db(&config.database).await?);
+
+    // Admin commands, these do their thing and exit instead of starting the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => {}
+        ["import-devices", path] => {
+            let summary = registry::import_csv(&conn, Path::new(path)).await?;
+            println!("Imported {} device(s), skipped {} already in the registry", summary.imported, summary.skipped);
+            return Ok(());
+        }
+        ["revoke-device", serial_number] => {
+            if ManufacturedDevices::revoke(&conn, serial_number).await? {
+                println!("Revoked {}", serial_number);
+            } else {
+                println!("{} is not in the registry or is already revoked", serial_number);
+            }
+            return Ok(());
+        }
+        ["claim-code", serial_number] => {
+            // For reprinting the card that comes in the box
+            let Some(device) = ManufacturedDevices::find(&conn, serial_number).await? else {
+                anyhow::bail!("{} is not in the registry", serial_number);
+            };
+            println!("{}", possession::claim_code(&hex::decode(&device.device_secret)?, serial_number)?);
+            return Ok(());
+        }
+        ["unlock-login", email_or_ip] => {
+            if lockout::unlock(&conn, email_or_ip).await? {
+                println!("Unlocked {}", email_or_ip);
+            } else {
+                println!("{} has no failed logins to forget", email_or_ip);
+            }
+            return Ok(());
+        }
+        _ => {
+            anyhow::bail!("Usage: Winklink-Web-API [import-devices <factory.csv> | revoke-device <serial number> | claim-code <serial number> | unlock-login <email or IP>]");
+        }
+    }
+
+    auth::prepare_login();
+
+    // Anonymous device lookups per IP, see privacy.rs
+    let lookup_limiter = Arc::new(RateLimiter::<IpAddr>::new(
+        config.devices.lookup_rate_limit,
+        Duration::from_secs(config.devices.lookup_rate_limit_window_secs),
+    ));
+
+    // Anonymous challenges per IP, each one is a row in the database
+    let challenge_limiter = Arc::new(RateLimiter::<IpAddr>::new(
+        config.devices.challenge_rate_limit,
+        Duration::from_secs(config.devices.challenge_rate_limit_window_secs),
+    ));
+
+    // Challenge nonces only need remembering until they would have expired anyway, events until
+    // SSE clients have had their chance to catch up, and dead letters until senders have had
+    // theirs to notice. Failed logins go once they've been forgotten or aged out of the audit
+    // trail.
+    let prune_conn = conn.clone();
+    let prune_login_config = config.login.clone();
+    let prune_lookup_limiter = lookup_limiter.clone();
+    let prune_challenge_limiter = challenge_limiter.clone();
+    let event_retention = config.realtime.event_retention_secs as i64;
+    let dead_letter_retention = config.winks.dead_letter_retention_secs as i64;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            match DeviceChallenges::prune_expired(&prune_conn).await {
+                Ok(pruned) => log::debug!("Pruned {} expired device challenge(s)", pruned),
+                Err(e) => log::error!("Failed to prune device challenges: {}", e),
+            }
+            match Events::prune_before(&prune_conn, chrono::Utc::now().timestamp() - event_retention).await {
+                Ok(pruned) => log::debug!("Pruned {} old event(s)", pruned),
+                Err(e) => log::error!("Failed to prune events: {}", e),
+            }
+            match Winks::prune_dead_letters(&prune_conn, chrono::Utc::now().timestamp() - dead_letter_retention).await {
+                Ok(pruned) => log::debug!("Pruned {} old dead letter(s)", pruned),
+                Err(e) => log::error!("Failed to prune dead letters: {}", e),
+            }
+            match lockout::prune(&prune_conn, &prune_login_config).await {
+                Ok((throttles, failures)) => log::debug!("Pruned {} login throttle(s) and {} old failed login(s)", throttles, failures),
+                Err(e) => log::error!("Failed to prune failed logins: {}", e),
+            }
+            prune_lookup_limiter.prune();
+            prune_challenge_limiter.prune();
+        }
+    });
+
+    // Everyone connected over WebSocket, handlers publish to it
+    let hub = Arc::new(Hub::new(config.realtime.clone()));
+
+    // Tells owners when their devices go quiet, see presence.rs
+    let sweep_conn = conn.clone();
+    let sweep_hub = hub.clone();
+    let online_timeout = config.devices.online_timeout_secs;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs((online_timeout / 4).clamp(1, 15)));
+        let mut last_cutoff = chrono::Utc::now().timestamp() - online_timeout as i64;
+        loop {
+            interval.tick().await;
+            match presence::sweep(&sweep_conn, &sweep_hub, online_timeout, &mut last_cutoff).await {
+                Ok(offline) => log::debug!("{} device(s) went offline", offline),
+                Err(e) => log::error!("Failed to sweep device presence: {}", e),
+            }
+        }
+    });
 
     // Define the health checker route
     let health_checker = warp::path!("api" / "healthchecker")
         .and(warp::get())
-        .and_then(handler::health_checker_handler);
+        .then(handler::health_checker_handler);
 
     // Define the register route
     let register_routes = warp::path!("api" / "register")
         .and(warp::post()) // Handle POST requests
         .and(warp::body::json()) // Parse the request body as JSON
         .and(with_db(conn.clone())) // Pass the database connection as a reference
-        .and_then(handler::register_handler);
+        .then(handler::register_handler);
 
     let login_routes = warp::path!("api" / "login")
         .and(warp::post())
         .and(warp::body::json()) // Parse the request body as JSON
+        .and(with_client_ip(config.clone())) // For the lockout, see lockout.rs
         .and(with_db(conn.clone())) // Pass the database connection
-        .and_then(handler::login_handler);
+        .and(with_config(config.clone())) // Needed to sign the token
+        .then(handler::login_handler);
 
+    // Deprecated, `GET /api/devices/{serial}` does the same
     let device_lookup_routes = warp::path!("api" / "device")
         .and(warp::post()) // Handle POST requests
+        .and(with_optional_auth(config.clone(), conn.clone())) // What callers see depends on who they are, see privacy.rs
+        .and(with_lookup_quota(lookup_limiter.clone(), config.clone())) // Anonymous lookups are rate limited per IP
         .and(warp::body::json::<DeviceRequest>()) // Parse the request body as JSON
         .and(with_db(conn.clone())) // Pass the database connection as a reference
-        .and_then(handler::device_lookup_handler);
+        .and(with_config(config.clone())) // For the online timeout
+        .and(with_hub(hub.clone())) // Open sockets count as online
+        .then(handler::device_lookup_handler);
+
+    let get_device_routes = warp::path!("api" / "devices" / String)
+        .and_then(valid_serial) // Leaves `/api/devices/self` and friends to their own routes
+        .and(warp::get())
+        .and(with_optional_auth(config.clone(), conn.clone()))
+        .and(with_lookup_quota(lookup_limiter.clone(), config.clone()))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(with_db(conn.clone()))
+        .and(with_config(config.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::get_device_handler);
+
+    let batch_lookup_routes = warp::path!("api" / "devices:batchLookup")
+        .and(warp::post())
+        .and(with_optional_auth(config.clone(), conn.clone()))
+        .and(with_lookup_quota(lookup_limiter.clone(), config.clone()))
+        .and(warp::body::json::<BatchLookupRequest>())
+        .and(with_db(conn.clone()))
+        .and(with_config(config.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::batch_lookup_handler);
+
+    let privacy_routes = warp::path!("api" / "devices" / "privacy")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .then(handler::privacy_handler);
+
+    let refresh_routes = warp::path!("api" / "token" / "refresh")
+        .and(warp::post())
+        .and(warp::body::json()) // Parse the request body as JSON
+        .and(with_db(conn.clone()))
+        .and(with_config(config.clone()))
+        .then(handler::refresh_handler);
+
+    let challenge_routes = warp::path!("api" / "devices" / String / "challenge")
+        .and(warp::get())
+        .and(with_client_ip(config.clone()))
+        .and(with_challenge_limiter(challenge_limiter)) // Anyone can ask, so per IP
+        .and(with_db(conn.clone()))
+        .and(with_config(config.clone())) // For the challenge lifetime
+        .then(handler::challenge_handler);
+
+    let logout_routes = warp::path!("api" / "logout")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(with_db(conn.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::logout_handler);
+
+    let logout_all_routes = warp::path!("api" / "logout" / "all")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(with_db(conn.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::logout_all_handler);
+
+    let claim_device_routes = warp::path!("api" / "devices" / "claim")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::claim_device_handler);
+
+    let transfer_offer_routes = warp::path!("api" / "devices" / "transfers")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .and(with_config(config.clone()))
+        .then(handler::transfer_offer_handler);
+
+    let transfer_accept_routes = warp::path!("api" / "devices" / "transfers" / "accept")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::transfer_accept_handler);
+
+    let transfer_cancel_routes = warp::path!("api" / "devices" / "transfers" / "cancel")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .then(handler::transfer_cancel_handler);
+
+    let reset_token_routes = warp::path!("api" / "devices" / "reset-token")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .then(handler::reset_token_handler);
+
+    let release_device_routes = warp::path!("api" / "devices" / "release")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::release_device_handler);
+
+    let rotate_credentials_routes = warp::path!("api" / "devices" / "credentials")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::rotate_credentials_handler);
+
+    let revoke_credentials_routes = warp::path!("api" / "devices" / "credentials" / "revoke")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::revoke_credentials_handler);
+
+    // Called by the devices themselves
+    let device_self_routes = warp::path!("api" / "devices" / "self")
+        .and(warp::get())
+        .and(with_device_auth(conn.clone())) // Requires an `Authorization: Device ...` credential
+        .and(with_db(conn.clone()))
+        .then(handler::device_self_handler);
+
+    let device_rotate_credentials_routes = warp::path!("api" / "devices" / "self" / "credentials" / "rotate")
+        .and(warp::post())
+        .and(with_device_auth(conn.clone()))
+        .and(with_db(conn.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::device_rotate_credentials_handler);
+
+    let send_wink_routes = warp::path!("api" / "winks")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .and(with_config(config.clone())) // For the rate limit
+        .and(with_hub(hub.clone()))
+        .then(handler::send_wink_handler);
+
+    let inbox_routes = warp::path!("api" / "winks")
+        .and(warp::get())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::query::<InboxQuery>())
+        .and(with_db(conn.clone()))
+        .then(handler::inbox_handler);
+
+    let ack_winks_routes = warp::path!("api" / "winks" / "ack")
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .then(handler::ack_winks_handler);
+
+    let dead_letters_routes = warp::path!("api" / "winks" / "dead-letters")
+        .and(warp::get())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::query::<InboxQuery>())
+        .and(with_db(conn.clone()))
+        .then(handler::dead_letters_handler);
+
+    let device_send_wink_routes = warp::path!("api" / "devices" / "self" / "winks")
+        .and(warp::post())
+        .and(with_device_auth(conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .and(with_config(config.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::device_send_wink_handler);
+
+    let device_inbox_routes = warp::path!("api" / "devices" / "self" / "winks")
+        .and(warp::get())
+        .and(with_device_auth(conn.clone()))
+        .and(warp::query::<InboxQuery>())
+        .and(with_db(conn.clone()))
+        .then(handler::device_inbox_handler);
+
+    let device_ack_winks_routes = warp::path!("api" / "devices" / "self" / "winks" / "ack")
+        .and(warp::post())
+        .and(with_device_auth(conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .then(handler::device_ack_winks_handler);
+
+    let device_dead_letters_routes = warp::path!("api" / "devices" / "self" / "winks" / "dead-letters")
+        .and(warp::get())
+        .and(with_device_auth(conn.clone()))
+        .and(warp::query::<InboxQuery>())
+        .and(with_db(conn.clone()))
+        .then(handler::device_dead_letters_handler);
+
+    let contact_action_routes = warp::path!("api" / "contacts" / String)
+        .and(warp::post())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::body::json())
+        .and(with_db(conn.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::contact_action_handler);
+
+    let contacts_routes = warp::path!("api" / "contacts")
+        .and(warp::get())
+        .and(with_auth(config.clone(), conn.clone()))
+        .and(warp::query::<DeviceRequest>())
+        .and(with_db(conn.clone()))
+        .then(handler::contacts_handler);
+
+    let device_contacts_routes = warp::path!("api" / "devices" / "self" / "contacts")
+        .and(warp::get())
+        .and(with_device_auth(conn.clone()))
+        .and(with_db(conn.clone()))
+        .then(handler::device_contacts_handler);
+
+    let heartbeat_routes = warp::path!("api" / "devices" / "self" / "heartbeat")
+        .and(warp::post())
+        .and(with_device_auth(conn.clone()))
+        .and(warp::body::json::<HeartbeatRequest>())
+        .and(with_db(conn.clone()))
+        .and(with_config(config.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::heartbeat_handler);
+
+    let realtime_routes = warp::path!("api" / "ws")
+        .and(warp::ws())
+        .and(with_realtime_auth(config.clone(), conn.clone())) // Device credential or Bearer token
+        .and(with_db(conn.clone()))
+        .and(with_config(config.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::realtime_handler);
+
+    let events_routes = warp::path!("api" / "events")
+        .and(warp::get())
+        .and(with_stream_auth(config.clone(), conn.clone()))
+        .and(warp::header::optional::<String>("last-event-id")) // Sent by EventSource when it reconnects
+        .and(with_db(conn.clone()))
+        .and(with_hub(hub.clone()))
+        .then(handler::events_handler);
+
+    let me_routes = warp::path!("api" / "me")
+        .and(warp::get())
+        .and(with_auth(config.clone(), conn.clone())) // Requires a valid token from /api/login
+        .and(with_db(conn.clone()))
+        .then(handler::me_handler);
 
     // Serve static files
     let static_files = warp::path("static")
-        .and(warp::fs::dir("./src/static"));
+        .and(warp::fs::dir(config.server.static_dir.clone()));
 
     // Serve index.html at the root
     let index = warp::path::end()
-        .and(warp::fs::file("./src/static/index.html"));
+        .and(warp::fs::file(config.server.static_dir.join("index.html")));
 
     // Configure CORS
     let cors = warp::cors()
         .allow_any_origin() // Allow any origin for development
         .allow_methods(&[Method::GET, Method::POST, Method::OPTIONS])
-        .allow_headers(vec!["content-type"])
+        .allow_headers(vec!["content-type", "authorization"])
         .allow_credentials(true);
 
     // Combine all routes
@@ -62,31 +455,197 @@ async fn main() -> anyhow::Result<()> {
         .or(health_checker)
         .or(device_lookup_routes)
         .or(login_routes)
+        .or(refresh_routes)
+        .or(logout_routes)
+        .or(logout_all_routes)
+        .or(me_routes)
+        .or(claim_device_routes)
+        .or(challenge_routes)
+        .or(transfer_offer_routes)
+        .or(transfer_accept_routes)
+        .or(transfer_cancel_routes)
+        .or(reset_token_routes)
+        .or(release_device_routes)
+        .or(privacy_routes)
+        .or(rotate_credentials_routes)
+        .or(revoke_credentials_routes)
+        .or(device_self_routes)
+        .or(get_device_routes)
+        .or(batch_lookup_routes)
+        .or(device_rotate_credentials_routes)
+        .or(send_wink_routes)
+        .or(inbox_routes)
+        .or(ack_winks_routes)
+        .or(dead_letters_routes)
+        .or(device_send_wink_routes)
+        .or(device_inbox_routes)
+        .or(device_ack_winks_routes)
+        .or(device_dead_letters_routes)
+        .or(contact_action_routes)
+        .or(contacts_routes)
+        .or(device_contacts_routes)
+        .or(heartbeat_routes)
+        .or(realtime_routes)
+        .or(events_routes)
         .or(static_files) // Serve static files
         .or(index)        // Serve index.html at root
+        .recover(error::handle_rejection)
         .with(cors)
         .with(warp::log("api"));
 
     // Print available endpoints
-    println!("🚀 Server started successfully at http://127.0.0.1:3030");
+    let base_url = format!("http://{}", config.server.socket_addr());
+    println!("🚀 Server started successfully at {}", base_url);
     println!("\nAvailable API Endpoints:");
     println!("-------------------------");
-    println!("• GET  http://127.0.0.1:3030/api/healthchecker");
-    println!("• POST http://127.0.0.1:3030/api/register");
-    println!("• POST http://127.0.0.1:3030/api/login");
-    println!("• POST http://127.0.0.1:3030/api/device");
-    println!("• GET  http://127.0.0.1:3030/ (serves index.html)");
-    println!("• GET  http://127.0.0.1:3030/static/* (serves static files)");
-    println!("\nFrontend available at: http://127.0.0.1:3030");
+    println!("• GET  {}/api/healthchecker", base_url);
+    println!("• POST {}/api/register", base_url);
+    println!("• POST {}/api/login", base_url);
+    println!("• POST {}/api/token/refresh", base_url);
+    println!("• POST {}/api/logout (requires Bearer token)", base_url);
+    println!("• POST {}/api/logout/all (requires Bearer token)", base_url);
+    println!("• POST {}/api/device (deprecated, use GET /api/devices/{{serial}})", base_url);
+    println!("• GET  {}/api/me (requires Bearer token)", base_url);
+    println!("• GET  {}/api/devices/{{serial}}", base_url);
+    println!("• POST {}/api/devices:batchLookup", base_url);
+    println!("• GET  {}/api/devices/{{serial}}/challenge", base_url);
+    println!("• POST {}/api/devices/claim (requires Bearer token)", base_url);
+    println!("• POST {}/api/devices/transfers (requires Bearer token)", base_url);
+    println!("• POST {}/api/devices/transfers/accept (requires Bearer token)", base_url);
+    println!("• POST {}/api/devices/transfers/cancel (requires Bearer token)", base_url);
+    println!("• POST {}/api/devices/reset-token (requires Bearer token)", base_url);
+    println!("• POST {}/api/devices/release (requires Bearer token)", base_url);
+    println!("• POST {}/api/devices/privacy (requires Bearer token)", base_url);
+    println!("• POST {}/api/devices/credentials (requires Bearer token)", base_url);
+    println!("• POST {}/api/devices/credentials/revoke (requires Bearer token)", base_url);
+    println!("• GET  {}/api/devices/self (requires Device credential)", base_url);
+    println!("• POST {}/api/devices/self/credentials/rotate (requires Device credential)", base_url);
+    println!("• POST {}/api/winks (requires Bearer token)", base_url);
+    println!("• GET  {}/api/winks?serial_number=... (requires Bearer token)", base_url);
+    println!("• POST {}/api/winks/ack (requires Bearer token)", base_url);
+    println!("• GET  {}/api/winks/dead-letters?serial_number=... (requires Bearer token)", base_url);
+    println!("• POST {}/api/devices/self/winks (requires Device credential)", base_url);
+    println!("• GET  {}/api/devices/self/winks (requires Device credential)", base_url);
+    println!("• POST {}/api/devices/self/winks/ack (requires Device credential)", base_url);
+    println!("• GET  {}/api/devices/self/winks/dead-letters (requires Device credential)", base_url);
+    println!("• POST {}/api/contacts/{{request,accept,decline,remove,block,unblock}} (requires Bearer token)", base_url);
+    println!("• GET  {}/api/contacts?serial_number=... (requires Bearer token)", base_url);
+    println!("• GET  {}/api/devices/self/contacts (requires Device credential)", base_url);
+    println!("• POST {}/api/devices/self/heartbeat (requires Device credential)", base_url);
+    println!("• GET  {}/api/ws (WebSocket, requires Device credential or Bearer token)", base_url);
+    println!("• GET  {}/api/events (Server-Sent Events, requires Bearer token)", base_url);
+    println!("• GET  {}/ (serves index.html)", base_url);
+    println!("• GET  {}/static/* (serves static files)", base_url);
+    println!("\nFrontend available at: {}", base_url);
 
     // Start the Warp server
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    warp::serve(routes).run(config.server.socket_addr()).await;
 
     Ok(())
 }
 
 fn with_db(
-    conn: Arc<Connection>,
-) -> impl Filter<Extract = (Arc<Connection>,), Error = std::convert::Infallible> + Clone {
+    conn: Arc<Db>,
+) -> impl Filter<Extract = (Arc<Db>,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || conn.clone()) // Pass a cloned Arc of the connection
+}
+
+fn with_config(
+    config: Arc<Config>,
+) -> impl Filter<Extract = (Arc<Config>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || config.clone())
+}
+
+/// Only lets the request through if it has a valid, unrevoked `Authorization: Bearer <token>` header
+fn with_auth(
+    config: Arc<Config>,
+    conn: Arc<Db>,
+) -> impl Filter<Extract = (AuthUser,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_config(config))
+        .and(with_db(conn))
+        .and_then(auth::authorize)
+}
+
+/// Like `with_auth`, but also lets anonymous requests through with `None`
+fn with_optional_auth(
+    config: Arc<Config>,
+    conn: Arc<Db>,
+) -> impl Filter<Extract = (Option<AuthUser>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_config(config))
+        .and(with_db(conn))
+        .and_then(auth::authorize_optional)
+}
+
+/// The anonymous lookup allowance of whoever's calling, see `lookup.rs`
+fn with_lookup_quota(
+    limiter: Arc<RateLimiter<IpAddr>>,
+    config: Arc<Config>,
+) -> impl Filter<Extract = (LookupQuota,), Error = Rejection> + Clone {
+    with_client_ip(config).map(move |client_ip| LookupQuota { limiter: limiter.clone(), client_ip })
+}
+
+fn with_challenge_limiter(
+    limiter: Arc<RateLimiter<IpAddr>>,
+) -> impl Filter<Extract = (Arc<RateLimiter<IpAddr>>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
+/// The caller's IP, from `X-Forwarded-For` if `server.trust_forwarded_for` is on
+fn with_client_ip(
+    config: Arc<Config>,
+) -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(move |remote, forwarded_for| ratelimit::client_ip(remote, forwarded_for, config.server.trust_forwarded_for))
+}
+
+/// Only matches path segments that look like a serial number
+async fn valid_serial(serial_number: String) -> Result<String, Rejection> {
+    match serial::validate(&serial_number) {
+        Ok(()) => Ok(serial_number),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+fn with_hub(
+    hub: Arc<Hub>,
+) -> impl Filter<Extract = (Arc<Hub>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || hub.clone())
+}
+
+/// For the WebSocket, lets devices and users in. Browsers can't set headers there so a user's
+/// token can come in `?access_token=` instead.
+fn with_realtime_auth(
+    config: Arc<Config>,
+    conn: Arc<Db>,
+) -> impl Filter<Extract = (Subscriber,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<RealtimeQuery>())
+        .and(with_config(config))
+        .and(with_db(conn))
+        .and_then(realtime::authorize)
+}
+
+/// Like `with_auth`, but the token can also come in `?access_token=` for browsers' EventSource
+fn with_stream_auth(
+    config: Arc<Config>,
+    conn: Arc<Db>,
+) -> impl Filter<Extract = (AuthUser,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<RealtimeQuery>())
+        .and(with_config(config))
+        .and(with_db(conn))
+        .and_then(realtime::authorize_user)
+}
+
+/// Only lets the request through if it has a valid `Authorization: Device <credential>` header,
+/// see `device_auth.rs`
+fn with_device_auth(
+    conn: Arc<Db>,
+) -> impl Filter<Extract = (AuthDevice,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_db(conn))
+        .and_then(device_auth::authorize_device)
 }
\ No newline at end of file
@@ -0,0 +1,328 @@
+//! Migrations module
+//!
+//! The schema lives in `src/migrations/*.sql` and gets baked into the binary. On startup
+//! [`run`] applies whatever hasn't been applied yet, in order, each one in its own transaction,
+//! and records it in `schema_migrations` along with a SHA-256 of its SQL.
+//!
+//! Adding a migration:
+//! 1. Write `src/migrations/NNNN_what_it_does.sql` with the next number
+//! 2. Add it to the bottom of [`MIGRATIONS`]
+//!
+//! NEVER edit a migration once it has shipped. The checksum won't match anymore and the server
+//! will refuse to start against any database that already ran the old version. Write a new
+//! migration instead.
+//!
+//! The server also refuses to start if the database has migrations this binary doesn't know
+//! about, i.e. someone ran a newer build against it. Running old code on a new schema is how
+//! data gets eaten.
+
+use chrono::Utc;
+use libsql::{params, Connection};
+use sha2::{Digest, Sha256};
+
+use crate::database::{Database, Db};
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        hex::encode(Sha256::digest(self.sql.as_bytes()))
+    }
+}
+
+/// Every migration, oldest first. Only ever add to the end of this.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "import_legacy_users",
+        sql: include_str!("migrations/0002_import_legacy_users.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "device_transfers",
+        sql: include_str!("migrations/0003_device_transfers.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "device_release",
+        sql: include_str!("migrations/0004_device_release.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "manufactured_devices",
+        sql: include_str!("migrations/0005_manufactured_devices.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "device_challenges",
+        sql: include_str!("migrations/0006_device_challenges.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "device_credentials",
+        sql: include_str!("migrations/0007_device_credentials.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "winks",
+        sql: include_str!("migrations/0008_winks.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "device_links",
+        sql: include_str!("migrations/0009_device_links.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "events",
+        sql: include_str!("migrations/0010_events.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "device_presence",
+        sql: include_str!("migrations/0011_device_presence.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "wink_queue",
+        sql: include_str!("migrations/0012_wink_queue.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "device_visibility",
+        sql: include_str!("migrations/0013_device_visibility.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "login_lockout",
+        sql: include_str!("migrations/0014_login_lockout.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "account_keys",
+        sql: include_str!("migrations/0015_account_keys.sql"),
+    },
+    Migration {
+        version: 16,
+        name: "login_attempt_reservations",
+        sql: include_str!("migrations/0016_login_attempt_reservations.sql"),
+    },
+    Migration {
+        version: 17,
+        name: "drop_revoked_tokens",
+        sql: include_str!("migrations/0017_drop_revoked_tokens.sql"),
+    },
+];
+
+/// A row out of `schema_migrations`
+struct AppliedMigration {
+    version: i64,
+    name: String,
+    checksum: String,
+}
+
+/// Brings the database up to date, see the module docs for what makes this bail
+pub async fn run(conn: &Db) -> anyhow::Result<()> {
+    check_ordering()?;
+
+    conn.execute("CREATE TABLE IF NOT EXISTS schema_migrations (
+                        version INTEGER PRIMARY KEY,
+                        name TEXT NOT NULL,
+                        checksum TEXT NOT NULL,
+                        applied_at TEXT NOT NULL
+                    )", ()).await?;
+
+    let applied = applied_migrations(conn).await?;
+    let latest_known = MIGRATIONS.last().map(|m| m.version).unwrap_or(0);
+
+    if let Some(newest) = applied.last() && newest.version > latest_known {
+        anyhow::bail!(
+            "Database schema is at version {} ({}) but this build only knows up to version {}, refusing to start",
+            newest.version, newest.name, latest_known
+        );
+    }
+
+    for done in &applied {
+        let Some(migration) = MIGRATIONS.iter().find(|m| m.version == done.version) else {
+            anyhow::bail!("Database has migration {} ({}) which this build doesn't have", done.version, done.name);
+        };
+        if migration.checksum() != done.checksum {
+            anyhow::bail!(
+                "Checksum mismatch for migration {} ({}), it was changed after being applied",
+                migration.version, migration.name
+            );
+        }
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| !applied.iter().any(|a| a.version == m.version)) {
+        apply(conn, migration).await?;
+    }
+
+    // Nags on every start until someone deals with them
+    let conflicts = legacy_import_conflicts(conn).await?;
+    if conflicts > 0 {
+        log::warn!("{} legacy user(s) couldn't be imported as accounts, see the legacy_import_conflicts table", conflicts);
+    }
+
+    Ok(())
+}
+
+async fn apply(conn: &Db, migration: &Migration) -> anyhow::Result<()> {
+    log::info!("Applying migration {} ({})", migration.version, migration.name);
+
+    let tx = Database::start_transaction(conn).await?;
+    let result = async {
+        tx.execute_batch(migration.sql).await?;
+        tx.execute("INSERT INTO schema_migrations (version, name, checksum, applied_at) VALUES (?, ?, ?, ?)",
+            params![migration.version, migration.name, migration.checksum(), Utc::now().to_rfc3339()]).await?;
+        anyhow::Ok(())
+    }.await;
+
+    if let Err(e) = result {
+        let _ = tx.rollback().await;
+        return Err(e.context(format!("Migration {} ({}) failed", migration.version, migration.name)));
+    }
+    Database::commit_transaction(tx).await
+}
+
+async fn applied_migrations(conn: &Connection) -> anyhow::Result<Vec<AppliedMigration>> {
+    let mut rows = conn.query("SELECT version, name, checksum FROM schema_migrations ORDER BY version", ()).await?;
+
+    let mut applied = Vec::new();
+    while let Some(row) = rows.next().await? {
+        applied.push(AppliedMigration {
+            version: row.get(0)?,
+            name: row.get(1)?,
+            checksum: row.get(2)?,
+        });
+    }
+
+    Ok(applied)
+}
+
+/// Old `users` rows migration 2 had to set aside
+async fn legacy_import_conflicts(conn: &Connection) -> anyhow::Result<i64> {
+    let mut rows = conn.query("SELECT COUNT(*) FROM legacy_import_conflicts", ()).await?;
+    let row = rows.next().await?.ok_or_else(|| anyhow::anyhow!("COUNT returned no row"))?;
+
+    Ok(row.get(0)?)
+}
+
+/// Catches someone adding a migration in the wrong place in [`MIGRATIONS`]
+fn check_ordering() -> anyhow::Result<()> {
+    for pair in MIGRATIONS.windows(2) {
+        if pair[1].version <= pair[0].version {
+            anyhow::bail!("Migrations are out of order: {} comes after {}", pair[1].version, pair[0].version);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libsql::Builder;
+
+    use super::*;
+    use crate::test_support::{test_db, test_db_at, TestPath};
+
+    /// The `users` table as builds from before migrations made it
+    const LEGACY_USERS: &str = "CREATE TABLE users (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        uuid TEXT UNIQUE NOT NULL,
+        serial_number TEXT UNIQUE NOT NULL,
+        device_name TEXT,
+        device_owner TEXT,
+        email TEXT UNIQUE NOT NULL,
+        password_hash TEXT,
+        created_at TEXT NOT NULL
+    )";
+
+    async fn strings(conn: &Connection, sql: &str) -> Vec<String> {
+        let mut rows = conn.query(sql, ()).await.unwrap();
+        let mut values = Vec::new();
+        while let Some(row) = rows.next().await.unwrap() {
+            values.push(row.get(0).unwrap());
+        }
+        values
+    }
+
+    #[test]
+    fn migrations_are_in_order() {
+        check_ordering().unwrap();
+    }
+
+    #[tokio::test]
+    async fn running_again_changes_nothing() {
+        let db = test_db().await;
+
+        run(&db).await.unwrap();
+        assert_eq!(applied_migrations(&db).await.unwrap().len(), MIGRATIONS.len());
+    }
+
+    #[tokio::test]
+    async fn edited_migrations_are_refused() {
+        let db = test_db().await;
+        db.execute("UPDATE schema_migrations SET checksum = 'edited' WHERE version = 3", ()).await.unwrap();
+
+        let error = run(&db).await.unwrap_err().to_string();
+        assert!(error.contains("Checksum mismatch for migration 3"), "{}", error);
+    }
+
+    /// A database a newer build has been at, this one doesn't know what it's looking at
+    #[tokio::test]
+    async fn newer_databases_are_refused() {
+        let db = test_db().await;
+        db.execute("INSERT INTO schema_migrations (version, name, checksum, applied_at) VALUES (9999, 'from_the_future', '', '')", ()).await.unwrap();
+
+        let error = run(&db).await.unwrap_err().to_string();
+        assert!(error.contains("refusing to start"), "{}", error);
+    }
+
+    /// Same, but with a gap where this build has nothing
+    #[tokio::test]
+    async fn unknown_migrations_are_refused() {
+        let db = test_db().await;
+        db.execute("UPDATE schema_migrations SET version = 0 WHERE version = 1", ()).await.unwrap();
+
+        let error = run(&db).await.unwrap_err().to_string();
+        assert!(error.contains("doesn't have"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn legacy_users_with_gaps_and_duplicates() {
+        let path = TestPath::temp();
+        {
+            let conn = Builder::new_local(&*path).build().await.unwrap().connect().unwrap();
+            conn.execute(LEGACY_USERS, ()).await.unwrap();
+            conn.execute_batch("
+                INSERT INTO users (uuid, serial_number, device_name, device_owner, email, password_hash, created_at) VALUES
+                    ('u1', 'S1', 'Kitchen', 'alice', 'alice@example.com', 'hash', '2023-01-01'),
+                    ('u2', 'S2', NULL, 'bob', 'bob@example.com', 'hash', '2023-01-02'),
+                    ('u3', 'S3', 'Hall', 'alice', 'alice2@example.com', 'hash', '2023-01-03'),
+                    ('u4', 'S4', 'Attic', NULL, 'nobody@example.com', 'hash', '2023-01-04'),
+                    ('u5', 'S5', 'Shed', 'carol', 'carol@example.com', NULL, '2023-01-05'),
+                    ('u6', 'S6', 'Porch', 'carol', 'carol2@example.com', 'hash', '2023-01-06');
+            ").await.unwrap();
+        }
+
+        let db = test_db_at(path).await;
+
+        assert_eq!(strings(&db, "SELECT uuid FROM accounts ORDER BY uuid").await, ["u1", "u2", "u6"]);
+        assert_eq!(strings(&db, "SELECT serial_number FROM devices ORDER BY serial_number").await, ["S1", "S2", "S6"]);
+        assert_eq!(
+            strings(&db, "SELECT uuid || ' ' || reason FROM legacy_import_conflicts ORDER BY id").await,
+            ["u3 duplicate_username", "u4 missing_username", "u5 missing_password"],
+        );
+        assert_eq!(legacy_import_conflicts(&db).await.unwrap(), 3);
+    }
+}
@@ -0,0 +1,45 @@
+-- Everything that existed before migrations did. IF NOT EXISTS so databases created by
+-- older builds (which made these tables on startup) pick up where they are.
+
+-- One account (a person) can own any number of devices
+CREATE TABLE IF NOT EXISTS accounts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uuid TEXT UNIQUE NOT NULL,
+    email TEXT UNIQUE NOT NULL,
+    username TEXT UNIQUE NOT NULL,
+    password_hash TEXT NOT NULL,
+    token_generation INTEGER NOT NULL DEFAULT 0,  -- bumped by logout everywhere
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS devices (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    serial_number TEXT UNIQUE NOT NULL,
+    account_uuid TEXT NOT NULL REFERENCES accounts (uuid) ON DELETE CASCADE,
+    device_name TEXT NOT NULL,
+    claimed_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_devices_account ON devices (account_uuid);
+
+-- Only the SHA-256 of a refresh token is stored, never the token itself.
+-- Every token that came from the same login shares a family_id.
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token_hash TEXT UNIQUE NOT NULL,
+    user_uuid TEXT NOT NULL,
+    family_id TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    used_at TEXT,     -- set once the token has been swapped for a new one
+    revoked_at TEXT
+);
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_family ON refresh_tokens (family_id);
+
+-- Access tokens that were logged out before they expired. expires_at is unix seconds
+-- (same as the token's exp) so pruning can be done in SQL.
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+    jti TEXT PRIMARY KEY,
+    user_uuid TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    revoked_at TEXT NOT NULL
+);
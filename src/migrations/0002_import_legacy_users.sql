@@ -0,0 +1,50 @@
+-- Databases from before accounts and devices were split have everything in one `users`
+-- table, one row per device. Copy those rows across and drop it. On anything newer the
+-- CREATE makes an empty table, nothing gets copied and it's dropped straight away.
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uuid TEXT UNIQUE NOT NULL,
+    serial_number TEXT UNIQUE NOT NULL,
+    device_name TEXT,
+    device_owner TEXT,
+    email TEXT UNIQUE NOT NULL,
+    password_hash TEXT,
+    created_at TEXT NOT NULL
+);
+
+-- Rows that can't become an account are kept here for someone to sort out by hand, rather than
+-- lost. Startup logs how many are waiting (see migrations.rs). Legacy usernames were never
+-- unique, so the oldest row gets a username and any later ones with it end up here too.
+CREATE TABLE legacy_import_conflicts (
+    id INTEGER PRIMARY KEY,                  -- the old users.id
+    uuid TEXT NOT NULL,
+    serial_number TEXT NOT NULL,
+    device_name TEXT,
+    device_owner TEXT,
+    email TEXT NOT NULL,
+    password_hash TEXT,
+    created_at TEXT NOT NULL,
+    reason TEXT NOT NULL                     -- 'missing_username', 'missing_password' or 'duplicate_username'
+);
+
+INSERT INTO legacy_import_conflicts (id, uuid, serial_number, device_name, device_owner, email, password_hash, created_at, reason)
+    SELECT id, uuid, serial_number, device_name, device_owner, email, password_hash, created_at,
+        CASE
+            WHEN device_owner IS NULL THEN 'missing_username'
+            WHEN password_hash IS NULL THEN 'missing_password'
+            ELSE 'duplicate_username'
+        END
+    FROM users
+    WHERE device_owner IS NULL OR password_hash IS NULL
+        OR EXISTS (SELECT 1 FROM users older
+                   WHERE older.device_owner = users.device_owner AND older.password_hash IS NOT NULL AND older.id < users.id);
+
+INSERT INTO accounts (uuid, email, username, password_hash, created_at)
+    SELECT uuid, email, device_owner, password_hash, created_at FROM users
+    WHERE id NOT IN (SELECT id FROM legacy_import_conflicts);
+
+INSERT INTO devices (serial_number, account_uuid, device_name, claimed_at)
+    SELECT serial_number, uuid, COALESCE(device_name, ''), created_at FROM users
+    WHERE uuid IN (SELECT uuid FROM accounts);
+
+DROP TABLE users;
@@ -0,0 +1,32 @@
+-- Two step hand over of a device between accounts. Only the SHA-256 of the one-time code is
+-- kept, the owner gets the code itself once and passes it on to the recipient.
+CREATE TABLE device_transfers (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uuid TEXT UNIQUE NOT NULL,
+    serial_number TEXT NOT NULL,
+    from_account_uuid TEXT NOT NULL,
+    to_account_uuid TEXT,            -- filled in when accepted
+    code_hash TEXT NOT NULL,
+    status TEXT NOT NULL,            -- pending, accepted, cancelled
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    completed_at TEXT
+);
+CREATE INDEX idx_device_transfers_serial ON device_transfers (serial_number, status);
+
+-- Who owned which device and when. No foreign keys on purpose, history should outlive both
+-- the device row and the account. released_at is NULL for the current owner.
+CREATE TABLE device_ownership_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    serial_number TEXT NOT NULL,
+    account_uuid TEXT NOT NULL,
+    acquired_at TEXT NOT NULL,
+    acquired_via TEXT NOT NULL,      -- claim, transfer
+    released_at TEXT,
+    released_via TEXT
+);
+CREATE INDEX idx_device_ownership_history_serial ON device_ownership_history (serial_number);
+
+-- Everyone who owns something right now gets their starting row
+INSERT INTO device_ownership_history (serial_number, account_uuid, acquired_at, acquired_via)
+    SELECT serial_number, account_uuid, claimed_at, 'claim' FROM devices;
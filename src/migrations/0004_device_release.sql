@@ -0,0 +1,3 @@
+-- Owners can provision a reset token onto a device. Once a device has one, releasing it
+-- requires the device to present it (i.e. it has actually been factory reset).
+ALTER TABLE devices ADD COLUMN reset_token_hash TEXT;
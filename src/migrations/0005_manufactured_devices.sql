@@ -0,0 +1,12 @@
+-- Every device that has actually been built, imported from the factory CSVs.
+-- device_secret is provisioned onto the device at manufacture and never leaves the server.
+CREATE TABLE manufactured_devices (
+    serial_number TEXT PRIMARY KEY,
+    hardware_revision TEXT NOT NULL,
+    batch TEXT NOT NULL,
+    device_secret TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'active',   -- active, revoked
+    imported_at TEXT NOT NULL,
+    revoked_at TEXT
+);
+CREATE INDEX idx_manufactured_devices_batch ON manufactured_devices (batch);
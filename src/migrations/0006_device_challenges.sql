@@ -0,0 +1,11 @@
+-- Nonces handed out by GET /api/devices/{serial}/challenge. The device signs one with its
+-- device_secret to prove it's really in the hands of whoever is registering it.
+-- Each nonce is good for exactly one attempt.
+CREATE TABLE device_challenges (
+    nonce TEXT PRIMARY KEY,
+    serial_number TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,             -- unix seconds, so pruning can compare in SQL
+    used_at TEXT
+);
+CREATE INDEX idx_device_challenges_expires_at ON device_challenges (expires_at);
@@ -0,0 +1,12 @@
+-- API credentials the devices themselves use, sent as `Authorization: Device <key_id>.<secret>`.
+-- Only the argon2 hash of the secret is kept. Revoked rows stay around for the audit trail.
+CREATE TABLE device_credentials (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    key_id TEXT NOT NULL UNIQUE,
+    serial_number TEXT NOT NULL,
+    secret_hash TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    last_used_at TEXT,
+    revoked_at TEXT
+);
+CREATE INDEX idx_device_credentials_serial_number ON device_credentials (serial_number);
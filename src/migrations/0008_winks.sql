@@ -0,0 +1,17 @@
+-- Winks sent from one device to another. from_serial_number is always the sending device,
+-- sent_by says whether the device itself or its owner (through the API) sent it.
+CREATE TABLE winks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uuid TEXT NOT NULL UNIQUE,
+    from_serial_number TEXT NOT NULL,
+    from_account_uuid TEXT NOT NULL,         -- owner of the sender at the time
+    sent_by TEXT NOT NULL,                   -- device, owner
+    to_serial_number TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    payload TEXT,
+    created_at INTEGER NOT NULL,             -- unix seconds, so the rate limit can compare in SQL
+    delivered_at TEXT,
+    read_at TEXT
+);
+CREATE INDEX idx_winks_to_serial_number ON winks (to_serial_number, id);
+CREATE INDEX idx_winks_from_serial_number ON winks (from_serial_number, created_at);
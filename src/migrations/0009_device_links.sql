@@ -0,0 +1,15 @@
+-- Contacts between devices. One row per pair, serial_a < serial_b, so there's never a second
+-- row for the same two devices going the other way.
+CREATE TABLE device_links (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    serial_a TEXT NOT NULL,
+    serial_b TEXT NOT NULL,
+    status TEXT NOT NULL,                    -- pending, accepted, declined, blocked
+    requested_by TEXT NOT NULL,              -- serial that sent the (latest) request
+    blocked_by TEXT,                         -- serial that blocked, only when status = 'blocked'
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    UNIQUE (serial_a, serial_b),
+    CHECK (serial_a < serial_b)
+);
+CREATE INDEX idx_device_links_serial_b ON device_links (serial_b);
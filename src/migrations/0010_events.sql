@@ -0,0 +1,11 @@
+-- Everything pushed to an account (see realtime.rs), kept for a while so SSE clients can pick up
+-- where they left off with Last-Event-ID. The id is the event id they send back.
+CREATE TABLE events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    account_uuid TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    data TEXT NOT NULL,                      -- the event as JSON, without its id
+    created_at INTEGER NOT NULL              -- unix seconds, for pruning
+);
+CREATE INDEX idx_events_account_uuid ON events (account_uuid, id);
+CREATE INDEX idx_events_created_at ON events (created_at);
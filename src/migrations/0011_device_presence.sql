@@ -0,0 +1,4 @@
+-- Presence, updated by device heartbeats and sockets (see presence.rs)
+ALTER TABLE devices ADD COLUMN last_seen_at INTEGER;     -- unix seconds, so "gone quiet" can be found in SQL
+ALTER TABLE devices ADD COLUMN firmware_version TEXT;    -- as last reported by the device
+CREATE INDEX idx_devices_last_seen_at ON devices (last_seen_at);
@@ -0,0 +1,11 @@
+-- Winks are a queue now: each one has until expires_at to be delivered (acknowledged by the
+-- device), after which it's a dead letter. See wink.rs.
+ALTER TABLE winks ADD COLUMN expires_at INTEGER NOT NULL DEFAULT 0;          -- unix seconds
+ALTER TABLE winks ADD COLUMN delivery_attempts INTEGER NOT NULL DEFAULT 0;   -- times it's been pushed to the device
+ALTER TABLE winks ADD COLUMN last_attempt_at INTEGER;                        -- unix seconds
+
+-- Winks from before there was a TTL get a week
+UPDATE winks SET expires_at = created_at + 604800;
+
+CREATE INDEX idx_winks_pending ON winks (to_serial_number, id) WHERE delivered_at IS NULL;
+CREATE INDEX idx_winks_dead_letters ON winks (from_serial_number, id) WHERE delivered_at IS NULL;
@@ -0,0 +1,3 @@
+-- Who can look a device up: public, contacts_only or hidden (see privacy.rs). Lookups used to
+-- show everyone everything, existing devices get the new default rather than staying public.
+ALTER TABLE devices ADD COLUMN visibility TEXT NOT NULL DEFAULT 'contacts_only';
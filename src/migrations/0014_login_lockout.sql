@@ -0,0 +1,24 @@
+-- Failed logins per email and per IP (see lockout.rs). `subject` is the email as typed but
+-- lowercased, whether or not there's an account with it, or the IP address.
+CREATE TABLE login_throttles (
+    kind TEXT NOT NULL,                      -- 'email' or 'ip'
+    subject TEXT NOT NULL,
+    failures INTEGER NOT NULL,               -- since the last success, or the last quiet spell
+    last_failure_at INTEGER NOT NULL,        -- unix seconds
+    locked_until INTEGER NOT NULL DEFAULT 0, -- unix seconds, 0 if it never was
+    PRIMARY KEY (kind, subject)
+);
+CREATE INDEX idx_login_throttles_last_failure_at ON login_throttles (last_failure_at);
+
+-- Every failed login, kept for `login.audit_retention_secs`
+CREATE TABLE login_failures (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    email TEXT NOT NULL,
+    account_uuid TEXT,                       -- NULL if nobody has that email
+    ip TEXT NOT NULL,
+    reason TEXT NOT NULL,                    -- 'invalid_credentials' or 'locked'
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX idx_login_failures_email ON login_failures (email, created_at);
+CREATE INDEX idx_login_failures_ip ON login_failures (ip, created_at);
+CREATE INDEX idx_login_failures_created_at ON login_failures (created_at);
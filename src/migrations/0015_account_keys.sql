@@ -0,0 +1,8 @@
+-- Normalised email and username for uniqueness and logins, see identity.rs. Filled in for
+-- existing accounts on startup, by Rust rather than here since sqlite can't do NFKC. Unique
+-- indexes let any number of NULLs through, which is what the backfill leaves behind when two
+-- old accounts collide.
+ALTER TABLE accounts ADD COLUMN email_key TEXT;
+ALTER TABLE accounts ADD COLUMN username_key TEXT;
+CREATE UNIQUE INDEX idx_accounts_email_key ON accounts (email_key);
+CREATE UNIQUE INDEX idx_accounts_username_key ON accounts (username_key);
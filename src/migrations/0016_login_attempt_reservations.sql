@@ -0,0 +1,3 @@
+-- Login attempts are counted before the password is checked (see lockout.rs). Once a lock runs
+-- out exactly one more attempt gets in, and this is the count it has to come in under.
+ALTER TABLE login_throttles ADD COLUMN allowed_failures INTEGER NOT NULL DEFAULT 0; -- 0 until the first lock
@@ -0,0 +1,4 @@
+-- Logging out denies the whole session now, by its refresh token family (see auth.rs), rather
+-- than the one access token it was done with. Every jti in here belongs to a family that was
+-- revoked at the same time, so the table has nothing left to say.
+DROP TABLE revoked_tokens;
@@ -1,16 +1,55 @@
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use validator::Validate;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+use crate::validation::{
+    self, DEVICE_NAME_MAX_LENGTH, EMAIL_MAX_LENGTH, PASSWORD_MAX_LENGTH, PASSWORD_MIN_LENGTH, USERNAME_MAX_LENGTH,
+    USERNAME_MIN_LENGTH,
+};
+
+/// Logins don't check the password policy, it may have been different when the password was set.
+/// Still capped so nobody can make us hash a megabyte.
+const LOGIN_PASSWORD_MAX_LENGTH: u64 = 1024;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Validate)]
+#[validate(schema(function = "validation::register_password"))]
 pub struct WLRegister {
     pub serial_number: String,
+    #[validate(
+        email(code = "invalid", message = "Email address is not valid"),
+        length(max = "EMAIL_MAX_LENGTH", code = "too_long", message = "Email address can be at most 254 characters")
+    )]
     pub email: String,
     pub account_created_at: Option<chrono::DateTime<Utc>>,
 
+    #[validate(
+        length(min = "USERNAME_MIN_LENGTH", max = "USERNAME_MAX_LENGTH", code = "out_of_range", message = "Username must be 3 to 32 characters"),
+        custom(function = "validation::username")
+    )]
     pub username: String,
+    #[validate(
+        length(min = "PASSWORD_MIN_LENGTH", max = "PASSWORD_MAX_LENGTH", code = "out_of_range", message = "Password must be 10 to 128 characters"),
+        custom(function = "validation::password")
+    )]
     pub password: String,
 
+    #[validate(
+        length(min = 1, max = "DEVICE_NAME_MAX_LENGTH", code = "out_of_range", message = "Device name must be 1 to 64 characters"),
+        custom(function = "validation::device_name")
+    )]
     pub device_name: String,
+
+    /// Shows the person registering actually has the device, see `possession.rs`
+    pub proof: PossessionProof,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PossessionProof {
+    /// The code on the card that comes in the box
+    ClaimCode { code: String },
+    /// A nonce from `GET /api/devices/{serial}/challenge`, signed by the device
+    Challenge { nonce: String, signature: String },
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -18,8 +57,153 @@ pub struct DeviceRequest {
     pub serial_number: String,
 }
 
+/// Who can look a device up, see `privacy.rs`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    Public,
+    ContactsOnly,
+    Hidden,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::ContactsOnly => "contacts_only",
+            Visibility::Hidden => "hidden",
+        }
+    }
+
+    /// Anything unexpected in the column is treated as the most private option
+    pub fn from_db(value: &str) -> Self {
+        match value {
+            "public" => Visibility::Public,
+            "contacts_only" => Visibility::ContactsOnly,
+            _ => Visibility::Hidden,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
+pub struct PrivacyRequest {
+    pub serial_number: String,
+    pub visibility: Visibility,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchLookupRequest {
+    pub serial_numbers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate)]
 pub struct LoginRequest {
+    #[validate(
+        custom(function = "validation::required"),
+        length(max = "EMAIL_MAX_LENGTH", code = "too_long", message = "Email address can be at most 254 characters")
+    )]
     pub email: String,
+    #[validate(
+        custom(function = "validation::required"),
+        length(max = "LOGIN_PASSWORD_MAX_LENGTH", code = "too_long", message = "Password can be at most 1024 characters")
+    )]
     pub password: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate)]
+pub struct ClaimDeviceRequest {
+    pub serial_number: String,
+    #[validate(
+        length(min = 1, max = "DEVICE_NAME_MAX_LENGTH", code = "out_of_range", message = "Device name must be 1 to 64 characters"),
+        custom(function = "validation::device_name")
+    )]
+    pub device_name: String,
+    pub proof: PossessionProof,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TransferOfferRequest {
+    pub serial_number: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate)]
+pub struct TransferAcceptRequest {
+    pub serial_number: String,
+    pub code: String,
+    /// Rename the device while taking it over, keeps the old name if left out
+    #[validate(
+        length(min = 1, max = "DEVICE_NAME_MAX_LENGTH", code = "out_of_range", message = "Device name must be 1 to 64 characters"),
+        custom(function = "validation::device_name")
+    )]
+    pub device_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReleaseDeviceRequest {
+    pub serial_number: String,
+    /// Only needed if a reset token was provisioned onto the device
+    pub reset_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SendWinkRequest {
+    /// Which of your devices it's from. Only for owners, a device always sends as itself.
+    pub from_serial_number: Option<String>,
+    pub to_serial_number: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub payload: Option<String>,
+    /// How long it has to be delivered, `winks.ttl_secs` if not given
+    pub ttl_secs: Option<u64>,
+}
+
+/// `?serial_number=...&before=...&limit=...`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct InboxQuery {
+    /// Whose inbox. Only for owners, a device always reads its own.
+    pub serial_number: Option<String>,
+    /// `next_cursor` from the previous page
+    pub before: Option<i64>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AckWinksRequest {
+    /// Only for owners, same as `InboxQuery::serial_number`
+    pub serial_number: Option<String>,
+    pub wink_ids: Vec<String>,
+    pub state: WinkState,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum WinkState {
+    Delivered,
+    Read,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ContactRequest {
+    /// Your device
+    pub serial_number: String,
+    /// The device on the other end
+    pub contact_serial_number: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RealtimeQuery {
+    /// For browsers, which can't set an `Authorization` header on a WebSocket
+    pub access_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HeartbeatRequest {
+    /// Only needs sending when it changes, the last one reported is kept
+    #[serde(default)]
+    pub firmware_version: Option<String>,
 }
\ No newline at end of file
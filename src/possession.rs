@@ -0,0 +1,234 @@
+//! Proof of possession module
+//!
+//! Knowing a serial number isn't enough to register or claim a device, it's printed on the box.
+//! The caller also has to prove they have the device in their hands, using the `device_secret`
+//! it was provisioned with at the factory (see `registry.rs`). There are two ways:
+//!
+//! - **Claim code**, for people. The card in the box has a code like `7KQ2M-XW9RT` printed on
+//!   it, which is derived from the secret with [`claim_code`]. Send it as
+//!   `{"type": "claim_code", "code": "7KQ2M-XW9RT"}`. Support can look one up with
+//!   `Winklink-Web-API claim-code <serial>`.
+//! - **Challenge**, for the app talking to the device. Get a nonce from
+//!   `GET /api/devices/{serial}/challenge` ([`issue_challenge`]), have the device sign
+//!   `<serial>:<nonce>` with HMAC-SHA256 keyed by its secret, and send
+//!   `{"type": "challenge", "nonce": "...", "signature": "<hex>"}`. A nonce is good for one
+//!   attempt, right or wrong.
+//!
+//! The secret itself never leaves the server. Neither does whether a serial number exists, has
+//! been revoked or is already claimed until the proof checks out: a challenge is handed out for
+//! any well formed serial, and an unknown one fails like a wrong proof.
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use libsql::Connection;
+use sha2::{Digest, Sha256};
+
+use crate::{config::DevicesConfig, database::{DeviceChallenges, ManufacturedDevices}, models::PossessionProof, transfer};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub struct Challenge {
+    pub nonce: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub enum ProofError {
+    InvalidClaimCode,
+    /// Unknown, expired, already used or for another device
+    InvalidChallenge,
+    InvalidSignature,
+    Internal(anyhow::Error),
+}
+
+impl ProofError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ProofError::InvalidClaimCode => "Claim code is incorrect",
+            ProofError::InvalidChallenge => "Challenge is invalid or has expired, request a new one",
+            ProofError::InvalidSignature => "Challenge signature is invalid",
+            ProofError::Internal(_) => "Internal error",
+        }
+    }
+}
+
+impl From<anyhow::Error> for ProofError {
+    fn from(e: anyhow::Error) -> Self {
+        ProofError::Internal(e)
+    }
+}
+
+/// Hands out a fresh nonce for the device to sign
+pub async fn issue_challenge(conn: &Connection, config: &DevicesConfig, serial_number: &str) -> anyhow::Result<Challenge> {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+
+    let nonce = hex::encode(bytes);
+    let expires_at = Utc::now() + Duration::seconds(config.challenge_lifetime_secs as i64);
+    DeviceChallenges::insert(conn, &nonce, serial_number, expires_at).await?;
+
+    Ok(Challenge { nonce, expires_at })
+}
+
+/// Checks the proof against the device's secret. Call this before writing anything, and before
+/// telling the caller anything about the device.
+pub async fn verify(conn: &Connection, serial_number: &str, proof: &PossessionProof) -> Result<(), ProofError> {
+    // A serial we never built is checked against a random secret, so it fails the same way as a
+    // wrong proof for a real one
+    let secret = match ManufacturedDevices::find(conn, serial_number).await? {
+        Some(device) => hex::decode(&device.device_secret)
+            .map_err(|e| anyhow::anyhow!("Device secret for {} is not hex: {}", serial_number, e))?,
+        None => {
+            let mut secret = vec![0u8; 32];
+            OsRng.fill_bytes(&mut secret);
+            secret
+        }
+    };
+
+    match proof {
+        PossessionProof::ClaimCode { code } => {
+            // Compare hashes rather than the codes themselves so the comparison time gives nothing away
+            let expected = Sha256::digest(transfer::normalise_code(&claim_code(&secret, serial_number)?));
+            if Sha256::digest(transfer::normalise_code(code)) != expected {
+                return Err(ProofError::InvalidClaimCode);
+            }
+        }
+        PossessionProof::Challenge { nonce, signature } => {
+            // Burn the nonce first, a wrong signature doesn't get another go with the same one
+            if !DeviceChallenges::consume(conn, nonce.trim(), serial_number).await? {
+                return Err(ProofError::InvalidChallenge);
+            }
+            let signature = hex::decode(signature.trim()).map_err(|_| ProofError::InvalidSignature)?;
+
+            let mut mac = new_mac(&secret)?;
+            mac.update(format!("{}:{}", serial_number, nonce.trim()).as_bytes());
+            mac.verify_slice(&signature).map_err(|_| ProofError::InvalidSignature)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The code printed on the card in the box, e.g. `7KQ2M-XW9RT`
+pub fn claim_code(secret: &[u8], serial_number: &str) -> anyhow::Result<String> {
+    let mut mac = new_mac(secret)?;
+    mac.update(format!("claim-code:{}", serial_number).as_bytes());
+
+    Ok(transfer::format_code(&mac.finalize().into_bytes()))
+}
+
+fn new_mac(secret: &[u8]) -> anyhow::Result<HmacSha256> {
+    HmacSha256::new_from_slice(secret).map_err(|e| anyhow::anyhow!("Bad device secret: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database::ManufacturedDevice, test_support::test_db};
+
+    const SERIAL: &str = "WNK000000019";
+    const OTHER_SERIAL: &str = "WNK000000027";
+    const SECRET: &str = "00112233445566778899aabbccddeeff";
+
+    async fn manufacture(conn: &Connection, serial_number: &str) {
+        ManufacturedDevices::insert(conn, &ManufacturedDevice {
+            serial_number: serial_number.to_string(),
+            hardware_revision: "rev-b".to_string(),
+            batch: "B0001".to_string(),
+            device_secret: SECRET.to_string(),
+            status: "active".to_string(),
+        }).await.unwrap();
+    }
+
+    /// What the device does with a nonce
+    fn sign(serial_number: &str, nonce: &str) -> String {
+        let mut mac = new_mac(&hex::decode(SECRET).unwrap()).unwrap();
+        mac.update(format!("{}:{}", serial_number, nonce).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn claim(code: &str) -> PossessionProof {
+        PossessionProof::ClaimCode { code: code.to_string() }
+    }
+
+    fn challenge(nonce: &str, signature: &str) -> PossessionProof {
+        PossessionProof::Challenge { nonce: nonce.to_string(), signature: signature.to_string() }
+    }
+
+    #[tokio::test]
+    async fn claim_code() {
+        let db = test_db().await;
+        manufacture(&db, SERIAL).await;
+        let code = super::claim_code(&hex::decode(SECRET).unwrap(), SERIAL).unwrap();
+
+        verify(&db, SERIAL, &claim(&code)).await.unwrap();
+        verify(&db, SERIAL, &claim(&code.to_lowercase().replace('-', " "))).await.unwrap();
+        assert!(matches!(verify(&db, SERIAL, &claim("00000-00000")).await, Err(ProofError::InvalidClaimCode)));
+        // The code is per serial, one card doesn't unlock another device with the same secret
+        manufacture(&db, OTHER_SERIAL).await;
+        assert!(matches!(verify(&db, OTHER_SERIAL, &claim(&code)).await, Err(ProofError::InvalidClaimCode)));
+    }
+
+    #[tokio::test]
+    async fn unknown_serial_fails_like_a_wrong_proof() {
+        let db = test_db().await;
+        let code = super::claim_code(&hex::decode(SECRET).unwrap(), SERIAL).unwrap();
+        let nonce = issue_challenge(&db, &DevicesConfig::default(), SERIAL).await.unwrap().nonce;
+
+        assert!(matches!(verify(&db, SERIAL, &claim(&code)).await, Err(ProofError::InvalidClaimCode)));
+        assert!(matches!(verify(&db, SERIAL, &challenge(&nonce, &sign(SERIAL, &nonce))).await, Err(ProofError::InvalidSignature)));
+    }
+
+    #[tokio::test]
+    async fn signed_challenge() {
+        let db = test_db().await;
+        manufacture(&db, SERIAL).await;
+        let nonce = issue_challenge(&db, &DevicesConfig::default(), SERIAL).await.unwrap().nonce;
+
+        verify(&db, SERIAL, &challenge(&nonce, &sign(SERIAL, &nonce))).await.unwrap();
+        // Once only
+        assert!(matches!(verify(&db, SERIAL, &challenge(&nonce, &sign(SERIAL, &nonce))).await, Err(ProofError::InvalidChallenge)));
+    }
+
+    #[tokio::test]
+    async fn wrong_signature_burns_the_nonce() {
+        let db = test_db().await;
+        manufacture(&db, SERIAL).await;
+        let nonce = issue_challenge(&db, &DevicesConfig::default(), SERIAL).await.unwrap().nonce;
+
+        assert!(matches!(verify(&db, SERIAL, &challenge(&nonce, &sign(OTHER_SERIAL, &nonce))).await, Err(ProofError::InvalidSignature)));
+        assert!(matches!(verify(&db, SERIAL, &challenge(&nonce, &sign(SERIAL, &nonce))).await, Err(ProofError::InvalidChallenge)));
+
+        let nonce = issue_challenge(&db, &DevicesConfig::default(), SERIAL).await.unwrap().nonce;
+        assert!(matches!(verify(&db, SERIAL, &challenge(&nonce, "not hex")).await, Err(ProofError::InvalidSignature)));
+    }
+
+    #[tokio::test]
+    async fn nonce_for_another_device() {
+        let db = test_db().await;
+        manufacture(&db, SERIAL).await;
+        manufacture(&db, OTHER_SERIAL).await;
+        let nonce = issue_challenge(&db, &DevicesConfig::default(), OTHER_SERIAL).await.unwrap().nonce;
+
+        assert!(matches!(verify(&db, SERIAL, &challenge(&nonce, &sign(SERIAL, &nonce))).await, Err(ProofError::InvalidChallenge)));
+    }
+
+    #[tokio::test]
+    async fn expired_nonce() {
+        let db = test_db().await;
+        manufacture(&db, SERIAL).await;
+        let config = DevicesConfig { challenge_lifetime_secs: 0, ..DevicesConfig::default() };
+        let nonce = issue_challenge(&db, &config, SERIAL).await.unwrap().nonce;
+
+        assert!(matches!(verify(&db, SERIAL, &challenge(&nonce, &sign(SERIAL, &nonce))).await, Err(ProofError::InvalidChallenge)));
+    }
+
+    #[tokio::test]
+    async fn made_up_nonce() {
+        let db = test_db().await;
+        manufacture(&db, SERIAL).await;
+
+        assert!(matches!(verify(&db, SERIAL, &challenge("00000000", &sign(SERIAL, "00000000"))).await, Err(ProofError::InvalidChallenge)));
+    }
+}
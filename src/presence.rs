@@ -0,0 +1,156 @@
+//! Presence module
+//!
+//! Whether a device is online. A device is online while it has a socket open on `/api/ws`, or
+//! if it's been seen in the last `devices.online_timeout_secs`. Seen means a heartbeat on
+//! `POST /api/devices/self/heartbeat` (which also reports its firmware version), a ping on its
+//! socket, or the socket opening or closing. Devices that can't keep a socket open just
+//! heartbeat a bit more often than the timeout.
+//!
+//! The owner gets `device_online` as soon as a device that was offline is seen, and
+//! `device_offline` from [`sweep`] once it's been quiet for the whole timeout. Going offline
+//! only after the timeout means a device that drops its socket and reconnects straight away
+//! doesn't flap.
+//!
+//! Who gets to see all this is up to `lookup.rs`: the owner sees everything, owners of the
+//! device's contacts see whether it's online and when it was last seen.
+
+use chrono::{DateTime, Utc};
+use libsql::Connection;
+
+use crate::{
+    database::{DevicePresence, Devices},
+    realtime::{self, Event, Hub},
+};
+
+const MAX_FIRMWARE_VERSION_LENGTH: usize = 64;
+
+#[derive(Debug)]
+pub enum PresenceError {
+    NoSuchDevice,
+    InvalidFirmwareVersion,
+    Internal(anyhow::Error),
+}
+
+impl PresenceError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            PresenceError::NoSuchDevice => "Device with this serial number not found",
+            PresenceError::InvalidFirmwareVersion => "Firmware version must be 1-64 printable characters",
+            PresenceError::Internal(_) => "Internal error",
+        }
+    }
+}
+
+impl From<anyhow::Error> for PresenceError {
+    fn from(e: anyhow::Error) -> Self {
+        PresenceError::Internal(e)
+    }
+}
+
+/// What a device's presence looks like to anyone allowed to see it
+pub struct Status {
+    pub online: bool,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub firmware_version: Option<String>,
+}
+
+impl Status {
+    pub fn label(&self) -> &'static str {
+        if self.online { "online" } else { "offline" }
+    }
+}
+
+pub fn status(hub: &Hub, online_timeout_secs: u64, serial_number: &str, presence: &DevicePresence) -> Status {
+    Status {
+        online: is_online(hub, online_timeout_secs, serial_number, presence.last_seen_at),
+        last_seen_at: presence.last_seen_at.and_then(|t| DateTime::from_timestamp(t, 0)),
+        firmware_version: presence.firmware_version.clone(),
+    }
+}
+
+fn is_online(hub: &Hub, online_timeout_secs: u64, serial_number: &str, last_seen_at: Option<i64>) -> bool {
+    let cutoff = Utc::now().timestamp() - online_timeout_secs as i64;
+    hub.is_connected(serial_number) || last_seen_at.is_some_and(|t| t > cutoff)
+}
+
+/// Records a heartbeat from `serial_number`, telling the owner if it's just come online
+pub async fn heartbeat(
+    conn: &Connection,
+    hub: &Hub,
+    online_timeout_secs: u64,
+    serial_number: &str,
+    firmware_version: Option<&str>,
+) -> Result<(), PresenceError> {
+    let firmware_version = firmware_version.map(str::trim);
+    if let Some(version) = firmware_version
+        && (version.is_empty() || version.len() > MAX_FIRMWARE_VERSION_LENGTH || version.chars().any(|c| c.is_control()))
+    {
+        return Err(PresenceError::InvalidFirmwareVersion);
+    }
+
+    seen(conn, hub, online_timeout_secs, serial_number, firmware_version).await
+}
+
+/// A device opened a socket, `realtime::serve` calls this once it's counted by the hub
+pub async fn socket_opened(conn: &Connection, hub: &Hub, online_timeout_secs: u64, serial_number: &str) {
+    if let Err(e) = seen(conn, hub, online_timeout_secs, serial_number, None).await {
+        log::warn!("Failed to record {} coming online: {:?}", serial_number, e);
+    }
+}
+
+/// A device's socket is still there (it answered a ping) or just went away. Either way the
+/// timeout runs from now, [`sweep`] says when it's offline.
+pub async fn touch(conn: &Connection, serial_number: &str) {
+    if let Err(e) = Devices::record_seen(conn, serial_number, None).await {
+        log::warn!("Failed to record {} as seen: {}", serial_number, e);
+    }
+}
+
+async fn seen(
+    conn: &Connection,
+    hub: &Hub,
+    online_timeout_secs: u64,
+    serial_number: &str,
+    firmware_version: Option<&str>,
+) -> Result<(), PresenceError> {
+    let Some(presence) = Devices::presence(conn, serial_number).await? else {
+        return Err(PresenceError::NoSuchDevice);
+    };
+    // A socket that's only just opened doesn't count yet, it's why we're here
+    let cutoff = Utc::now().timestamp() - online_timeout_secs as i64;
+    let was_online = hub.connections_of(serial_number) > 1 || presence.last_seen_at.is_some_and(|t| t > cutoff);
+
+    Devices::record_seen(conn, serial_number, firmware_version).await?;
+
+    if !was_online {
+        let event = Event::DeviceOnline { serial_number: serial_number.to_string() };
+        realtime::publish_to_account(hub, conn, &presence.account_uuid, &event).await;
+    }
+
+    Ok(())
+}
+
+/// Tells owners about devices that have gone quiet since the last sweep. `last_cutoff` is
+/// where the last one got to and is moved along, so each device is only reported once.
+pub async fn sweep(conn: &Connection, hub: &Hub, online_timeout_secs: u64, last_cutoff: &mut i64) -> anyhow::Result<usize> {
+    let cutoff = Utc::now().timestamp() - online_timeout_secs as i64;
+    if cutoff <= *last_cutoff {
+        return Ok(0);
+    }
+
+    let quiet = Devices::last_seen_between(conn, *last_cutoff, cutoff).await?;
+    *last_cutoff = cutoff;
+
+    let mut offline = 0;
+    for (serial_number, account_uuid) in quiet {
+        // Still has a socket open, it just hasn't been pinged in a while
+        if hub.is_connected(&serial_number) {
+            continue;
+        }
+        let event = Event::DeviceOffline { serial_number };
+        realtime::publish_to_account(hub, conn, &account_uuid, &event).await;
+        offline += 1;
+    }
+
+    Ok(offline)
+}
@@ -0,0 +1,160 @@
+//! Privacy module
+//!
+//! Who can look a device up (see `lookup.rs`). Each device has a [`Visibility`] its owner sets
+//! with `POST /api/devices/privacy`:
+//!
+//! - `public`: anyone, logged in or not, sees its name and owner's username
+//! - `contacts_only` (the default): its owner and the owners of its contacts
+//! - `hidden`: only its owner
+//!
+//! Anyone else gets the same 404 as for a serial number nobody has claimed, so lookups can't
+//! be used to find out which devices exist. Contact requests and winks still go by serial
+//! number, visibility only covers lookups. Anonymous lookups are also rate limited per IP
+//! (`devices.lookup_rate_limit`).
+
+use libsql::Connection;
+
+use crate::{database::Devices, models::Visibility};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Viewer {
+    Owner,
+    /// Owns one of the device's contacts
+    Contact,
+    Other,
+}
+
+#[derive(Debug)]
+pub enum PrivacyError {
+    /// Nobody has claimed it, or somebody else has. Same thing to the caller, see above.
+    NoSuchDevice,
+    Internal(anyhow::Error),
+}
+
+impl PrivacyError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            PrivacyError::NoSuchDevice => "Device with this serial number not found",
+            PrivacyError::Internal(_) => "Internal error",
+        }
+    }
+}
+
+impl From<anyhow::Error> for PrivacyError {
+    fn from(e: anyhow::Error) -> Self {
+        PrivacyError::Internal(e)
+    }
+}
+
+impl Viewer {
+    /// How `account_uuid` (if anyone's logged in) relates to a device owned by `owner_uuid`
+    pub fn of(owner_uuid: &str, account_uuid: Option<&str>, is_contact: bool) -> Self {
+        match account_uuid {
+            Some(account_uuid) if account_uuid == owner_uuid => Viewer::Owner,
+            Some(_) if is_contact => Viewer::Contact,
+            _ => Viewer::Other,
+        }
+    }
+}
+
+pub fn can_see(visibility: Visibility, viewer: Viewer) -> bool {
+    match visibility {
+        Visibility::Public => true,
+        Visibility::ContactsOnly => viewer != Viewer::Other,
+        Visibility::Hidden => viewer == Viewer::Owner,
+    }
+}
+
+pub async fn set_visibility(conn: &Connection, account_uuid: &str, serial_number: &str, visibility: Visibility) -> Result<(), PrivacyError> {
+    // Someone else's device is a 404 too, otherwise this would tell any logged in user which
+    // serial numbers exist, hidden ones included
+    if Devices::owner_of(conn, serial_number).await?.as_deref() != Some(account_uuid) {
+        return Err(PrivacyError::NoSuchDevice);
+    }
+
+    Devices::set_visibility(conn, serial_number, visibility.as_str()).await?;
+    log::debug!("Visibility of {} set to {}", serial_number, visibility.as_str());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::RealtimeConfig, database::DeviceLinks, lookup, realtime::Hub, test_support::{account, contacts, device, test_db}};
+
+    #[test]
+    fn who_can_see_what() {
+        for (visibility, owner, contact, other) in [
+            (Visibility::Public, true, true, true),
+            (Visibility::ContactsOnly, true, true, false),
+            (Visibility::Hidden, true, false, false),
+        ] {
+            assert_eq!(can_see(visibility, Viewer::Owner), owner, "{:?}", visibility);
+            assert_eq!(can_see(visibility, Viewer::Contact), contact, "{:?}", visibility);
+            assert_eq!(can_see(visibility, Viewer::Other), other, "{:?}", visibility);
+        }
+    }
+
+    #[test]
+    fn viewers() {
+        assert_eq!(Viewer::of("alice", Some("alice"), false), Viewer::Owner);
+        assert_eq!(Viewer::of("alice", Some("bob"), true), Viewer::Contact);
+        assert_eq!(Viewer::of("alice", Some("bob"), false), Viewer::Other);
+        // Being logged out trumps whatever the lookup said about contacts
+        assert_eq!(Viewer::of("alice", None, true), Viewer::Other);
+    }
+
+    #[test]
+    fn unexpected_visibility_is_hidden() {
+        assert_eq!(Visibility::from_db("public"), Visibility::Public);
+        assert_eq!(Visibility::from_db("contacts_only"), Visibility::ContactsOnly);
+        assert_eq!(Visibility::from_db("Public"), Visibility::Hidden);
+        assert_eq!(Visibility::from_db(""), Visibility::Hidden);
+    }
+
+    /// Alice has a device of each visibility, all of them contacts of Bob's. Carol only has a
+    /// pending request in with one of them.
+    #[tokio::test]
+    async fn lookups_only_find_what_the_caller_can_see() {
+        let db = test_db().await;
+        let hub = Hub::new(RealtimeConfig::default());
+        let (alice, bob, carol) = (account(&db, "alice").await, account(&db, "bob").await, account(&db, "carol").await);
+        let (bobs, carols) = (device(&db, &bob).await, device(&db, &carol).await);
+
+        let mut serial_numbers = Vec::new();
+        for visibility in [Visibility::Public, Visibility::ContactsOnly, Visibility::Hidden] {
+            let serial_number = device(&db, &alice).await;
+            set_visibility(&db, &alice, &serial_number, visibility).await.unwrap();
+            contacts(&db, &serial_number, &bobs).await;
+            serial_numbers.push(serial_number);
+        }
+        DeviceLinks::set(&db, &carols, &serial_numbers[1], "pending", &carols, None).await.unwrap();
+
+        for (account_uuid, visible) in [(Some(&alice), 3), (Some(&bob), 2), (Some(&carol), 1), (None, 1)] {
+            let found = lookup::lookup(&db, &hub, 60, account_uuid.map(String::as_str), &serial_numbers).await.unwrap();
+            let mut expected = serial_numbers[..visible].to_vec();
+            expected.sort();
+            assert_eq!(found.into_keys().collect::<Vec<_>>(), expected, "{:?}", account_uuid);
+        }
+
+        // Presence is for the owner and contacts only
+        for (account_uuid, shown) in [(Some(&alice), true), (Some(&bob), true), (Some(&carol), false), (None, false)] {
+            let found = lookup::lookup(&db, &hub, 60, account_uuid.map(String::as_str), &serial_numbers[..1]).await.unwrap();
+            assert_eq!(found[&serial_numbers[0]].status.is_some(), shown, "{:?}", account_uuid);
+        }
+    }
+
+    /// Someone else's hidden device looks the same as one that doesn't exist
+    #[tokio::test]
+    async fn other_peoples_devices_are_not_found() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let serial_number = device(&db, &alice).await;
+        set_visibility(&db, &alice, &serial_number, Visibility::Hidden).await.unwrap();
+
+        assert!(matches!(set_visibility(&db, &bob, &serial_number, Visibility::Public).await, Err(PrivacyError::NoSuchDevice)));
+        assert!(matches!(set_visibility(&db, &bob, "000000000000", Visibility::Public).await, Err(PrivacyError::NoSuchDevice)));
+        assert_eq!(Devices::find(&db, &serial_number).await.unwrap().unwrap().visibility, Visibility::Hidden);
+    }
+}
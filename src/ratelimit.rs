@@ -0,0 +1,114 @@
+//! Rate limit module
+//!
+//! An in-memory sliding window limiter for things that aren't worth a trip to the database,
+//! like anonymous device lookups or challenges per IP. It forgets everything on restart, which
+//! is fine for slowing down enumeration. Winks have their own limit in `wink.rs` that's kept in the
+//! database because it's per device and has to survive restarts.
+
+use std::{collections::{HashMap, VecDeque}, hash::Hash, net::{IpAddr, Ipv4Addr, SocketAddr}, sync::Mutex, time::{Duration, Instant}};
+
+pub struct RateLimiter<K> {
+    limit: usize,
+    window: Duration,
+    hits: Mutex<HashMap<K, VecDeque<Instant>>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit: limit as usize,
+            window,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts `cost` hits for `key`. If that would go over the limit none of them are counted
+    /// and you get back how many seconds until there's room again.
+    pub fn check(&self, key: K, cost: usize) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+        let times = hits.entry(key).or_default();
+        while times.front().is_some_and(|t| now.duration_since(*t) >= self.window) {
+            times.pop_front();
+        }
+
+        if times.len() + cost > self.limit {
+            // Room frees up as the oldest hits fall out of the window. If it's more than the
+            // whole limit there never will be, but a window is as good an answer as any.
+            let retry_after = times.get(times.len() + cost - self.limit - 1)
+                .filter(|_| cost <= self.limit)
+                .map(|t| self.window - now.duration_since(*t))
+                .unwrap_or(self.window);
+            return Err(retry_after.as_secs().max(1));
+        }
+        times.extend(std::iter::repeat_n(now, cost));
+
+        Ok(())
+    }
+
+    /// Forgets keys that haven't been seen for a whole window, call it now and then
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.hits.lock().unwrap().retain(|_, times| times.back().is_some_and(|t| now.duration_since(*t) < self.window));
+    }
+}
+
+/// Where a request came from. Behind a reverse proxy that's the last address the proxy added to
+/// `X-Forwarded-For` (`server.trust_forwarded_for`), anything before it is up to the client.
+pub fn client_ip(remote: Option<SocketAddr>, forwarded_for: Option<String>, trust_forwarded_for: bool) -> IpAddr {
+    if trust_forwarded_for
+        && let Some(ip) = forwarded_for.as_deref().and_then(|f| f.rsplit(',').next()).and_then(|ip| ip.trim().parse().ok())
+    {
+        return ip;
+    }
+
+    remote.map(|addr| addr.ip()).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_per_key() {
+        let limiter = RateLimiter::new(3, Duration::from_secs(60));
+
+        for _ in 0..3 {
+            limiter.check("a", 1).unwrap();
+        }
+        assert!(matches!(limiter.check("a", 1), Err(secs) if (1..=60).contains(&secs)));
+        limiter.check("b", 1).unwrap();
+    }
+
+    /// A batch that doesn't fit isn't counted at all
+    #[test]
+    fn costs_all_or_nothing() {
+        let limiter = RateLimiter::new(3, Duration::from_secs(60));
+
+        limiter.check("a", 2).unwrap();
+        assert!(limiter.check("a", 2).is_err());
+        limiter.check("a", 1).unwrap();
+        assert_eq!(limiter.check("a", 10), Err(60));
+    }
+
+    #[test]
+    fn window_slides() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(50));
+
+        limiter.check("a", 1).unwrap();
+        assert!(limiter.check("a", 1).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        limiter.check("a", 1).unwrap();
+    }
+
+    #[test]
+    fn forwarded_for_only_when_trusted() {
+        let remote = Some(SocketAddr::from(([10, 0, 0, 1], 4000)));
+        let forwarded_for = Some("1.2.3.4, 5.6.7.8".to_string());
+
+        assert_eq!(client_ip(remote, forwarded_for.clone(), false), IpAddr::from([10, 0, 0, 1]));
+        // The proxy appends, so only the last address is one we can believe
+        assert_eq!(client_ip(remote, forwarded_for, true), IpAddr::from([5, 6, 7, 8]));
+        assert_eq!(client_ip(remote, Some("nonsense".to_string()), true), IpAddr::from([10, 0, 0, 1]));
+    }
+}
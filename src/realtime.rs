@@ -0,0 +1,533 @@
+//! Realtime module
+//!
+//! Pushes things to clients as they happen so nobody has to poll. Devices and the web UI open a
+//! WebSocket on `GET /api/ws`:
+//!
+//! - A device sends its usual `Authorization: Device <credential>` header and gets events for
+//!   itself: winks and contact requests. Right after `ready` it also gets every wink it hasn't
+//!   acknowledged yet (see `wink.rs`).
+//! - The web UI sends `Authorization: Bearer <token>`, or `?access_token=<token>` because
+//!   browsers can't set headers on a WebSocket. It gets the same events for every device the
+//!   account owns, plus account events (devices claimed, released or transferred away, and
+//!   coming online or going offline).
+//!
+//! Every message is a JSON [`Event`] with a `type`, the first one on a socket is always `ready`.
+//!
+//! Clients that can't do WebSockets can use `GET /api/events` instead, a Server-Sent Events
+//! stream of the same account events ([`event_stream`]), logged in the same two ways. Account
+//! events are written to the `events` table before they go out and carry its `id`, so an SSE
+//! client that reconnects with `Last-Event-ID` gets whatever it missed, as long as it's within
+//! `realtime.event_retention_secs`. Delivery is at least once, the odd event can come twice.
+//!
+//! A device counts as online while it has a socket open, see `presence.rs` for the rest.
+//!
+//! Each connection gets its own bounded queue in the [`Hub`]. Publishing never waits on a
+//! client: if a connection's queue is full it's closed with 1013 and the client is expected to
+//! reconnect and catch up from the inbox, since winks are stored anyway. The server pings every
+//! `realtime.heartbeat_interval_secs` and drops connections it hasn't heard anything from in
+//! `realtime.heartbeat_timeout_secs`.
+//!
+//! Sockets don't outlive what let them in. A device's are closed when its credentials are
+//! rotated or revoked, or it's released or transferred. A user's are closed when their token
+//! expires or they log out. The same goes for SSE streams, which just end.
+
+use std::{collections::{HashMap, VecDeque}, convert::Infallible, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, OnceLock}, time::Duration};
+
+use futures_util::{stream::{self, SplitSink}, SinkExt, Stream, StreamExt};
+use libsql::Connection;
+use serde::Serialize;
+use tokio::{sync::mpsc::{self, error::TrySendError}, time::{self, Instant}};
+use warp::{ws::{Message, WebSocket}, Rejection};
+
+use crate::{auth::{self, AuthUser}, config::{Config, RealtimeConfig}, database::{Db, Devices, Events}, device_auth, models::RealtimeQuery, presence, response::WinkResponse, wink};
+
+/// What a connection is listening to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Topic {
+    Device(String),
+    Account(String),
+}
+
+/// Whoever opened the socket
+#[derive(Debug)]
+pub enum Subscriber {
+    Device { serial_number: String },
+    Account {
+        uuid: String,
+        session_id: String,
+        /// When the token they connected with expires (unix seconds)
+        expires_at: usize,
+    },
+}
+
+impl Subscriber {
+    fn topic(&self) -> Topic {
+        match self {
+            Subscriber::Device { serial_number } => Topic::Device(serial_number.clone()),
+            Subscriber::Account { uuid, .. } => Topic::Account(uuid.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Ready { heartbeat_interval_secs: u64 },
+    Wink { wink: WinkResponse },
+    /// `from_serial_number` wants to be a contact of `serial_number`
+    ContactRequest { serial_number: String, from_serial_number: String },
+    ContactAccepted { serial_number: String, contact_serial_number: String },
+    DeviceClaimed { serial_number: String },
+    DeviceReleased { serial_number: String },
+    /// The device now belongs to someone else
+    DeviceTransferred { serial_number: String },
+    DeviceOnline { serial_number: String },
+    DeviceOffline { serial_number: String },
+}
+
+/// An event ready to go out, serialised once however many clients get it
+struct Published {
+    /// From the `events` table, only account events have one
+    id: Option<i64>,
+    kind: String,
+    /// The event as JSON, with its `id` in it
+    text: String,
+}
+
+impl Published {
+    fn new(id: Option<i64>, mut data: serde_json::Value) -> Self {
+        let kind = data["type"].as_str().unwrap_or_default().to_string();
+        if let (Some(id), Some(object)) = (id, data.as_object_mut()) {
+            object.insert("id".to_string(), id.into());
+        }
+
+        Self { id, kind, text: data.to_string() }
+    }
+}
+
+/// Why the server hung up
+#[derive(Debug, Clone, Copy)]
+pub enum CloseReason {
+    /// The connection's queue filled up
+    TooSlow,
+    /// Too many connections for the same device/account, this was the oldest
+    Replaced,
+    /// Whatever it connected with isn't valid any more
+    Revoked,
+    TokenExpired,
+}
+
+impl CloseReason {
+    fn frame(self) -> (u16, &'static str) {
+        match self {
+            CloseReason::TooSlow => (1013, "Falling behind, reconnect and catch up from the inbox"),
+            CloseReason::Replaced => (1008, "Too many connections"),
+            CloseReason::Revoked => (1008, "Credentials revoked"),
+            CloseReason::TokenExpired => (1008, "Token expired"),
+        }
+    }
+}
+
+struct Client {
+    id: u64,
+    tx: mpsc::Sender<Arc<Published>>,
+    session_id: Option<String>,
+    close_reason: Arc<OnceLock<CloseReason>>,
+}
+
+impl Client {
+    /// Dropping the client drops its sender, which is what ends the connection
+    fn close(self, reason: CloseReason) {
+        let _ = self.close_reason.set(reason);
+    }
+}
+
+struct Subscription {
+    id: u64,
+    rx: mpsc::Receiver<Arc<Published>>,
+    close_reason: Arc<OnceLock<CloseReason>>,
+}
+
+/// Everyone connected right now, by topic
+pub struct Hub {
+    config: RealtimeConfig,
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<Topic, Vec<Client>>>,
+    /// How many sockets each device has open
+    online: Mutex<HashMap<String, usize>>,
+}
+
+impl Hub {
+    pub fn new(config: RealtimeConfig) -> Self {
+        Self {
+            config,
+            next_id: AtomicU64::new(1),
+            clients: Mutex::new(HashMap::new()),
+            online: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.config.heartbeat_interval_secs)
+    }
+
+    fn subscribe(&self, topic: Topic, session_id: Option<String>) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(self.config.queue_size);
+        let close_reason = Arc::new(OnceLock::new());
+
+        let mut clients = self.clients.lock().unwrap();
+        let list = clients.entry(topic).or_default();
+        // Oldest first, so the ones at the front are the ones to go
+        while list.len() >= self.config.max_connections_per_client {
+            list.remove(0).close(CloseReason::Replaced);
+        }
+        list.push(Client { id, tx, session_id, close_reason: close_reason.clone() });
+
+        Subscription { id, rx, close_reason }
+    }
+
+    fn unsubscribe(&self, topic: &Topic, id: u64) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(list) = clients.get_mut(topic) {
+            list.retain(|c| c.id != id);
+            if list.is_empty() {
+                clients.remove(topic);
+            }
+        }
+    }
+
+    /// Sends `event` to everyone on `topic` without logging it. Account events should go
+    /// through [`publish_to_account`] so SSE clients can catch up on them.
+    pub fn publish(&self, topic: &Topic, event: &Event) {
+        match serde_json::to_value(event) {
+            Ok(data) => self.deliver(topic, Arc::new(Published::new(None, data))),
+            Err(e) => log::error!("Failed to serialise realtime event: {}", e),
+        }
+    }
+
+    /// Hands `event` to everyone on `topic`, closing anyone who can't keep up
+    fn deliver(&self, topic: &Topic, event: Arc<Published>) {
+        let mut clients = self.clients.lock().unwrap();
+        let Some(list) = clients.get_mut(topic) else {
+            return;
+        };
+        let mut i = 0;
+        while i < list.len() {
+            match list[i].tx.try_send(event.clone()) {
+                Ok(()) => i += 1,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("Realtime client {} on {:?} is too slow, closing it", list[i].id, topic);
+                    list.remove(i).close(CloseReason::TooSlow);
+                }
+                // Connection already gone, it'll unsubscribe itself
+                Err(TrySendError::Closed(_)) => i += 1,
+            }
+        }
+    }
+
+    /// Closes every connection on `topic`
+    pub fn disconnect(&self, topic: &Topic, reason: CloseReason) {
+        if let Some(list) = self.clients.lock().unwrap().remove(topic) {
+            for client in list {
+                client.close(reason);
+            }
+        }
+    }
+
+    /// Closes the connections opened with tokens from one login
+    pub fn disconnect_session(&self, account_uuid: &str, session_id: &str, reason: CloseReason) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(list) = clients.get_mut(&Topic::Account(account_uuid.to_string())) {
+            for client in list.extract_if(.., |c| c.session_id.as_deref() == Some(session_id)) {
+                client.close(reason);
+            }
+        }
+    }
+
+    /// How many sockets the device has open
+    pub fn connections_of(&self, serial_number: &str) -> usize {
+        self.online.lock().unwrap().get(serial_number).copied().unwrap_or(0)
+    }
+
+    pub fn is_connected(&self, serial_number: &str) -> bool {
+        self.connections_of(serial_number) > 0
+    }
+
+    fn device_connected(&self, serial_number: &str) {
+        *self.online.lock().unwrap().entry(serial_number.to_string()).or_default() += 1;
+    }
+
+    fn device_disconnected(&self, serial_number: &str) {
+        let mut online = self.online.lock().unwrap();
+        if let Some(count) = online.get_mut(serial_number) {
+            *count -= 1;
+            if *count == 0 {
+                online.remove(serial_number);
+            }
+        }
+    }
+}
+
+/// Logs `event` for the account and sends it to everyone connected as them
+pub async fn publish_to_account(hub: &Hub, conn: &Connection, account_uuid: &str, event: &Event) {
+    let data = match serde_json::to_value(event) {
+        Ok(data) => data,
+        Err(e) => {
+            log::error!("Failed to serialise realtime event: {}", e);
+            return;
+        }
+    };
+
+    let kind = data["type"].as_str().unwrap_or_default();
+    // If it can't be logged it still goes out live, SSE clients just can't replay it
+    let id = match Events::insert(conn, account_uuid, kind, &data.to_string()).await {
+        Ok(id) => Some(id),
+        Err(e) => {
+            log::error!("Failed to log {} event for {}: {}", kind, account_uuid, e);
+            None
+        }
+    };
+
+    hub.deliver(&Topic::Account(account_uuid.to_string()), Arc::new(Published::new(id, data)));
+}
+
+/// Sends `event` to a device and to whoever owns it
+pub async fn notify_device(hub: &Hub, conn: &Connection, serial_number: &str, event: Event) {
+    hub.publish(&Topic::Device(serial_number.to_string()), &event);
+    match Devices::owner_of(conn, serial_number).await {
+        Ok(Some(owner)) => publish_to_account(hub, conn, &owner, &event).await,
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to look up owner of {} for a realtime event: {}", serial_number, e),
+    }
+}
+
+/// Filter body for the socket route. Takes a device credential or a user token, from the
+/// header or (user tokens only) the query string.
+pub async fn authorize(header: Option<String>, query: RealtimeQuery, config: Arc<Config>, conn: Arc<Db>) -> Result<Subscriber, Rejection> {
+    if let Some(header) = header.as_deref().filter(|h| h.starts_with("Device ")) {
+        let device = device_auth::authorize_device(Some(header.to_string()), conn).await?;
+        return Ok(Subscriber::Device { serial_number: device.serial_number });
+    }
+
+    let user = authorize_user(header, query, config, conn).await?;
+    Ok(Subscriber::Account {
+        uuid: user.uuid.to_string(),
+        session_id: user.session_id,
+        expires_at: user.expires_at,
+    })
+}
+
+/// Filter body for the SSE route, a user token from the header or the query string
+pub async fn authorize_user(header: Option<String>, query: RealtimeQuery, config: Arc<Config>, conn: Arc<Db>) -> Result<AuthUser, Rejection> {
+    let header = header.or_else(|| query.access_token.map(|token| format!("Bearer {}", token)));
+    auth::authorize(header, config, conn).await
+}
+
+/// Runs one connection until either side hangs up
+pub async fn serve(socket: WebSocket, hub: Arc<Hub>, conn: Arc<Db>, config: Arc<Config>, subscriber: Subscriber) {
+    let topic = subscriber.topic();
+    let session_id = match &subscriber {
+        Subscriber::Account { session_id, .. } => Some(session_id.clone()),
+        Subscriber::Device { .. } => None,
+    };
+    let Subscription { id, mut rx, close_reason } = hub.subscribe(topic.clone(), session_id);
+    log::debug!("Realtime client {} connected to {:?}", id, topic);
+
+    let device = match &subscriber {
+        Subscriber::Device { serial_number } => Some(serial_number.clone()),
+        Subscriber::Account { .. } => None,
+    };
+    let online_timeout_secs = config.devices.online_timeout_secs;
+    if let Some(serial_number) = &device {
+        hub.device_connected(serial_number);
+        presence::socket_opened(&conn, &hub, online_timeout_secs, serial_number).await;
+    }
+
+    let heartbeat_interval = Duration::from_secs(hub.config.heartbeat_interval_secs);
+    let heartbeat_timeout = Duration::from_secs(hub.config.heartbeat_timeout_secs);
+    // A client that stops reading shouldn't be able to wedge us on a write either
+    let write_timeout = heartbeat_timeout;
+
+    let token_expiry = match &subscriber {
+        Subscriber::Account { expires_at, .. } => {
+            let left = (*expires_at as i64 - chrono::Utc::now().timestamp()).max(0) as u64;
+            Instant::now() + Duration::from_secs(left)
+        }
+        // Far enough away to never happen
+        Subscriber::Device { .. } => Instant::now() + Duration::from_secs(10 * 365 * 24 * 60 * 60),
+    };
+    let token_expiry = time::sleep_until(token_expiry);
+    tokio::pin!(token_expiry);
+
+    let (mut sink, mut stream) = socket.split();
+    let mut heartbeat = time::interval_at(Instant::now() + heartbeat_interval, heartbeat_interval);
+    let mut last_seen = Instant::now();
+
+    let ready = Event::Ready { heartbeat_interval_secs: hub.config.heartbeat_interval_secs };
+    let ready = serde_json::to_string(&ready).unwrap_or_default();
+
+    let reason = if !send(&mut sink, Message::text(ready), write_timeout).await
+        || !redeliver(&mut sink, &conn, &topic, write_timeout).await
+    {
+        None
+    } else {
+        loop {
+            tokio::select! {
+                outgoing = rx.recv() => match outgoing {
+                    Some(event) => {
+                        if !send(&mut sink, Message::text(event.text.as_str()), write_timeout).await {
+                            break None;
+                        }
+                    }
+                    // The hub let go of us, it says why
+                    None => break Some(close_reason.get().copied().unwrap_or(CloseReason::TooSlow)),
+                },
+                incoming = stream.next() => match incoming {
+                    // Pongs, pings and anything else all count as the client being alive
+                    Some(Ok(message)) if !message.is_close() => last_seen = Instant::now(),
+                    _ => break None,
+                },
+                _ = heartbeat.tick() => {
+                    if last_seen.elapsed() > heartbeat_timeout {
+                        log::debug!("Realtime client {} missed its heartbeats", id);
+                        break None;
+                    }
+                    if !send(&mut sink, Message::ping(Vec::new()), write_timeout).await {
+                        break None;
+                    }
+                    if let Some(serial_number) = &device {
+                        presence::touch(&conn, serial_number).await;
+                    }
+                },
+                _ = &mut token_expiry => break Some(CloseReason::TokenExpired),
+            }
+        }
+    };
+
+    hub.unsubscribe(&topic, id);
+    if let Some(serial_number) = &device {
+        hub.device_disconnected(serial_number);
+        presence::touch(&conn, serial_number).await;
+    }
+    if let Some(reason) = reason {
+        let (code, text) = reason.frame();
+        send(&mut sink, Message::close_with(code, text), write_timeout).await;
+    }
+    let _ = time::timeout(write_timeout, sink.close()).await;
+    log::debug!("Realtime client {} on {:?} disconnected", id, topic);
+}
+
+/// Pushes a device the winks it hasn't acknowledged yet, right after `ready`. False if the
+/// socket went away.
+async fn redeliver(sink: &mut SplitSink<WebSocket, Message>, conn: &Db, topic: &Topic, timeout: Duration) -> bool {
+    let Topic::Device(serial_number) = topic else {
+        return true;
+    };
+    let winks = match wink::redeliver(conn, serial_number).await {
+        Ok(winks) => winks,
+        Err(e) => {
+            // They're still in the inbox, it's not worth dropping the connection over
+            log::error!("Failed to load pending winks for {}: {}", serial_number, e);
+            return true;
+        }
+    };
+
+    for w in &winks {
+        let text = serde_json::to_string(&Event::Wink { wink: wink::response(w) }).unwrap_or_default();
+        if !send(sink, Message::text(text), timeout).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// False if the client is gone or wouldn't take it in time
+async fn send(sink: &mut SplitSink<WebSocket, Message>, message: Message, timeout: Duration) -> bool {
+    matches!(time::timeout(timeout, sink.send(message)).await, Ok(Ok(())))
+}
+
+/// An SSE subscriber, unsubscribes when the stream is dropped
+struct EventFeed {
+    hub: Arc<Hub>,
+    topic: Topic,
+    id: u64,
+    rx: mpsc::Receiver<Arc<Published>>,
+    backlog: VecDeque<Arc<Published>>,
+    /// Live events up to this id were already in the backlog
+    replayed_up_to: i64,
+    /// The backlog hit the replay limit. The stream ends once it's sent and the client comes
+    /// back for the rest, rather than us leaving a gap before the live events.
+    truncated: bool,
+    expires_at: Instant,
+}
+
+impl Drop for EventFeed {
+    fn drop(&mut self) {
+        self.hub.unsubscribe(&self.topic, self.id);
+    }
+}
+
+/// The `GET /api/events` stream for `user`. With `last_event_id` it starts with whatever they
+/// missed since then. Ends when their token expires, they log out or they fall behind.
+pub async fn event_stream(
+    hub: Arc<Hub>,
+    conn: &Connection,
+    user: &AuthUser,
+    last_event_id: Option<i64>,
+) -> anyhow::Result<impl Stream<Item = Result<warp::sse::Event, Infallible>> + Send + 'static> {
+    let account_uuid = user.uuid.to_string();
+    let topic = Topic::Account(account_uuid.clone());
+    // Subscribe before reading the log so nothing falls between the two
+    let Subscription { id, rx, .. } = hub.subscribe(topic.clone(), Some(user.session_id.clone()));
+
+    let token_left = (user.expires_at as i64 - chrono::Utc::now().timestamp()).max(0) as u64;
+    let mut feed = EventFeed {
+        hub: hub.clone(),
+        topic,
+        id,
+        rx,
+        backlog: VecDeque::new(),
+        replayed_up_to: last_event_id.unwrap_or(0),
+        truncated: false,
+        expires_at: Instant::now() + Duration::from_secs(token_left),
+    };
+    if let Some(last_event_id) = last_event_id {
+        let missed = Events::since(conn, &account_uuid, last_event_id, hub.config.replay_limit).await?;
+        feed.truncated = missed.len() == hub.config.replay_limit as usize;
+        for event in missed {
+            let data = serde_json::from_str(&event.data).unwrap_or_else(|_| serde_json::json!({ "type": event.kind }));
+            feed.replayed_up_to = event.id;
+            feed.backlog.push_back(Arc::new(Published::new(Some(event.id), data)));
+        }
+    }
+    log::debug!("SSE client {} connected to {:?}, replaying {} event(s)", id, feed.topic, feed.backlog.len());
+
+    Ok(stream::unfold(feed, |mut feed| async move {
+        if let Some(event) = feed.backlog.pop_front() {
+            return Some((Ok(sse_event(&event)), feed));
+        }
+        if feed.truncated {
+            return None;
+        }
+
+        loop {
+            tokio::select! {
+                next = feed.rx.recv() => match next {
+                    Some(event) if event.id.is_some_and(|id| id <= feed.replayed_up_to) => continue,
+                    Some(event) => return Some((Ok(sse_event(&event)), feed)),
+                    None => return None,
+                },
+                _ = time::sleep_until(feed.expires_at) => return None,
+            }
+        }
+    }))
+}
+
+fn sse_event(event: &Published) -> warp::sse::Event {
+    let sse = warp::sse::Event::default().event(event.kind.as_str()).data(event.text.as_str());
+    match event.id {
+        Some(id) => sse.id(id.to_string()),
+        None => sse,
+    }
+}
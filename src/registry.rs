@@ -0,0 +1,152 @@
+//! Registry module
+//!
+//! The `manufactured_devices` table is the list of every WinkLink that was actually built.
+//! A serial number can only be registered or claimed if it's in here, hasn't been revoked and
+//! isn't already owned by someone, see [`check_claimable`].
+//!
+//! The factory sends a CSV per batch, import it with:
+//! ```sh
+//! Winklink-Web-API import-devices batch-0042.csv
+//! ```
+//!
+//! The CSV needs a header row with these columns (in any order):
+//! `serial_number,hardware_revision,batch,device_secret`
+//!
+//! Importing the same file twice is fine, serials already in the registry are skipped. If any
+//! row is bad nothing gets imported.
+//!
+//! To pull a device from circulation:
+//! ```sh
+//! Winklink-Web-API revoke-device 7KQ2MXW9RT0V
+//! ```
+
+use std::{collections::HashSet, fmt, path::Path};
+
+use anyhow::Context;
+use libsql::Connection;
+use serde::Deserialize;
+
+use crate::{database::{Database, Db, ManufacturedDevice, ManufacturedDevices, WLdbKeyword}, serial::{self, SerialError}};
+
+/// Per-device secrets are at least 128 bits, hex encoded
+const MIN_SECRET_LENGTH: usize = 32;
+
+#[derive(Debug, Deserialize)]
+struct FactoryRecord {
+    serial_number: String,
+    hardware_revision: String,
+    batch: String,
+    device_secret: String,
+}
+
+pub struct ImportSummary {
+    pub imported: u64,
+    /// Already in the registry
+    pub skipped: u64,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    InvalidFormat(SerialError),
+    /// Well formed, but we never built it
+    Unknown,
+    Revoked,
+    AlreadyClaimed,
+    Internal(anyhow::Error),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::InvalidFormat(e) => write!(f, "{}", e),
+            RegistryError::Unknown => write!(f, "Unknown serial number"),
+            RegistryError::Revoked => write!(f, "This device has been revoked and cannot be registered"),
+            RegistryError::AlreadyClaimed => write!(f, "Serial number already exists"),
+            RegistryError::Internal(_) => write!(f, "Internal error"),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RegistryError {
+    fn from(e: anyhow::Error) -> Self {
+        RegistryError::Internal(e)
+    }
+}
+
+/// Is this a well formed serial number? That's public (see `serial.rs`), so unlike
+/// [`check_claimable`] it's fine to answer before the caller has proven they have the device.
+pub fn check_format(serial_number: &str) -> Result<(), RegistryError> {
+    serial::validate(serial_number).map_err(RegistryError::InvalidFormat)
+}
+
+/// Can this serial number be registered or claimed right now? Whether it can't because it's
+/// unknown, revoked or claimed is only for someone who has proven they have the device, see
+/// `possession::verify`.
+pub async fn check_claimable(conn: &Connection, serial_number: &str) -> Result<(), RegistryError> {
+    check_format(serial_number)?;
+
+    match ManufacturedDevices::find(conn, serial_number).await? {
+        None => return Err(RegistryError::Unknown),
+        Some(device) if device.status != "active" => return Err(RegistryError::Revoked),
+        Some(_) => {}
+    }
+
+    if Database::keyword_exists(conn, WLdbKeyword::SerialNumber(serial_number.to_string())).await? {
+        return Err(RegistryError::AlreadyClaimed);
+    }
+
+    Ok(())
+}
+
+/// Loads a factory CSV into `manufactured_devices`, all or nothing
+pub async fn import_csv(conn: &Db, path: &Path) -> anyhow::Result<ImportSummary> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    // Check every row before touching the database
+    let mut devices = Vec::new();
+    let mut seen = HashSet::new();
+    for (i, record) in reader.deserialize::<FactoryRecord>().enumerate() {
+        // +2 because of the header row and humans counting from 1
+        let line = i + 2;
+        let record = record.with_context(|| format!("{}: line {} is malformed", path.display(), line))?;
+
+        serial::validate(&record.serial_number)
+            .with_context(|| format!("{}: line {} has a bad serial number {}", path.display(), line, record.serial_number))?;
+        if !seen.insert(record.serial_number.clone()) {
+            anyhow::bail!("{}: line {} repeats serial number {}", path.display(), line, record.serial_number);
+        }
+        if record.hardware_revision.is_empty() || record.batch.is_empty() {
+            anyhow::bail!("{}: line {} is missing its hardware revision or batch", path.display(), line);
+        }
+        if record.device_secret.len() < MIN_SECRET_LENGTH || !record.device_secret.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("{}: line {} needs a device secret of at least {} hex characters", path.display(), line, MIN_SECRET_LENGTH);
+        }
+
+        devices.push(ManufacturedDevice {
+            serial_number: record.serial_number,
+            hardware_revision: record.hardware_revision,
+            batch: record.batch,
+            device_secret: record.device_secret.to_ascii_lowercase(),
+            status: "active".to_string(),
+        });
+    }
+
+    let tx = Database::start_transaction(conn).await?;
+    let mut summary = ImportSummary { imported: 0, skipped: 0 };
+    for device in &devices {
+        match ManufacturedDevices::insert(&tx, device).await {
+            Ok(true) => summary.imported += 1,
+            Ok(false) => summary.skipped += 1,
+            Err(e) => {
+                let _ = tx.rollback().await;
+                return Err(e.context(format!("Failed to import {}", device.serial_number)));
+            }
+        }
+    }
+    Database::commit_transaction(tx).await?;
+
+    Ok(summary)
+}
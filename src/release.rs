@@ -0,0 +1,216 @@
+//! Release module
+//!
+//! Unbinding a device from its owner, e.g. when it's returned or factory reset, so the serial
+//! number can be registered again.
+//!
+//! By default the owner can just release it. If they'd rather not have a stolen login be enough,
+//! they can provision a reset token onto the device with [`issue_reset_token`]. From then on a
+//! release has to include that token, which the device only hands over once it has actually
+//! been reset.
+//!
+//! The release itself is recorded in `device_ownership_history` (`released_via` is `release`,
+//! or `factory_reset` when a reset token was presented), which outlives the device row. The
+//! device's API credentials are revoked and its contacts and inbox dropped along with it.
+
+use libsql::Connection;
+use sha2::{Digest, Sha256};
+
+use crate::{auth, database::{Database, Db, DeviceCredentials, DeviceLinks, Devices, OwnershipHistory, Transfers, Winks}};
+
+#[derive(Debug)]
+pub enum ReleaseError {
+    NoSuchDevice,
+    NotOwner,
+    /// The device has a reset token provisioned and it wasn't presented
+    ResetTokenRequired,
+    InvalidResetToken,
+    Internal(anyhow::Error),
+}
+
+impl ReleaseError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ReleaseError::NoSuchDevice => "Device with this serial number not found",
+            ReleaseError::NotOwner => "You do not own this device",
+            ReleaseError::ResetTokenRequired => "This device requires its reset token to be released",
+            ReleaseError::InvalidResetToken => "Reset token is invalid",
+            ReleaseError::Internal(_) => "Internal error",
+        }
+    }
+}
+
+impl From<anyhow::Error> for ReleaseError {
+    fn from(e: anyhow::Error) -> Self {
+        ReleaseError::Internal(e)
+    }
+}
+
+/// Makes a new reset token for the owner to put on the device, replacing any old one
+pub async fn issue_reset_token(conn: &Connection, owner_uuid: &str, serial_number: &str) -> Result<String, ReleaseError> {
+    check_owner(conn, owner_uuid, serial_number).await?;
+
+    // Same shape as a refresh token, it's just as sensitive
+    let reset_token = auth::generate_refresh_token();
+    Devices::set_reset_token_hash(conn, serial_number, &hash_reset_token(&reset_token)).await?;
+
+    Ok(reset_token)
+}
+
+/// Frees the serial number. `reset_token` is only needed if one was provisioned.
+pub async fn release_device(
+    conn: &Db,
+    owner_uuid: &str,
+    serial_number: &str,
+    reset_token: Option<&str>,
+) -> Result<(), ReleaseError> {
+    // Checked in the transaction, a transfer accepted in the meantime makes it someone else's
+    let tx = Database::start_transaction(conn).await?;
+    let result = async {
+        check_owner(&tx, owner_uuid, serial_number).await?;
+
+        let released_via = match (Devices::reset_token_hash(&tx, serial_number).await?, reset_token) {
+            (None, _) => "release",
+            (Some(_), None) => return Err(ReleaseError::ResetTokenRequired),
+            (Some(expected), Some(presented)) if expected == hash_reset_token(presented) => "factory_reset",
+            (Some(_), Some(_)) => return Err(ReleaseError::InvalidResetToken),
+        };
+
+        Transfers::cancel_pending(&tx, serial_number).await?;
+        OwnershipHistory::released(&tx, serial_number, released_via).await?;
+        DeviceCredentials::revoke_all_for_device(&tx, serial_number).await?;
+        DeviceLinks::remove_all_for_device(&tx, serial_number).await?;
+        Winks::remove_all_to_device(&tx, serial_number).await?;
+        if !Devices::release(&tx, serial_number, owner_uuid).await? {
+            return Err(ReleaseError::NotOwner);
+        }
+        Ok(released_via)
+    }.await;
+
+    match result {
+        Ok(released_via) => {
+            Database::commit_transaction(tx).await?;
+            log::info!("Device {} released by {} ({})", serial_number, owner_uuid, released_via);
+            Ok(())
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+async fn check_owner(conn: &Connection, account_uuid: &str, serial_number: &str) -> Result<(), ReleaseError> {
+    match Devices::owner_of(conn, serial_number).await? {
+        None => Err(ReleaseError::NoSuchDevice),
+        Some(owner) if owner != account_uuid => Err(ReleaseError::NotOwner),
+        Some(_) => Ok(()),
+    }
+}
+
+fn hash_reset_token(reset_token: &str) -> String {
+    hex::encode(Sha256::digest(reset_token.trim().as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::DevicesConfig, test_support::{account, device, test_db}, transfer::{self, TransferError}};
+
+    /// The old owner releasing while the new owner accepts a transfer: one of them wins, and the
+    /// device never disappears out of the new owner's account
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn release_racing_a_transfer() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+
+        for _ in 0..5 {
+            let serial_number = device(&db, &alice).await;
+            let offer = transfer::create_offer(&db, &DevicesConfig::default(), &alice, &serial_number).await.unwrap();
+
+            let accept = tokio::spawn({
+                let (db, bob, serial_number) = (db.clone(), bob.clone(), serial_number.clone());
+                async move { transfer::accept_offer(&db, &bob, &serial_number, &offer.code, None).await.map(drop) }
+            });
+            let released = release_device(&db, &alice, &serial_number, None).await;
+            let accepted = accept.await.unwrap();
+
+            let owner = Devices::owner_of(&db, &serial_number).await.unwrap();
+            match (released, accepted) {
+                (Ok(()), Err(TransferError::InvalidCode)) => assert_eq!(owner, None),
+                (Err(ReleaseError::NotOwner), Ok(())) => assert_eq!(owner.as_deref(), Some(bob.as_str())),
+                other => panic!("Release and transfer both went {:?}, device is with {:?}", other, owner),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cant_release_after_giving_it_away() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let serial_number = device(&db, &alice).await;
+
+        let offer = transfer::create_offer(&db, &DevicesConfig::default(), &alice, &serial_number).await.unwrap();
+        transfer::accept_offer(&db, &bob, &serial_number, &offer.code, None).await.unwrap();
+
+        assert!(matches!(release_device(&db, &alice, &serial_number, None).await, Err(ReleaseError::NotOwner)));
+        assert_eq!(Devices::owner_of(&db, &serial_number).await.unwrap(), Some(bob));
+    }
+
+    async fn released_via(db: &Db, serial_number: &str) -> String {
+        let mut rows = db.query("SELECT released_via FROM device_ownership_history WHERE serial_number = ?", [serial_number]).await.unwrap();
+        rows.next().await.unwrap().unwrap().get(0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn release_without_a_reset_token() {
+        let db = test_db().await;
+        let alice = account(&db, "alice").await;
+        let serial_number = device(&db, &alice).await;
+
+        release_device(&db, &alice, &serial_number, None).await.unwrap();
+
+        assert_eq!(Devices::owner_of(&db, &serial_number).await.unwrap(), None);
+        assert_eq!(released_via(&db, &serial_number).await, "release");
+    }
+
+    #[tokio::test]
+    async fn reset_token_is_required_once_issued() {
+        let db = test_db().await;
+        let alice = account(&db, "alice").await;
+        let serial_number = device(&db, &alice).await;
+        let reset_token = issue_reset_token(&db, &alice, &serial_number).await.unwrap();
+
+        assert!(matches!(release_device(&db, &alice, &serial_number, None).await, Err(ReleaseError::ResetTokenRequired)));
+        assert!(matches!(release_device(&db, &alice, &serial_number, Some("not-the-token")).await, Err(ReleaseError::InvalidResetToken)));
+        assert_eq!(Devices::owner_of(&db, &serial_number).await.unwrap().as_deref(), Some(alice.as_str()));
+
+        release_device(&db, &alice, &serial_number, Some(&reset_token)).await.unwrap();
+        assert_eq!(Devices::owner_of(&db, &serial_number).await.unwrap(), None);
+        assert_eq!(released_via(&db, &serial_number).await, "factory_reset");
+    }
+
+    #[tokio::test]
+    async fn new_reset_token_replaces_the_old_one() {
+        let db = test_db().await;
+        let alice = account(&db, "alice").await;
+        let serial_number = device(&db, &alice).await;
+
+        let old = issue_reset_token(&db, &alice, &serial_number).await.unwrap();
+        let new = issue_reset_token(&db, &alice, &serial_number).await.unwrap();
+
+        assert!(matches!(release_device(&db, &alice, &serial_number, Some(&old)).await, Err(ReleaseError::InvalidResetToken)));
+        release_device(&db, &alice, &serial_number, Some(&new)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_can_release() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let serial_number = device(&db, &alice).await;
+
+        assert!(matches!(issue_reset_token(&db, &bob, &serial_number).await, Err(ReleaseError::NotOwner)));
+        assert!(matches!(release_device(&db, &bob, &serial_number, None).await, Err(ReleaseError::NotOwner)));
+        assert!(matches!(release_device(&db, &bob, "WNK000000019", None).await, Err(ReleaseError::NoSuchDevice)));
+        assert_eq!(Devices::owner_of(&db, &serial_number).await.unwrap().as_deref(), Some(alice.as_str()));
+    }
+}
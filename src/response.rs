@@ -1,5 +1,9 @@
+use std::collections::BTreeMap;
+
 use serde::Serialize;
 
+use crate::models::Visibility;
+
 #[derive(Serialize)]
 pub struct GenericResponse {
     pub status: String,
@@ -10,6 +14,41 @@ pub struct GenericResponse {
 pub struct WLDeviceResponse {
     pub device_owner: String,
     pub device_name: String,
+    /// `online` or `offline`, only for the owner and owners of its contacts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen_at: Option<String>,
+    /// Only for the owner
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware_version: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchLookupResponse {
+    pub status: String,
+    /// By serial number. Ones that aren't claimed, or that you can't see, are left out.
+    pub devices: BTreeMap<String, WLDeviceResponse>,
+}
+
+/// Every error looks like this, see `error.rs`
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub status: String,
+    pub code: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<FieldError>,
+    /// Only for internal errors, what to quote to whoever runs the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -17,5 +56,122 @@ pub struct LoginResponse {
     pub status: String,
     pub message: String,
     pub token: String,
+    pub refresh_token: String,
+    /// Seconds until `token` expires
+    pub expires_in: u64,
     pub user_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountResponse {
+    pub user_id: String,
+    pub username: String,
+    pub email: String,
+    pub created_at: String,
+    pub devices: Vec<AccountDevice>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountDevice {
+    pub serial_number: String,
+    pub device_name: String,
+    pub claimed_at: String,
+    /// Who can look it up
+    pub visibility: Visibility,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TransferOfferResponse {
+    pub status: String,
+    pub message: String,
+    pub transfer_id: String,
+    /// Give this to the new owner, it isn't shown again
+    pub code: String,
+    pub expires_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResetTokenResponse {
+    pub status: String,
+    pub message: String,
+    /// Put this on the device, it isn't shown again
+    pub reset_token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChallengeResponse {
+    pub status: String,
+    pub serial_number: String,
+    /// The device signs `<serial_number>:<nonce>`
+    pub nonce: String,
+    pub expires_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceCredentialResponse {
+    pub status: String,
+    pub message: String,
+    pub serial_number: String,
+    /// Put this on the device, it isn't shown again
+    pub device_credential: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HeartbeatResponse {
+    pub status: String,
+    /// Heartbeat at least this often to stay online
+    pub next_heartbeat_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SendWinkResponse {
+    pub status: String,
+    pub message: String,
+    pub wink_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WinkResponse {
+    pub wink_id: String,
+    pub from_serial_number: String,
+    /// `device` or `owner`
+    pub sent_by: String,
+    pub to_serial_number: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub payload: Option<String>,
+    pub sent_at: String,
+    pub delivered_at: Option<String>,
+    pub read_at: Option<String>,
+    /// Undelivered by then and it's a dead letter
+    pub expires_at: String,
+    /// How many times it's been pushed to the device
+    pub delivery_attempts: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InboxResponse {
+    pub status: String,
+    /// Newest first
+    pub winks: Vec<WinkResponse>,
+    /// Pass as `before` to get the next page, `None` when there isn't one
+    pub next_cursor: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ContactsResponse {
+    pub status: String,
+    pub serial_number: String,
+    pub contacts: Vec<ContactResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ContactResponse {
+    pub serial_number: String,
+    /// contact, incoming, outgoing, declined or blocked
+    pub state: String,
+    /// Only for contacts and incoming requests
+    pub device_name: Option<String>,
+    pub device_owner: Option<String>,
+    pub updated_at: String,
 }
\ No newline at end of file
@@ -0,0 +1,135 @@
+//! Serial number module
+//!
+//! WinkLink serial numbers are 12 characters of Crockford base32 (`0-9`, `A-Z` minus `I`, `L`,
+//! `O` and `U`), uppercase. The first 11 are assigned at the factory and the 12th is a
+//! Luhn mod 32 check character over them, so a typo'd serial gets caught before it hits the
+//! database.
+//!
+//! ```rust
+//! serial::validate("7KQ2MXW9RT0V")?;
+//! ```
+//!
+//! This only says a serial *could* exist. Whether it actually does is up to the
+//! `manufactured_devices` table, see `registry.rs`.
+
+use std::fmt;
+
+pub const SERIAL_LENGTH: usize = 12;
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SerialError {
+    WrongLength,
+    InvalidCharacter(char),
+    BadCheckCharacter,
+}
+
+impl fmt::Display for SerialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialError::WrongLength => write!(f, "Serial number must be exactly {} characters long", SERIAL_LENGTH),
+            SerialError::InvalidCharacter(c) => write!(f, "Serial number contains an invalid character '{}'", c),
+            SerialError::BadCheckCharacter => write!(f, "Serial number is not valid, check for typos"),
+        }
+    }
+}
+
+impl std::error::Error for SerialError {}
+
+/// Checks length, alphabet and the check character
+pub fn validate(serial_number: &str) -> Result<(), SerialError> {
+    if serial_number.chars().count() != SERIAL_LENGTH {
+        return Err(SerialError::WrongLength);
+    }
+    if let Some(c) = serial_number.chars().find(|c| value_of(*c).is_none()) {
+        return Err(SerialError::InvalidCharacter(c));
+    }
+
+    // Everything is ASCII by now so splitting on a byte index is fine
+    let (payload, check) = serial_number.split_at(SERIAL_LENGTH - 1);
+    if check_character(payload) != check.chars().next() {
+        return Err(SerialError::BadCheckCharacter);
+    }
+
+    Ok(())
+}
+
+/// The check character for the first 11 characters of a serial, `None` if any of them aren't
+/// in the alphabet
+pub fn check_character(payload: &str) -> Option<char> {
+    let n = ALPHABET.len();
+    let mut sum = 0;
+
+    // Right to left, doubling every other value starting with the rightmost payload character
+    // (the check character would sit after it and isn't doubled)
+    for (i, c) in payload.chars().rev().enumerate() {
+        let value = value_of(c)?;
+        sum += if i % 2 == 0 {
+            let doubled = value * 2;
+            doubled / n + doubled % n
+        } else {
+            value
+        };
+    }
+
+    Some(ALPHABET[(n - sum % n) % n] as char)
+}
+
+fn value_of(c: char) -> Option<usize> {
+    ALPHABET.iter().position(|&a| a as char == c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERIAL: &str = "WNK000000019";
+
+    #[test]
+    fn valid_serials() {
+        for serial_number in [SERIAL, "WNK000000027", "WNK000000035", "7KQ2MXW9RT0V"] {
+            assert_eq!(validate(serial_number), Ok(()), "{}", serial_number);
+        }
+    }
+
+    #[test]
+    fn every_single_character_typo_is_caught() {
+        for i in 0..SERIAL_LENGTH {
+            for &replacement in ALPHABET.iter().filter(|&&c| c != SERIAL.as_bytes()[i]) {
+                let mut typo = SERIAL.as_bytes().to_vec();
+                typo[i] = replacement;
+                let typo = String::from_utf8(typo).unwrap();
+
+                assert_eq!(validate(&typo), Err(SerialError::BadCheckCharacter), "{}", typo);
+            }
+        }
+    }
+
+    #[test]
+    fn swapped_characters_are_caught() {
+        assert_eq!(validate("NWK000000019"), Err(SerialError::BadCheckCharacter));
+        assert_eq!(validate("WNK000000109"), Err(SerialError::BadCheckCharacter));
+    }
+
+    #[test]
+    fn wrong_length() {
+        assert_eq!(validate(""), Err(SerialError::WrongLength));
+        assert_eq!(validate("WNK00000001"), Err(SerialError::WrongLength));
+        assert_eq!(validate("WNK0000000190"), Err(SerialError::WrongLength));
+    }
+
+    #[test]
+    fn outside_the_alphabet() {
+        assert_eq!(validate("wnk000000019"), Err(SerialError::InvalidCharacter('w')));
+        assert_eq!(validate("WNK00000001I"), Err(SerialError::InvalidCharacter('I')));
+        assert_eq!(validate("WNK0000000U9"), Err(SerialError::InvalidCharacter('U')));
+        // 12 characters but more than 12 bytes
+        assert_eq!(validate("WNK00000001é"), Err(SerialError::InvalidCharacter('é')));
+    }
+
+    #[test]
+    fn check_character_needs_the_alphabet() {
+        assert_eq!(check_character("WNK00000001"), Some('9'));
+        assert_eq!(check_character("WNK0000000O"), None);
+    }
+}
@@ -169,7 +169,11 @@ document.addEventListener('DOMContentLoaded', () => {
             email: formData.get('email'),
             username: formData.get('username'),
             password: formData.get('password'),
-            device_name: formData.get('device_name')
+            device_name: formData.get('device_name'),
+            proof: {
+                type: 'claim_code',
+                code: formData.get('claim_code')
+            }
         };
 
         // Debug logs
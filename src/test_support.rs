@@ -0,0 +1,89 @@
+//! Test support module
+//!
+//! A throwaway database for each test, migrated like the real one, and the accounts and
+//! devices most tests need in it. The files are deleted when the `TestDb` is dropped.
+
+use std::{ops::Deref, path::{Path, PathBuf}, sync::Arc};
+
+use crate::{config::DatabaseConfig, database::{Database, Db, DeviceLinks, Devices, Register}, serial};
+
+/// Where a test database goes. It's deleted, along with its `-wal` and `-shm`, on drop.
+pub struct TestPath(PathBuf);
+
+impl TestPath {
+    pub fn temp() -> TestPath {
+        TestPath(std::env::temp_dir().join(format!("winklink-test-{}.db", uuid::Uuid::new_v4())))
+    }
+
+    pub fn config(&self) -> DatabaseConfig {
+        DatabaseConfig { path: self.0.clone() }
+    }
+}
+
+impl Deref for TestPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestPath {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+pub struct TestDb {
+    db: Arc<Db>,
+    _path: TestPath,
+}
+
+impl Deref for TestDb {
+    type Target = Arc<Db>;
+
+    fn deref(&self) -> &Arc<Db> {
+        &self.db
+    }
+}
+
+pub async fn test_db() -> TestDb {
+    test_db_at(TestPath::temp()).await
+}
+
+/// Runs `init_db` on whatever is already at `path`, for starting from an old schema
+pub async fn test_db_at(path: TestPath) -> TestDb {
+    let db = Database::init_db(&path.config()).await.expect("test database");
+
+    TestDb { db: Arc::new(db), _path: path }
+}
+
+/// A new account called `username`, returns its uuid
+pub async fn account(db: &Db, username: &str) -> String {
+    let tx = Database::start_transaction(db).await.unwrap();
+    let uuid = Register::create_account(&tx, &format!("{}@example.com", username), username, "correct horse 1").await.unwrap();
+    Database::commit_transaction(tx).await.unwrap();
+
+    uuid
+}
+
+/// A device with a random serial number claimed by `account_uuid`, returns the serial number
+pub async fn device(db: &Db, account_uuid: &str) -> String {
+    let payload = format!("{:011}", uuid::Uuid::new_v4().as_u128() % 100_000_000_000);
+    let serial_number = format!("{}{}", payload, serial::check_character(&payload).unwrap());
+
+    let tx = Database::start_transaction(db).await.unwrap();
+    Devices::claim(&tx, account_uuid, &serial_number, "Test device").await.unwrap();
+    Database::commit_transaction(tx).await.unwrap();
+
+    serial_number
+}
+
+/// Makes two devices contacts, so they can wink at each other
+pub async fn contacts(db: &Db, serial_number: &str, other_serial_number: &str) {
+    DeviceLinks::set(db, serial_number, other_serial_number, "accepted", serial_number, None).await.unwrap();
+}
@@ -0,0 +1,298 @@
+//! Transfer module
+//!
+//! Moving a device from one account to another when it's sold or given away. It's two steps:
+//!
+//! 1. The current owner calls [`create_offer`] and gets a one-time code (e.g. `7KQ2M-XW9RT`)
+//! 2. They hand the code to the new owner, who calls [`accept_offer`] with it
+//!
+//! Only the hash of the code is stored. A device only ever has one open offer, making a new one
+//! cancels the old one. When an offer is accepted the device row, the offer and
+//! `device_ownership_history` are all updated in the same transaction, so the device is never
+//! owned by both or neither. The device's API credentials are revoked and its contacts and
+//! inbox dropped at the same time, the new owner starts fresh.
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Duration, Utc};
+use libsql::Connection;
+use sha2::{Digest, Sha256};
+
+use crate::{config::DevicesConfig, database::{Database, Db, DeviceCredentials, DeviceLinks, Devices, OwnershipHistory, Transfers, Winks}};
+
+/// Crockford's base32, no I, L, O or U so codes survive being read out over the phone
+const CODE_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const CODE_LENGTH: usize = 10;
+
+pub struct TransferOffer {
+    pub transfer_id: String,
+    /// Only ever shown to the owner once, right after they make the offer
+    pub code: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    /// Nobody has claimed this serial number
+    NoSuchDevice,
+    /// The caller doesn't own the device they're trying to give away
+    NotOwner,
+    /// No open offer for this device
+    NoPendingOffer,
+    /// Wrong code, or the offer has been cancelled/used
+    InvalidCode,
+    Expired,
+    /// Accepting your own offer
+    AlreadyOwner,
+    Internal(anyhow::Error),
+}
+
+impl TransferError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            TransferError::NoSuchDevice => "Device with this serial number not found",
+            TransferError::NotOwner => "You do not own this device",
+            TransferError::NoPendingOffer => "There is no pending transfer for this device",
+            TransferError::InvalidCode => "Transfer code is invalid",
+            TransferError::Expired => "Transfer code has expired",
+            TransferError::AlreadyOwner => "You already own this device",
+            TransferError::Internal(_) => "Internal error",
+        }
+    }
+}
+
+impl From<anyhow::Error> for TransferError {
+    fn from(e: anyhow::Error) -> Self {
+        TransferError::Internal(e)
+    }
+}
+
+/// Step 1, the owner offers the device up. Replaces any offer that was already open.
+pub async fn create_offer(
+    conn: &Db,
+    config: &DevicesConfig,
+    owner_uuid: &str,
+    serial_number: &str,
+) -> Result<TransferOffer, TransferError> {
+    check_owner(conn, owner_uuid, serial_number).await?;
+
+    let code = generate_code();
+    let expires_at = Utc::now() + Duration::seconds(config.transfer_offer_lifetime_secs as i64);
+
+    let tx = Database::start_transaction(conn).await?;
+    let result = async {
+        Transfers::cancel_pending(&tx, serial_number).await?;
+        Transfers::insert(&tx, serial_number, owner_uuid, &hash_code(&code), expires_at).await
+    }.await;
+
+    match result {
+        Ok(transfer_id) => {
+            Database::commit_transaction(tx).await?;
+            Ok(TransferOffer { transfer_id, code, expires_at })
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e.into())
+        }
+    }
+}
+
+/// Step 2, the new owner takes the device using the code they were given. Returns who had it.
+pub async fn accept_offer(
+    conn: &Db,
+    recipient_uuid: &str,
+    serial_number: &str,
+    code: &str,
+    device_name: Option<&str>,
+) -> Result<String, TransferError> {
+    let tx = Database::start_transaction(conn).await?;
+
+    let result = async {
+        let Some(offer) = Transfers::find_pending(&tx, serial_number).await? else {
+            return Err(TransferError::InvalidCode);
+        };
+        if offer.code_hash != hash_code(code) {
+            return Err(TransferError::InvalidCode);
+        }
+        if offer.expires_at <= Utc::now() {
+            return Err(TransferError::Expired);
+        }
+        if offer.from_account_uuid == recipient_uuid {
+            return Err(TransferError::AlreadyOwner);
+        }
+
+        // The device could have changed hands some other way since the offer was made
+        let owner = Devices::owner_of(&tx, serial_number).await?;
+        if owner.as_deref() != Some(offer.from_account_uuid.as_str()) {
+            return Err(TransferError::InvalidCode);
+        }
+
+        OwnershipHistory::released(&tx, serial_number, "transfer").await?;
+        Devices::set_owner(&tx, serial_number, recipient_uuid, device_name).await?;
+        OwnershipHistory::acquired(&tx, serial_number, recipient_uuid, "transfer").await?;
+        // The old owner could have copied the device's credentials
+        DeviceCredentials::revoke_all_for_device(&tx, serial_number).await?;
+        // Contacts were the old owner's friends, not the new one's
+        DeviceLinks::remove_all_for_device(&tx, serial_number).await?;
+        // Same for the winks they were sent
+        Winks::remove_all_to_device(&tx, serial_number).await?;
+        Transfers::mark_accepted(&tx, offer.id, recipient_uuid).await?;
+
+        log::info!("Device {} transferred from {} to {} (transfer {})",
+            serial_number, offer.from_account_uuid, recipient_uuid, offer.uuid);
+        Ok(offer.from_account_uuid)
+    }.await;
+
+    match result {
+        Ok(previous_owner) => {
+            Database::commit_transaction(tx).await?;
+            Ok(previous_owner)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+/// The owner changed their mind
+pub async fn cancel_offer(conn: &Connection, owner_uuid: &str, serial_number: &str) -> Result<(), TransferError> {
+    check_owner(conn, owner_uuid, serial_number).await?;
+
+    if Transfers::cancel_pending(conn, serial_number).await? == 0 {
+        return Err(TransferError::NoPendingOffer);
+    }
+
+    Ok(())
+}
+
+async fn check_owner(conn: &Connection, account_uuid: &str, serial_number: &str) -> Result<(), TransferError> {
+    match Devices::owner_of(conn, serial_number).await? {
+        None => Err(TransferError::NoSuchDevice),
+        Some(owner) if owner != account_uuid => Err(TransferError::NotOwner),
+        Some(_) => Ok(()),
+    }
+}
+
+/// e.g. `7KQ2M-XW9RT`
+fn generate_code() -> String {
+    let mut bytes = [0u8; CODE_LENGTH];
+    OsRng.fill_bytes(&mut bytes);
+
+    format_code(&bytes)
+}
+
+/// Turns the first 10 bytes into a code, one character per byte
+pub fn format_code(bytes: &[u8]) -> String {
+    let chars: String = bytes.iter().take(CODE_LENGTH).map(|b| CODE_ALPHABET[(b & 31) as usize] as char).collect();
+    format!("{}-{}", &chars[..CODE_LENGTH / 2], &chars[CODE_LENGTH / 2..])
+}
+
+fn hash_code(code: &str) -> String {
+    hex::encode(Sha256::digest(normalise_code(code).as_bytes()))
+}
+
+/// Forgives the usual mistakes people make typing a Crockford code in, e.g. `7kq2m xw9rt` and
+/// `7KQ2M-XW9RT` are the same code
+pub fn normalise_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| match c.to_ascii_uppercase() {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device_auth, test_support::{account, device, test_db}};
+
+    #[tokio::test]
+    async fn accepting_moves_the_device() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let serial_number = device(&db, &alice).await;
+        let credential = device_auth::issue_credential(&db, &serial_number).await.unwrap();
+
+        let offer = create_offer(&db, &DevicesConfig::default(), &alice, &serial_number).await.unwrap();
+        let previous_owner = accept_offer(&db, &bob, &serial_number, &offer.code, Some("Bob's")).await.unwrap();
+
+        assert_eq!(previous_owner, alice);
+        assert_eq!(Devices::owner_of(&db, &serial_number).await.unwrap().as_deref(), Some(bob.as_str()));
+        assert!(device_auth::authorize_device(Some(format!("Device {}", credential)), db.clone()).await.is_err());
+        // Codes are good for one go
+        assert!(matches!(accept_offer(&db, &bob, &serial_number, &offer.code, None).await, Err(TransferError::InvalidCode)));
+    }
+
+    #[tokio::test]
+    async fn wrong_code() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let serial_number = device(&db, &alice).await;
+
+        create_offer(&db, &DevicesConfig::default(), &alice, &serial_number).await.unwrap();
+
+        assert!(matches!(accept_offer(&db, &bob, &serial_number, "00000-00000", None).await, Err(TransferError::InvalidCode)));
+        assert_eq!(Devices::owner_of(&db, &serial_number).await.unwrap().as_deref(), Some(alice.as_str()));
+    }
+
+    #[tokio::test]
+    async fn codes_are_forgiving() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let serial_number = device(&db, &alice).await;
+
+        let offer = create_offer(&db, &DevicesConfig::default(), &alice, &serial_number).await.unwrap();
+        let typed = offer.code.to_lowercase().replace('-', " ").replace('0', "o").replace('1', "l");
+
+        accept_offer(&db, &bob, &serial_number, &typed, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_offer() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let serial_number = device(&db, &alice).await;
+        let config = DevicesConfig { transfer_offer_lifetime_secs: 0, ..DevicesConfig::default() };
+
+        let offer = create_offer(&db, &config, &alice, &serial_number).await.unwrap();
+
+        assert!(matches!(accept_offer(&db, &bob, &serial_number, &offer.code, None).await, Err(TransferError::Expired)));
+        assert_eq!(Devices::owner_of(&db, &serial_number).await.unwrap().as_deref(), Some(alice.as_str()));
+    }
+
+    #[tokio::test]
+    async fn new_offer_cancels_the_old_one() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let serial_number = device(&db, &alice).await;
+
+        let old = create_offer(&db, &DevicesConfig::default(), &alice, &serial_number).await.unwrap();
+        let new = create_offer(&db, &DevicesConfig::default(), &alice, &serial_number).await.unwrap();
+
+        assert!(matches!(accept_offer(&db, &bob, &serial_number, &old.code, None).await, Err(TransferError::InvalidCode)));
+        accept_offer(&db, &bob, &serial_number, &new.code, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_can_offer() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let serial_number = device(&db, &alice).await;
+
+        assert!(matches!(create_offer(&db, &DevicesConfig::default(), &bob, &serial_number).await, Err(TransferError::NotOwner)));
+        assert!(matches!(cancel_offer(&db, &bob, &serial_number).await, Err(TransferError::NotOwner)));
+    }
+
+    #[tokio::test]
+    async fn accepting_your_own_offer() {
+        let db = test_db().await;
+        let alice = account(&db, "alice").await;
+        let serial_number = device(&db, &alice).await;
+
+        let offer = create_offer(&db, &DevicesConfig::default(), &alice, &serial_number).await.unwrap();
+
+        assert!(matches!(accept_offer(&db, &alice, &serial_number, &offer.code, None).await, Err(TransferError::AlreadyOwner)));
+    }
+}
@@ -0,0 +1,192 @@
+//! Validation module
+//!
+//! The rules for what people can type into a request body. They're declared on the structs in
+//! `models.rs` with `#[derive(Validate)]`, e.g.
+//!
+//! ```rust
+//! #[derive(Deserialize, Validate)]
+//! pub struct ClaimDeviceRequest {
+//!     #[validate(length(min = 1, max = "DEVICE_NAME_MAX_LENGTH"), custom(function = "validation::device_name"))]
+//!     pub device_name: String,
+//! }
+//! ```
+//!
+//! and handlers call `body.validate()?` before touching the database. Every broken rule comes
+//! back at once as a `400 validation_failed`, one entry in `details` per field and rule, so a
+//! form can show them all instead of one per round trip.
+//!
+//! The checks that need more than the string itself live here, the simple ones (email syntax,
+//! lengths) are straight attributes on the struct.
+
+use std::borrow::Cow;
+
+use validator::ValidationError;
+
+use crate::models::WLRegister;
+
+pub const EMAIL_MAX_LENGTH: u64 = 254;
+pub const USERNAME_MIN_LENGTH: u64 = 3;
+pub const USERNAME_MAX_LENGTH: u64 = 32;
+pub const PASSWORD_MIN_LENGTH: u64 = 10;
+pub const PASSWORD_MAX_LENGTH: u64 = 128;
+pub const DEVICE_NAME_MAX_LENGTH: u64 = 64;
+
+/// Punctuation a device name can have on top of letters, digits and spaces
+const DEVICE_NAME_PUNCTUATION: &[char] = &['-', '_', '.', ',', '\'', '’', '(', ')', '#', '&', '+'];
+
+/// Not empty and not just spaces
+pub fn required(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(error("required", "This field is required"));
+    }
+    Ok(())
+}
+
+/// Letters, digits, `_`, `-` and `.`, starting with a letter or digit. Letters don't have to be
+/// ASCII.
+pub fn username(value: &str) -> Result<(), ValidationError> {
+    if !value.chars().all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        return Err(error("invalid_characters", "Username can only have letters, digits, '_', '-' and '.'"));
+    }
+    if !value.starts_with(char::is_alphanumeric) {
+        return Err(error("invalid", "Username must start with a letter or digit"));
+    }
+    Ok(())
+}
+
+/// At least one letter and one something else. Length is checked on the field.
+pub fn password(value: &str) -> Result<(), ValidationError> {
+    if !value.chars().any(char::is_alphabetic) || value.chars().all(char::is_alphabetic) {
+        return Err(error("too_weak", "Password must have at least one letter and one digit or symbol"));
+    }
+    Ok(())
+}
+
+/// Letters, digits, single spaces and a bit of punctuation, nothing invisible. Length is
+/// checked on the field.
+pub fn device_name(value: &str) -> Result<(), ValidationError> {
+    if !value.chars().all(|c| c.is_alphanumeric() || c == ' ' || DEVICE_NAME_PUNCTUATION.contains(&c)) {
+        return Err(error("invalid_characters", "Device name can only have letters, digits, spaces and - _ . , ' ( ) # & +"));
+    }
+    if value.trim() != value || value.contains("  ") {
+        return Err(error("invalid", "Device name can't start or end with a space or have two in a row"));
+    }
+    Ok(())
+}
+
+/// Someone who knows your username shouldn't get your password for free
+pub fn register_password(body: &WLRegister) -> Result<(), ValidationError> {
+    let password = body.password.to_lowercase();
+    let username = body.username.to_lowercase();
+    let email_name = body.email.split('@').next().unwrap_or_default().to_lowercase();
+
+    let personal = [username, email_name].into_iter().filter(|s| s.chars().count() as u64 >= USERNAME_MIN_LENGTH);
+    for personal in personal {
+        if password.contains(&personal) {
+            let mut e = error("too_personal", "Password can't contain your username or email");
+            e.add_param(Cow::from("field"), &"password");
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn error(code: &'static str, message: &'static str) -> ValidationError {
+    ValidationError::new(code).with_message(Cow::from(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use validator::Validate;
+
+    use super::*;
+    use crate::models::PossessionProof;
+
+    fn code(result: Result<(), ValidationError>) -> Option<String> {
+        result.err().map(|e| e.code.to_string())
+    }
+
+    fn register() -> WLRegister {
+        WLRegister {
+            serial_number: "WNK000000019".to_string(),
+            email: "alice@example.com".to_string(),
+            account_created_at: None,
+            username: "alice".to_string(),
+            password: "correct horse 1".to_string(),
+            device_name: "Kitchen".to_string(),
+            proof: PossessionProof::ClaimCode { code: "7KQ2M-XW9RT".to_string() },
+        }
+    }
+
+    #[test]
+    fn required() {
+        assert_eq!(code(super::required("x")), None);
+        assert_eq!(code(super::required("")).as_deref(), Some("required"));
+        assert_eq!(code(super::required(" \t\n")).as_deref(), Some("required"));
+    }
+
+    #[test]
+    fn usernames() {
+        for ok in ["alice", "alice_b", "a.l-i_c.e", "9lives", "zoë", "Алиса"] {
+            assert_eq!(code(username(ok)), None, "{}", ok);
+        }
+        for (bad, expected) in [("al ice", "invalid_characters"), ("alice!", "invalid_characters"), ("ali\u{200b}ce", "invalid_characters"),
+                                ("_alice", "invalid"), (".alice", "invalid"), ("", "invalid")] {
+            assert_eq!(code(username(bad)).as_deref(), Some(expected), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn passwords() {
+        for ok in ["correct horse", "hunter2hunter", "pässwörd 1"] {
+            assert_eq!(code(password(ok)), None, "{}", ok);
+        }
+        for bad in ["correcthorse", "1234567890", "!!!!!!!!!!"] {
+            assert_eq!(code(password(bad)).as_deref(), Some("too_weak"), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn device_names() {
+        for ok in ["Kitchen", "Bob's #2 (upstairs)", "Zoë’s Room", "R&D + QA"] {
+            assert_eq!(code(device_name(ok)), None, "{}", ok);
+        }
+        for (bad, expected) in [("Kitchen\n", "invalid_characters"), ("Kit\u{202e}chen", "invalid_characters"), ("<b>", "invalid_characters"),
+                                (" Kitchen", "invalid"), ("Kitchen ", "invalid"), ("Big  Kitchen", "invalid")] {
+            assert_eq!(code(device_name(bad)).as_deref(), Some(expected), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn passwords_cant_be_personal() {
+        assert_eq!(code(register_password(&register())), None);
+
+        for password in ["Alice123456", "my email is alice"] {
+            let body = WLRegister { password: password.to_string(), ..register() };
+            assert_eq!(code(register_password(&body)).as_deref(), Some("too_personal"), "{}", password);
+        }
+
+        // Too short to count, "al" turns up in plenty of passwords
+        let body = WLRegister { username: "al".to_string(), email: "al@example.com".to_string(), password: "always 1 al".to_string(), ..register() };
+        assert_eq!(code(register_password(&body)), None);
+    }
+
+    /// Every broken rule comes back, not just the first
+    #[test]
+    fn all_errors_at_once() {
+        register().validate().unwrap();
+
+        let body = WLRegister {
+            email: "not an email".to_string(),
+            username: "a".to_string(),
+            password: "short".to_string(),
+            device_name: String::new(),
+            ..register()
+        };
+        let errors = body.validate().unwrap_err();
+        let mut fields = errors.field_errors().into_keys().map(|field| field.to_string()).collect::<Vec<_>>();
+        fields.sort();
+
+        assert_eq!(fields, ["device_name", "email", "password", "username"]);
+    }
+}
@@ -0,0 +1,346 @@
+//! Wink module
+//!
+//! The actual point of a WinkLink: one device winking at another. A wink always comes *from* a
+//! device, either sent by the device itself (with its credential, see `device_auth.rs`) or by
+//! its owner through the app on its behalf. It can only go to one of the sending device's
+//! contacts (see `contacts.rs`).
+//!
+//! A wink has a `type` (short lowercase name like `wink` or `thinking_of_you`, the firmware
+//! decides what to do with it) and an optional short payload. The recipient pages through its
+//! inbox newest first with [`inbox`] and marks winks `delivered` (it got them) or `read` (a
+//! human saw them) with [`acknowledge`].
+//!
+//! Each sending device can only send `winks.rate_limit` winks per `winks.rate_limit_window_secs`,
+//! however they're sent.
+//!
+//! Winks are queued until the recipient acknowledges them as `delivered`, so a device that's
+//! offline gets them when it's back:
+//!
+//! - A device with a socket open (`realtime.rs`) gets each wink pushed as it's sent, and every
+//!   pending one again ([`redeliver`]) whenever it reconnects. That's at least once, a device
+//!   can see the same `wink_id` twice and should acknowledge it both times.
+//! - A device without one finds them in its inbox.
+//!
+//! Each wink has a TTL, `winks.ttl_secs` unless the sender asks for something else. One that
+//! hasn't been delivered by then is a dead letter: it's gone from the inbox, can't be
+//! acknowledged any more, and the sender can see it with [`dead_letters`] for
+//! `winks.dead_letter_retention_secs`.
+//!
+//! Winks belong to the owners at the time. When a device is transferred or released its inbox
+//! goes, and the new owner doesn't see the dead letters the old one left behind.
+
+use chrono::{DateTime, Utc};
+use libsql::Connection;
+
+use crate::{config::WinksConfig, contacts, database::{Database, Db, Devices, NewWink, WinkRecord, Winks}, device_auth::AuthDevice, models::WinkState, response::WinkResponse};
+
+const MAX_KIND_LENGTH: usize = 32;
+const MAX_PAYLOAD_LENGTH: usize = 256;
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+/// Most winks one acknowledge call can touch
+const MAX_ACK_BATCH: usize = 100;
+/// Most pending winks pushed to a device when it reconnects, the rest wait in the inbox
+const MAX_REDELIVERY: u32 = 100;
+
+/// Who a wink is from
+pub struct WinkSender {
+    pub serial_number: String,
+    /// The sending device's owner
+    pub account_uuid: String,
+    /// `device` or `owner`
+    pub sent_by: &'static str,
+}
+
+pub struct InboxPage {
+    pub winks: Vec<WinkRecord>,
+    pub next_cursor: Option<i64>,
+}
+
+#[derive(Debug)]
+pub enum WinkError {
+    /// The sending/receiving device the caller named isn't claimed
+    NoSuchDevice,
+    NotOwner,
+    /// An owner has to say which of their devices they mean
+    SerialNumberRequired,
+    /// A device tried to send as, or read the inbox of, some other device
+    WrongDevice,
+    RecipientNotFound,
+    CannotWinkSelf,
+    /// Only contacts can wink at each other, see `contacts.rs`
+    NotContacts,
+    InvalidKind,
+    PayloadTooLong,
+    /// Asked for a TTL of zero or over `winks.max_ttl_secs`
+    InvalidTtl,
+    TooManyWinkIds,
+    RateLimited { retry_after_secs: u64 },
+    Internal(anyhow::Error),
+}
+
+impl WinkError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            WinkError::NoSuchDevice => "Device with this serial number not found",
+            WinkError::NotOwner => "You do not own this device",
+            WinkError::SerialNumberRequired => "Say which of your devices with serial_number",
+            WinkError::WrongDevice => "A device can only act as itself",
+            WinkError::RecipientNotFound => "No device with that serial number to wink at",
+            WinkError::CannotWinkSelf => "A device can't wink at itself",
+            WinkError::NotContacts => "You can only wink at your contacts",
+            WinkError::InvalidKind => "Wink type must be 1-32 characters of a-z, 0-9 or _",
+            WinkError::PayloadTooLong => "Wink payload is too long",
+            WinkError::InvalidTtl => "ttl_secs must be between 1 and the server's maximum",
+            WinkError::TooManyWinkIds => "Too many wink ids in one request",
+            WinkError::RateLimited { .. } => "Too many winks, slow down",
+            WinkError::Internal(_) => "Internal error",
+        }
+    }
+}
+
+impl From<anyhow::Error> for WinkError {
+    fn from(e: anyhow::Error) -> Self {
+        WinkError::Internal(e)
+    }
+}
+
+/// An owner acting through one of their devices. `serial_number` is required.
+pub async fn owner_device(conn: &Connection, account_uuid: &str, serial_number: Option<&str>) -> Result<String, WinkError> {
+    let serial_number = serial_number.ok_or(WinkError::SerialNumberRequired)?;
+
+    match Devices::owner_of(conn, serial_number).await? {
+        None => Err(WinkError::NoSuchDevice),
+        Some(owner) if owner != account_uuid => Err(WinkError::NotOwner),
+        Some(_) => Ok(serial_number.to_string()),
+    }
+}
+
+/// A device acting as itself. If it names a serial number anyway it had better be its own.
+pub fn own_device(device: &AuthDevice, serial_number: Option<&str>) -> Result<String, WinkError> {
+    match serial_number {
+        Some(serial_number) if serial_number != device.serial_number => Err(WinkError::WrongDevice),
+        _ => Ok(device.serial_number.clone()),
+    }
+}
+
+/// The sender for a wink a device is sending itself
+pub async fn device_sender(conn: &Connection, device: &AuthDevice, from_serial_number: Option<&str>) -> Result<WinkSender, WinkError> {
+    let serial_number = own_device(device, from_serial_number)?;
+    let account_uuid = Devices::owner_of(conn, &serial_number).await?.ok_or(WinkError::NoSuchDevice)?;
+
+    Ok(WinkSender { serial_number, account_uuid, sent_by: "device" })
+}
+
+/// The sender for a wink an owner is sending on behalf of one of their devices
+pub async fn owner_sender(conn: &Connection, account_uuid: &str, from_serial_number: Option<&str>) -> Result<WinkSender, WinkError> {
+    let serial_number = owner_device(conn, account_uuid, from_serial_number).await?;
+
+    Ok(WinkSender { serial_number, account_uuid: account_uuid.to_string(), sent_by: "owner" })
+}
+
+/// Sends a wink and hands back what was stored
+pub async fn send(
+    conn: &Connection,
+    config: &WinksConfig,
+    sender: &WinkSender,
+    to_serial_number: &str,
+    kind: &str,
+    payload: Option<&str>,
+    ttl_secs: Option<u64>,
+) -> Result<WinkRecord, WinkError> {
+    let ttl_secs = ttl_secs.unwrap_or(config.ttl_secs);
+    if ttl_secs == 0 || ttl_secs > config.max_ttl_secs {
+        return Err(WinkError::InvalidTtl);
+    }
+    if kind.is_empty() || kind.len() > MAX_KIND_LENGTH || !kind.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+        return Err(WinkError::InvalidKind);
+    }
+    if payload.is_some_and(|p| p.chars().count() > MAX_PAYLOAD_LENGTH) {
+        return Err(WinkError::PayloadTooLong);
+    }
+    if to_serial_number == sender.serial_number {
+        return Err(WinkError::CannotWinkSelf);
+    }
+    if Devices::owner_of(conn, to_serial_number).await?.is_none() {
+        return Err(WinkError::RecipientNotFound);
+    }
+    if !contacts::are_contacts(conn, &sender.serial_number, to_serial_number).await? {
+        return Err(WinkError::NotContacts);
+    }
+
+    let now = Utc::now().timestamp();
+    let window = config.rate_limit_window_secs as i64;
+    let new_wink = NewWink {
+        from_serial_number: &sender.serial_number,
+        from_account_uuid: &sender.account_uuid,
+        sent_by: sender.sent_by,
+        to_serial_number,
+        kind,
+        payload,
+        ttl_secs,
+    };
+    let Some(wink) = Winks::insert(conn, new_wink, now - window, config.rate_limit).await? else {
+        // Room frees up once the oldest wink in the window falls out of it
+        let oldest = Winks::oldest_sent_since(conn, &sender.serial_number, now - window).await?;
+        let retry_after_secs = oldest.map(|oldest| oldest + window - now).unwrap_or(window).max(1) as u64;
+        return Err(WinkError::RateLimited { retry_after_secs });
+    };
+    log::debug!("Wink {} sent from {} to {} ({})", wink.uuid, sender.serial_number, to_serial_number, sender.sent_by);
+
+    Ok(wink)
+}
+
+/// One page of a device's inbox, newest first
+pub async fn inbox(conn: &Connection, serial_number: &str, before: Option<i64>, limit: Option<u32>) -> Result<InboxPage, WinkError> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let winks = Winks::inbox(conn, serial_number, before, limit).await?;
+
+    // A full page means there might be more
+    let next_cursor = if winks.len() == limit as usize { winks.last().map(|w| w.id) } else { None };
+
+    Ok(InboxPage { winks, next_cursor })
+}
+
+/// Winks sent from a device that expired before they were delivered, newest first
+pub async fn dead_letters(conn: &Connection, serial_number: &str, before: Option<i64>, limit: Option<u32>) -> Result<InboxPage, WinkError> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let winks = Winks::dead_letters(conn, serial_number, before, limit).await?;
+
+    let next_cursor = if winks.len() == limit as usize { winks.last().map(|w| w.id) } else { None };
+
+    Ok(InboxPage { winks, next_cursor })
+}
+
+/// The winks still waiting for a device that's just connected, oldest first. They count as
+/// another delivery attempt.
+pub async fn redeliver(conn: &Db, serial_number: &str) -> anyhow::Result<Vec<WinkRecord>> {
+    let tx = Database::start_transaction(conn).await?;
+    let winks = match Winks::take_pending(&tx, serial_number, MAX_REDELIVERY).await {
+        Ok(winks) => winks,
+        Err(e) => {
+            let _ = tx.rollback().await;
+            return Err(e);
+        }
+    };
+    Database::commit_transaction(tx).await?;
+
+    if !winks.is_empty() {
+        log::debug!("Redelivering {} wink(s) to {}", winks.len(), serial_number);
+    }
+    Ok(winks)
+}
+
+/// Marks winks in a device's inbox, ids that aren't in it are ignored. Returns how many changed.
+pub async fn acknowledge(conn: &Connection, serial_number: &str, wink_ids: &[String], state: WinkState) -> Result<u64, WinkError> {
+    if wink_ids.len() > MAX_ACK_BATCH {
+        return Err(WinkError::TooManyWinkIds);
+    }
+
+    let mut changed = 0;
+    for wink_id in wink_ids {
+        let updated = match state {
+            WinkState::Delivered => Winks::mark_delivered(conn, serial_number, wink_id).await?,
+            WinkState::Read => Winks::mark_read(conn, serial_number, wink_id).await?,
+        };
+        if updated {
+            changed += 1;
+        }
+    }
+
+    Ok(changed)
+}
+
+pub fn response(w: &WinkRecord) -> WinkResponse {
+    WinkResponse {
+        wink_id: w.uuid.clone(),
+        from_serial_number: w.from_serial_number.clone(),
+        sent_by: w.sent_by.clone(),
+        to_serial_number: w.to_serial_number.clone(),
+        kind: w.kind.clone(),
+        payload: w.payload.clone(),
+        sent_at: rfc3339(w.created_at),
+        delivered_at: w.delivered_at.clone(),
+        read_at: w.read_at.clone(),
+        expires_at: rfc3339(w.expires_at),
+        delivery_attempts: w.delivery_attempts,
+    }
+}
+
+/// `created_at` and `expires_at` are stored as unix seconds, everything else goes out as RFC 3339
+fn rfc3339(unix_secs: i64) -> String {
+    DateTime::<Utc>::from_timestamp(unix_secs, 0).unwrap_or_default().to_rfc3339()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::task::JoinSet;
+
+    use super::*;
+    use crate::{config::DevicesConfig, test_support::{account, contacts, device, test_db}, transfer};
+
+    #[tokio::test]
+    async fn new_owner_doesnt_see_old_owners_winks() {
+        let db = test_db().await;
+        let (alice, bob, carol) = (account(&db, "alice").await, account(&db, "bob").await, account(&db, "carol").await);
+        let (sold, friend) = (device(&db, &alice).await, device(&db, &bob).await);
+        contacts(&db, &sold, &friend).await;
+
+        let config = WinksConfig::default();
+        let bobs = WinkSender { serial_number: friend.clone(), account_uuid: bob.clone(), sent_by: "owner" };
+        send(&db, &config, &bobs, &sold, "wink", Some("for alice"), None).await.unwrap();
+        let alices = WinkSender { serial_number: sold.clone(), account_uuid: alice.clone(), sent_by: "owner" };
+        send(&db, &config, &alices, &friend, "wink", Some("from alice"), None).await.unwrap();
+        // Expires straight away, so it's a dead letter of alice's
+        Winks::insert(&db, NewWink {
+            from_serial_number: &sold,
+            from_account_uuid: &alice,
+            sent_by: "owner",
+            to_serial_number: &friend,
+            kind: "wink",
+            payload: None,
+            ttl_secs: 0,
+        }, 0, u32::MAX).await.unwrap();
+        assert_eq!(inbox(&db, &sold, None, None).await.unwrap().winks.len(), 1);
+        assert_eq!(dead_letters(&db, &sold, None, None).await.unwrap().winks.len(), 1);
+
+        let offer = transfer::create_offer(&db, &DevicesConfig::default(), &alice, &sold).await.unwrap();
+        transfer::accept_offer(&db, &carol, &sold, &offer.code, None).await.unwrap();
+
+        assert!(inbox(&db, &sold, None, None).await.unwrap().winks.is_empty());
+        assert!(redeliver(&db, &sold).await.unwrap().is_empty());
+        assert!(dead_letters(&db, &sold, None, None).await.unwrap().winks.is_empty());
+        // What alice sent is still bob's
+        let bobs_inbox = inbox(&db, &friend, None, None).await.unwrap().winks;
+        assert_eq!(bobs_inbox.len(), 1);
+        assert_eq!(bobs_inbox[0].payload.as_deref(), Some("from alice"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_sends_stay_within_the_rate_limit() {
+        let db = test_db().await;
+        let (alice, bob) = (account(&db, "alice").await, account(&db, "bob").await);
+        let (from, to) = (device(&db, &alice).await, device(&db, &bob).await);
+        contacts(&db, &from, &to).await;
+
+        let config = Arc::new(WinksConfig { rate_limit: 5, ..WinksConfig::default() });
+        let sender = Arc::new(WinkSender { serial_number: from, account_uuid: alice, sent_by: "device" });
+        let mut tasks = JoinSet::new();
+        for _ in 0..20 {
+            let (db, config, sender, to) = (db.clone(), config.clone(), sender.clone(), to.clone());
+            tasks.spawn(async move { send(&db, &config, &sender, &to, "wink", None, None).await });
+        }
+
+        let (mut sent, mut limited) = (0, 0);
+        while let Some(result) = tasks.join_next().await {
+            match result.unwrap() {
+                Ok(_) => sent += 1,
+                Err(WinkError::RateLimited { .. }) => limited += 1,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        assert_eq!((sent, limited), (5, 15));
+    }
+}